@@ -11,6 +11,26 @@ pub struct Args {
     /// Which operation to run with blueprint
     #[command(subcommand)]
     pub command: Commands,
+
+    /// Answer every prompt of blueprint with its default and every prompt
+    /// of the package managers with yes, for running non-interactively
+    #[arg(short, long, visible_alias = "no-confirm", global = true)]
+    pub yes: bool,
+
+    /// Format to write the results of the command in, json writes
+    /// a report to standard output while logs stay on standard error
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, global = true)]
+    pub output: OutputFormat,
+}
+
+// Formats blueprint can write the results of commands in
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    // Human readable logs only
+    Text,
+
+    // Versioned JSON report on standard output
+    Json,
 }
 
 // Enum for commands for different operations within blueprint
@@ -21,21 +41,111 @@ pub enum Commands {
         /// Path to the template file to create
         #[arg(short, long, default_value = "blueprint.toml")]
         file: String,
+
+        /// Overwrite the file if it already exists, without asking
+        #[arg(long)]
+        overwrite: bool,
+    },
+
+    /// Captures all of the packages explicitly installed on the
+    /// system into a new file, grouped by their source
+    Capture {
+        /// Path to the file to write the captured packages to
+        #[arg(short, long, default_value = "blueprint.toml")]
+        file: String,
+
+        /// Overwrite the file if it already exists, without asking
+        #[arg(long)]
+        overwrite: bool,
     },
 
     /// Runs blueprint and ensures all applyed packages only
     /// match the config provided, unapplying all other software
     Apply {
-        /// Path to the template file to create
-        #[arg(short, long)]
-        file: String,
+        #[command(flatten)]
+        config: ConfigArgs,
+
+        /// Only show what apply would change on the
+        /// system, without changing anything
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Continue even when removing more packages
+        /// than the max_removals limit allows
+        #[arg(long)]
+        force: bool,
+
+        /// Back up then replace existing files which
+        /// conflict with declared files
+        #[arg(long)]
+        backup_conflicts: bool,
 
-        /// Name of the provided section for
-        /// Quill TOML extensions. ALL of the config files
-        /// should share this section to minimise confusion.
-        #[arg(short, long, default_value = "blueprint")]
-        section: String,
+        /// Install exactly the versions in the lockfile, refusing
+        /// to apply if the system would diverge from it
+        #[arg(long)]
+        locked: bool,
     },
+
+    /// Shows what apply would install, mark and remove for
+    /// each source, without changing anything on the system
+    Plan {
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+
+    /// Reports packages which are not installed, installed without being
+    /// declared or only installed as a dependency, along with services not
+    /// in their declared state, exiting with code 2 when the system has
+    /// drifted from the config and code 1 when the check fails
+    Status {
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+
+    /// Checks the installed versions of the package list match the
+    /// lockfile next to the root configuration file
+    Lock {
+        #[command(flatten)]
+        config: ConfigArgs,
+
+        /// Record the installed versions into
+        /// the lockfile rather than checking them
+        #[arg(long)]
+        update: bool,
+    },
+
+    /// Lists every generation recorded by apply and rollback,
+    /// with the packages each of them installed and removed
+    History,
+
+    /// Rolls the system back to how it was before a generation, reinstalling
+    /// the packages it removed and removing the packages it installed
+    Rollback {
+        /// Number of the generation to roll back, as listed by history
+        generation: u32,
+
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+}
+
+// Arguments for commands which read in a root configuration file
+#[derive(clap::Args, Debug)]
+pub struct ConfigArgs {
+    /// Path to the root configuration file
+    #[arg(short, long)]
+    pub file: String,
+
+    /// Name of the provided section for
+    /// Quill TOML extensions. ALL of the config files
+    /// should share this section to minimise confusion.
+    #[arg(short, long, default_value = "blueprint")]
+    pub section: String,
+
+    /// Profile to activate, may be given multiple times. Replaces
+    /// the default_profiles of the root configuration file
+    #[arg(short, long = "profile", value_name = "PROFILE")]
+    pub profiles: Vec<String>,
 }
 
 impl Display for Commands {
@@ -43,7 +153,13 @@ impl Display for Commands {
         // Should display what type of command.
         match self {
             Commands::Init { .. } => write!(f, "init"),
+            Commands::Capture { .. } => write!(f, "capture"),
             Commands::Apply { .. } => write!(f, "apply"),
+            Commands::Plan { .. } => write!(f, "plan"),
+            Commands::Status { .. } => write!(f, "status"),
+            Commands::Lock { .. } => write!(f, "lock"),
+            Commands::History => write!(f, "history"),
+            Commands::Rollback { .. } => write!(f, "rollback"),
         }
     }
 }
@@ -1,17 +1,81 @@
 //! Ensures software configuration matches exactly as defined by the package list
 //! provided to the command
 
-use std::path::PathBuf;
+use std::path::Path;
 
 use anyhow::bail;
-use inquire::Confirm;
-use log::info;
+use log::{info, warn};
 
 use crate::{
-    cleanpath::CleanPath, config::ROOT_CONFIG, package::sources::PackageSource,
-    parse_config::parse_config,
+    commands::plan::{
+        log_exclusions, log_file_plan, log_package_list_plan, log_profiles, log_service_plan,
+    },
+    config::ROOT_CONFIG,
+    file::{self, FilePlan, ManagedFile},
+    hook::{HookPhase, Hooks, PackagesByBackend, installed_packages, removed_packages},
+    package::{
+        history::{Snapshot, record_generation, take_snapshot},
+        lock::{Lockfile, lockfile_path},
+        package::PackageList,
+        plan::BackendPlan,
+        sources::PackageSource,
+        version::ensure_no_mismatches,
+    },
+    parse_config::{LoadedConfig, load_config},
+    prompt::{confirm, ensure_can_prompt},
+    report::{record_file_plan, record_plans, record_service_plan},
+    runner::{CommandRunner, ProcessRunner},
+    service::{self, Service, ServicePlan},
 };
 
+/// Ensures the plans don't remove more packages than the configured
+/// limit allows, going over it only warns when the apply is forced
+fn ensure_within_max_removals(plans: &[BackendPlan], force: bool) -> anyhow::Result<()> {
+    let Some(max_removals) = ROOT_CONFIG.get_config().max_removals else {
+        return Ok(());
+    };
+
+    let removals: usize = plans.iter().map(|plan| plan.remove.len()).sum();
+    let installed: usize = plans.iter().map(|plan| plan.installed).sum();
+    if removals <= max_removals.allowed(installed) {
+        return Ok(());
+    }
+
+    if force {
+        warn!(
+            "Removing {} out of {} installed packages, over the limit of {}, continuing as forced",
+            removals, installed, max_removals
+        );
+        return Ok(());
+    }
+
+    bail!(
+        "Apply would remove {} out of {} installed packages, over the max_removals limit of {}, pass --force to remove them anyway",
+        removals,
+        installed,
+        max_removals
+    );
+}
+
+/// Shows the packages the plan would remove and questions the
+/// user whether or not to remove them based on the configuration
+fn continue_removal_prompt(plan: &BackendPlan) -> anyhow::Result<bool> {
+    plan.log_removal();
+
+    if !ROOT_CONFIG.get_config().prompt_removal_per_source {
+        return Ok(true);
+    }
+
+    confirm(
+        &format!(
+            "Remove these {} packages from {}?, No will skip to the next source",
+            plan.remove.len(),
+            plan.backend
+        ),
+        true,
+    )
+}
+
 /// Questions the user whether or not to continue the apply based on
 /// the configuration
 fn continue_apply_prompt(num_packages: usize) -> anyhow::Result<bool> {
@@ -20,42 +84,235 @@ fn continue_apply_prompt(num_packages: usize) -> anyhow::Result<bool> {
         return Ok(true);
     }
 
-    Ok(
-        Confirm::new(format!("Run apply on {} packages?", num_packages).as_str())
-            .with_default(true)
-            .prompt()?,
-    )
+    confirm(&format!("Run apply on {} packages?", num_packages), true)
 }
 
-pub fn apply_command(file: String, section: String) -> anyhow::Result<()> {
-    // Validate file path
-    let path = PathBuf::from(file).clean_path()?;
+/// Records the installed versions of the package list into the lockfile,
+/// which is left alone if any of the packages couldn't be locked
+fn update_lockfile(
+    runner: &dyn CommandRunner,
+    package_list: &PackageList,
+    root_file: &Path,
+    lockfile_path: &Path,
+) {
+    let updated = Lockfile::resolve(runner, package_list, root_file)
+        .and_then(|lockfile| lockfile.write(lockfile_path));
+
+    match updated {
+        Ok(()) => info!("Updated lockfile {:?}", lockfile_path),
+        Err(err) => warn!("Did not update lockfile {:?}: {:?}", lockfile_path, err),
+    }
+}
 
-    // Parse configs to config structs.
-    let (root, configs) = parse_config(path, section)?;
+pub fn apply_command(
+    file: String,
+    section: String,
+    profiles: Vec<String>,
+    dry_run: bool,
+    force: bool,
+    backup_conflicts: bool,
+    locked: bool,
+) -> anyhow::Result<()> {
+    // Parse configs and grab all of the packages
+    let LoadedConfig {
+        mut package_list,
+        excluded,
+        profiles,
+        hooks,
+        services,
+        files,
+        root_file,
+    } = load_config(file, section, profiles)?;
 
-    // Fill in global root config from root
-    let global_config = root.config.unwrap_or_default();
-    ROOT_CONFIG.set_config(global_config);
+    // Package sources run their commands as real processes
+    let runner = ProcessRunner;
 
-    // Re-get the config for usage later
-    let config = ROOT_CONFIG.get_config();
+    // Install exactly what was locked, before anything is shown or changed
+    let lockfile_path = lockfile_path(&root_file);
+    if locked {
+        Lockfile::read(&lockfile_path)?.pin(&runner, &mut package_list)?;
+    }
+
+    // Only show what would happen, without changing anything
+    if dry_run {
+        info!("Dry run of apply on {} packages", package_list.len());
+        log_profiles(&profiles, &package_list);
+        log_exclusions(&excluded);
+        log_package_list_plan(&runner, &package_list)?;
+        log_service_plan(&runner, &services)?;
+        log_file_plan(&runner, &files)?;
+        return Ok(());
+    }
 
-    // Grab all of the packages
-    let mut package_list = configs.flatten_data();
-    package_list.extend(root.packages.0.into_iter());
-    package_list = package_list.remove_duplicates()?;
+    // Fail now rather than hang on a prompt nobody can answer,
+    // or part way through when a source can't run as this user
+    ensure_can_prompt()?;
+    package_list
+        .split_by_source()
+        .keys()
+        .try_for_each(|source| source.privilege_command().map(|_| ()))?;
+    service::privilege_command(services.iter().map(|service| service.user))?;
+    file::privilege_command(&files)?;
+
+    // Nothing is changed if a declared file would replace an existing one
+    let file_plan = FilePlan::new(&runner, &files)?;
+    record_file_plan(&file_plan);
+    file_plan.ensure_no_conflicts(backup_conflicts)?;
 
     if !continue_apply_prompt(package_list.len())? {
         bail!("Aborting apply operation");
     }
 
+    // Whatever this apply changes is recorded as a generation, even
+    // when it fails part way through, so it can be rolled back later
+    let before = take_snapshot(&runner, package_list.split_by_backend().into_keys())?;
+    let applied = apply_package_list(&runner, &package_list, &hooks, &before, force)
+        .and_then(|()| apply_services(&runner, &services))
+        .and_then(|()| apply_files(&runner, &file_plan, &files))
+        .and_then(|()| {
+            hooks.run_phase(
+                &runner,
+                HookPhase::AfterApply,
+                &declared_packages(&package_list),
+            )
+        });
+    record_generation(
+        &runner,
+        "apply",
+        applied.is_ok(),
+        Some(&package_list),
+        before,
+    );
+    applied?;
+
+    // The lockfile already matches the system when applying from it
+    if !locked {
+        update_lockfile(&runner, &package_list, &root_file, &lockfile_path);
+    }
+
+    Ok(())
+}
+
+/// Names of the packages of the package list in each backend, which
+/// global hooks before and after apply are given
+fn declared_packages(package_list: &PackageList) -> PackagesByBackend {
+    package_list
+        .split_by_backend()
+        .into_iter()
+        .map(|(backend, (_, packages))| {
+            (
+                backend,
+                packages
+                    .iter()
+                    .map(|package| package.name.clone())
+                    .collect(),
+            )
+        })
+        .collect()
+}
+
+/// Plans the services once the packages providing them
+/// are installed and brings them to their declared state
+fn apply_services(runner: &dyn CommandRunner, services: &[Service]) -> anyhow::Result<()> {
+    if services.is_empty() {
+        return Ok(());
+    }
+
+    let plan = ServicePlan::new(runner, services)?;
+    record_service_plan(&plan);
+    plan.log();
+    plan.apply(runner)
+}
+
+/// Puts the declared files into place, which were planned before
+/// anything was changed so conflicts could stop the apply
+fn apply_files(
+    runner: &dyn CommandRunner,
+    plan: &FilePlan,
+    files: &[ManagedFile],
+) -> anyhow::Result<()> {
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    plan.log();
+    plan.apply(runner, files)
+}
+
+/// Installs the package list and then removes everything else, running
+/// the hooks around each step. Before is the snapshot of every backend
+/// from before anything was changed
+fn apply_package_list(
+    runner: &dyn CommandRunner,
+    package_list: &PackageList,
+    hooks: &Hooks,
+    before: &Snapshot,
+    force: bool,
+) -> anyhow::Result<()> {
+    let config = ROOT_CONFIG.get_config();
+
+    hooks.run_phase(
+        runner,
+        HookPhase::BeforeApply,
+        &declared_packages(package_list),
+    )?;
+    hooks.run_pre_install(runner, package_list, before)?;
+
     // First, ensure all software is installed before removing non-required
-    package_list.run_source_function(PackageSource::install_all_packagelist)?;
+    package_list.run_source_function(runner, PackageSource::install_all_packagelist)?;
+
+    // What was installed is only worked out when a hook needs it
+    if hooks.need_installed(package_list) {
+        let installed = installed_packages(before, &take_snapshot(runner, before.keys().copied())?);
+        hooks.run_post_install(runner, package_list, &installed)?;
+        hooks.run_phase(runner, HookPhase::AfterInstall, &installed)?;
+    }
+
+    // Version ranges aren't installed directly, so ensure every
+    // installed version now satisfies its constraint before removing
+    if package_list.iter().any(|package| package.version.is_some()) {
+        let mismatches: Vec<_> = package_list
+            .plan_backends(runner)?
+            .into_iter()
+            .flat_map(|plan| plan.mismatched)
+            .collect();
+        ensure_no_mismatches(&mismatches)?;
+    }
 
     if config.remove_unrequired_software {
-        // Then, remove all non-required software from the system.
-        package_list.run_source_function(PackageSource::remove_all_non_packagelist)?;
+        // Work out everything to remove up front, nothing is removed if
+        // any protected package would be, including those only pulled
+        // out as a dependency of another removed package
+        let plans = package_list.plan_backends(runner)?;
+        record_plans(&plans);
+        plans
+            .iter()
+            .try_for_each(BackendPlan::ensure_no_protected_removals)?;
+        ensure_within_max_removals(&plans, force)?;
+
+        // Then, remove all non-required software from the system, sources sharing
+        // a backend must be handled together so they don't remove each others packages
+        for ((source, backend_packages), plan) in
+            package_list.split_by_backend().values().zip(&plans)
+        {
+            // Install reasons are still corrected when nothing is removed
+            if !plan.remove.is_empty() && !continue_removal_prompt(plan)? {
+                continue;
+            }
+
+            source.remove_all_non_packagelist()(
+                &source.privileged_runner(runner)?,
+                backend_packages,
+                plan,
+            )?;
+        }
+
+        // What was removed is only worked out when a hook needs it
+        if hooks.need_removed() {
+            let removed = removed_packages(before, &take_snapshot(runner, before.keys().copied())?);
+            hooks.run_post_remove(runner, &removed)?;
+            hooks.run_phase(runner, HookPhase::AfterRemove, &removed)?;
+        }
     }
 
     Ok(())
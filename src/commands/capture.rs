@@ -0,0 +1,93 @@
+//! Captures the packages currently explicitly installed
+//! on the system into a new blueprint configuration file
+
+use anyhow::{Context, bail};
+use log::{info, warn};
+use serde::Serialize;
+use std::{fs, path::PathBuf};
+
+use crate::{
+    package::sources::PackageSource,
+    prompt::confirm,
+    runner::{CommandRunner, ProcessRunner},
+};
+
+/// Header written to the top of every captured file
+const CAPTURE_HEADER: &str = "# Captured from the system by blueprint capture,
+# every explicitly installed package grouped by its source.\n\n";
+
+/// Blueprint configuration file holding captured packages,
+/// matches the layout parse_config reads back in
+#[derive(Serialize)]
+struct CapturedBlueprint {
+    multi_packages: Vec<CapturedPackages>,
+}
+
+/// All of the packages captured from one source
+#[derive(Serialize)]
+struct CapturedPackages {
+    names: Vec<String>,
+    source: PackageSource,
+}
+
+/// Asks every package source for the packages it has explicitly installed,
+/// skipping sources which could not be queried on this system
+fn capture_sources(runner: &dyn CommandRunner) -> Vec<CapturedPackages> {
+    PackageSource::ALL
+        .iter()
+        .filter_map(|source| match source.explicit_packages()(runner) {
+            Ok(names) => {
+                info!("Captured {} packages from source {}", names.len(), source);
+                Some(CapturedPackages {
+                    names,
+                    source: *source,
+                })
+            }
+            Err(err) => {
+                warn!(
+                    "Skipping source {} which could not be captured: {:?}",
+                    source, err
+                );
+                None
+            }
+        })
+        .filter(|captured| !captured.names.is_empty())
+        .collect()
+}
+
+pub fn capture_command(file: String, overwrite: bool) -> anyhow::Result<()> {
+    // Path to the file
+    let path = PathBuf::from(file);
+
+    // File already exists, prompt user unless told to overwrite it
+    if path.exists()
+        && !overwrite
+        && !confirm(
+            "Supplied capture path already exists, overwrite this file?",
+            false,
+        )?
+    {
+        bail!(
+            "Not capturing system to {:?}, file already exists, pass --overwrite to replace it",
+            path
+        );
+    }
+
+    let captured = CapturedBlueprint {
+        multi_packages: capture_sources(&ProcessRunner),
+    };
+
+    if captured.multi_packages.is_empty() {
+        bail!("No explicitly installed packages could be captured from any source");
+    }
+
+    let content = toml::to_string_pretty(&captured)
+        .context("While trying to convert captured packages to a configuration file")?;
+
+    // Write captured configuration
+    fs::write(&path, format!("{}{}", CAPTURE_HEADER, content))
+        .with_context(|| format!("While trying to write captured packages to {:?}", path))?;
+    info!("Wrote captured system packages to {:?}", path);
+
+    Ok(())
+}
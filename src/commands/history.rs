@@ -0,0 +1,86 @@
+//! Lists every generation recorded by apply and rollback,
+//! along with what each of them changed on the system
+
+use std::collections::BTreeMap;
+
+use log::info;
+
+use crate::package::history::Generation;
+
+/// Formats seconds since the unix epoch as a UTC date and time
+fn format_timestamp(seconds: u64) -> String {
+    // Days since the epoch to a civil date, from Howard Hinnant's algorithm
+    let days = (seconds / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds % 86400 / 3600,
+        seconds % 3600 / 60
+    )
+}
+
+/// Formats packages with their version as name=version
+fn format_versions(versions: &BTreeMap<String, String>) -> String {
+    versions
+        .iter()
+        .map(|(name, version)| format!("{}={}", name, version))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+pub fn history_command() -> anyhow::Result<()> {
+    let generations = Generation::read_all()?;
+
+    if generations.is_empty() {
+        info!("No generations have been recorded yet, they are recorded by blueprint apply");
+        return Ok(());
+    }
+
+    for generation in generations {
+        info!(
+            "Generation {} from {} at {}{}",
+            generation.number,
+            generation.command,
+            format_timestamp(generation.created),
+            if generation.succeeded {
+                ""
+            } else {
+                ", which did not complete"
+            }
+        );
+
+        for backend in &generation.backends {
+            info!(
+                "  {}: {} installed, {} removed",
+                backend.backend,
+                backend.installed.len(),
+                backend.removed.len()
+            );
+
+            if !backend.installed.is_empty() {
+                info!("    installed: {}", format_versions(&backend.installed));
+            }
+            if !backend.removed.is_empty() {
+                info!("    removed: {}", format_versions(&backend.removed));
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -2,14 +2,15 @@
 //! with a basic default configuration file
 
 use anyhow::bail;
-use inquire::Confirm;
 use log::info;
 use std::{fs, path::PathBuf};
 
+use crate::prompt::confirm;
+
 /// Default file just include it as a str..
 const DEFAULT_TEMPLATE: &'static str = include_str!("../default.toml");
 
-pub fn init_command(file: String) -> anyhow::Result<()> {
+pub fn init_command(file: String, overwrite: bool) -> anyhow::Result<()> {
     // Path to the file
     let path = PathBuf::from(file);
 
@@ -17,16 +18,19 @@ pub fn init_command(file: String) -> anyhow::Result<()> {
     // set to false to disable at the end
     let mut generate_output = true;
 
-    // File already exists, prompt user
-    if path.exists() {
-        generate_output =
-            Confirm::new("Supplied template path already exists, overwrite this file?")
-                .with_default(false)
-                .prompt()?;
+    // File already exists, prompt user unless told to overwrite it
+    if path.exists() && !overwrite {
+        generate_output = confirm(
+            "Supplied template path already exists, overwrite this file?",
+            false,
+        )?;
     }
 
     if !generate_output {
-        bail!("Not generating template to {:?}, file already exists", path);
+        bail!(
+            "Not generating template to {:?}, file already exists, pass --overwrite to replace it",
+            path
+        );
     }
 
     // Write default template
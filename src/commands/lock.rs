@@ -0,0 +1,60 @@
+//! Records the exact installed versions of the package list into the
+//! lockfile, or checks the system still matches the lockfile
+
+use anyhow::bail;
+use log::info;
+
+use crate::{
+    package::lock::{Lockfile, lockfile_path},
+    parse_config::{LoadedConfig, load_config},
+    runner::ProcessRunner,
+};
+
+pub fn lock_command(
+    file: String,
+    section: String,
+    profiles: Vec<String>,
+    update: bool,
+) -> anyhow::Result<()> {
+    let LoadedConfig {
+        package_list,
+        root_file,
+        ..
+    } = load_config(file, section, profiles)?;
+    let lockfile_path = lockfile_path(&root_file);
+
+    let current = Lockfile::resolve(&ProcessRunner, &package_list, &root_file)?;
+
+    if update {
+        current.write(&lockfile_path)?;
+        info!(
+            "Locked {} packages into {:?}",
+            current.packages.len(),
+            lockfile_path
+        );
+        return Ok(());
+    }
+
+    // Differences are an error so the exit code can be checked
+    let differences = Lockfile::read(&lockfile_path)?.differences(&current);
+    if !differences.is_empty() {
+        bail!(
+            "System differs from the lockfile {:?} in {} ways:\n{}\nUpdate the lockfile with blueprint lock --update",
+            lockfile_path,
+            differences.len(),
+            differences
+                .iter()
+                .map(|difference| format!("  {}", difference))
+                .collect::<Vec<String>>()
+                .join("\n")
+        );
+    }
+
+    info!(
+        "System matches the {} packages in lockfile {:?}",
+        current.packages.len(),
+        lockfile_path
+    );
+
+    Ok(())
+}
@@ -3,6 +3,24 @@
 // Initialise a default template file.
 pub mod init;
 
+// Writes the packages installed on the system to a new file.
+pub mod capture;
+
 // Runs the package manager, ensuring all packages
 // match only what is provided
 pub mod apply;
+
+// Shows what apply would change without changing anything
+pub mod plan;
+
+// Reports where the system has drifted from the package list
+pub mod status;
+
+// Records and checks the exact versions of the package list
+pub mod lock;
+
+// Lists the generations recorded by every apply
+pub mod history;
+
+// Rolls the system back to before a generation
+pub mod rollback;
@@ -0,0 +1,128 @@
+//! Shows what applying the package list would change
+//! on the system without changing anything
+
+use std::collections::BTreeMap;
+
+use log::info;
+
+use crate::{
+    condition::Exclusion,
+    config::ROOT_CONFIG,
+    file::{FilePlan, ManagedFile},
+    package::{package::PackageList, plan::BackendPlan},
+    parse_config::{LoadedConfig, load_config},
+    profile::ActiveProfiles,
+    report::{record_file_plan, record_plans, record_service_plan},
+    runner::{CommandRunner, ProcessRunner},
+    service::{Service, ServicePlan},
+};
+
+/// Logs the active profiles and which packages each of them pulled in
+pub fn log_profiles(profiles: &ActiveProfiles, package_list: &PackageList) {
+    info!("Active profiles: {}", profiles);
+
+    let mut pulled_in: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for package in package_list.iter() {
+        for profile in &package.pulled_in_by {
+            pulled_in.entry(profile).or_default().push(&package.name);
+        }
+    }
+
+    for (profile, packages) in pulled_in {
+        info!(
+            "  profile {} pulled in {} packages: {}",
+            profile,
+            packages.len(),
+            packages.join(" ")
+        );
+    }
+}
+
+/// Logs every configuration entry which was left out as
+/// its condition did not hold or its profiles are inactive
+pub fn log_exclusions(excluded: &[Exclusion]) {
+    if excluded.is_empty() {
+        return;
+    }
+
+    info!("{} entries excluded:", excluded.len());
+    for exclusion in excluded {
+        info!("  excluded {}", exclusion);
+    }
+}
+
+/// Works out and logs the plan for every backend in the package list,
+/// leaving out removal if removing unrequired software is disabled
+pub fn log_package_list_plan(
+    runner: &dyn CommandRunner,
+    package_list: &PackageList,
+) -> anyhow::Result<Vec<BackendPlan>> {
+    let mut plans = package_list.plan_backends(runner)?;
+
+    if !ROOT_CONFIG.get_config().remove_unrequired_software {
+        info!("Removing unrequired software is disabled, only installation is planned");
+        plans = plans
+            .into_iter()
+            .map(BackendPlan::without_removal)
+            .collect();
+    }
+
+    plans.iter().for_each(BackendPlan::log);
+    record_plans(&plans);
+
+    Ok(plans)
+}
+
+/// Works out and logs what applying the services would change, units
+/// provided by packages which aren't installed yet can't be planned
+pub fn log_service_plan(
+    runner: &dyn CommandRunner,
+    services: &[Service],
+) -> anyhow::Result<Option<ServicePlan>> {
+    if services.is_empty() {
+        return Ok(None);
+    }
+
+    let plan = ServicePlan::new(runner, services)?;
+    plan.log();
+    record_service_plan(&plan);
+
+    Ok(Some(plan))
+}
+
+/// Works out and logs what applying the files would change,
+/// including every existing file they conflict with
+pub fn log_file_plan(
+    runner: &dyn CommandRunner,
+    files: &[ManagedFile],
+) -> anyhow::Result<Option<FilePlan>> {
+    if files.is_empty() {
+        return Ok(None);
+    }
+
+    let plan = FilePlan::new(runner, files)?;
+    plan.log();
+    record_file_plan(&plan);
+
+    Ok(Some(plan))
+}
+
+pub fn plan_command(file: String, section: String, profiles: Vec<String>) -> anyhow::Result<()> {
+    let LoadedConfig {
+        package_list,
+        excluded,
+        profiles,
+        services,
+        files,
+        ..
+    } = load_config(file, section, profiles)?;
+    info!("Planning apply on {} packages", package_list.len());
+    log_profiles(&profiles, &package_list);
+    log_exclusions(&excluded);
+
+    log_package_list_plan(&ProcessRunner, &package_list)?;
+    log_service_plan(&ProcessRunner, &services)?;
+    log_file_plan(&ProcessRunner, &files)?;
+
+    Ok(())
+}
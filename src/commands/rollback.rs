@@ -0,0 +1,128 @@
+//! Rolls back what a generation changed, reinstalling the packages
+//! it removed and removing the packages it installed
+
+use anyhow::bail;
+use log::info;
+
+use crate::{
+    package::{
+        history::{BackendRollback, Generation, record_generation, take_snapshot},
+        sources::SourceBackend,
+    },
+    parse_config::load_config,
+    prompt::{confirm, ensure_can_prompt},
+    runner::ProcessRunner,
+};
+
+/// Shows what rolling back a backend would change
+fn log_rollback(backend: SourceBackend, rollback: &BackendRollback) {
+    info!("Rolling back {}:", backend);
+
+    if !rollback.install.is_empty() {
+        info!(
+            "  reinstalling {} packages: {}",
+            rollback.install.len(),
+            rollback
+                .install
+                .iter()
+                .map(|(name, version)| format!("{}={}", name, version))
+                .collect::<Vec<String>>()
+                .join(" ")
+        );
+    }
+
+    if !rollback.remove.is_empty() {
+        info!(
+            "  removing {} packages: {}",
+            rollback.remove.len(),
+            rollback.remove.join(" ")
+        );
+    }
+
+    if !rollback.mark_explicit.is_empty() {
+        info!(
+            "  marking {} packages as explicitly installed: {}",
+            rollback.mark_explicit.len(),
+            rollback.mark_explicit.join(" ")
+        );
+    }
+
+    if !rollback.mark_dependency.is_empty() {
+        info!(
+            "  marking {} packages as installed as a dependency: {}",
+            rollback.mark_dependency.len(),
+            rollback.mark_dependency.join(" ")
+        );
+    }
+}
+
+pub fn rollback_command(
+    file: String,
+    section: String,
+    profiles: Vec<String>,
+    number: u32,
+) -> anyhow::Result<()> {
+    // Only the root configuration is needed, for how to elevate and prompt
+    load_config(file, section, profiles)?;
+
+    let generation = Generation::read(number)?;
+    let runner = ProcessRunner;
+
+    // Generations before it hold the options packages were installed with
+    let earlier: Vec<Generation> = Generation::read_all()?
+        .into_iter()
+        .filter(|earlier| earlier.number < number)
+        .collect();
+
+    // Undo only what the generation changed, which is still as it left it
+    let current = take_snapshot(
+        &runner,
+        generation.backends.iter().map(|backend| backend.backend),
+    )?;
+    let mut rollbacks = Vec::new();
+    for backend in &generation.backends {
+        if let Some(rollback) = backend.rollback_from(&current[&backend.backend], &earlier) {
+            log_rollback(backend.backend, &rollback);
+            rollbacks.push((backend.backend, rollback));
+        }
+    }
+
+    if rollbacks.is_empty() {
+        info!(
+            "Nothing generation {} changed is left to undo, nothing to roll back",
+            number
+        );
+        return Ok(());
+    }
+
+    // Fail now rather than hang on a prompt nobody can answer,
+    // or part way through when a source can't run as this user
+    ensure_can_prompt()?;
+    rollbacks
+        .iter()
+        .try_for_each(|(backend, _)| backend.native_source().privilege_command().map(|_| ()))?;
+
+    if !confirm(&format!("Roll back to before generation {}?", number), true)? {
+        bail!("Aborting rollback operation");
+    }
+
+    // The rollback is itself recorded, so it can be rolled back too
+    let rolled_back = rollbacks.iter().try_for_each(|(backend, rollback)| {
+        backend.rollback()(
+            &backend.native_source().privileged_runner(&runner)?,
+            rollback,
+        )
+    });
+    record_generation(
+        &runner,
+        &format!("rollback {}", number),
+        rolled_back.is_ok(),
+        None,
+        current,
+    );
+    rolled_back?;
+
+    info!("Rolled back to before generation {}", number);
+
+    Ok(())
+}
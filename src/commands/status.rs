@@ -0,0 +1,74 @@
+//! Reports where the system has drifted from the package list
+//! and services without changing anything on the system
+
+use std::fmt::Display;
+
+use log::info;
+
+use crate::{
+    parse_config::{LoadedConfig, load_config},
+    report::{record_plans, record_service_plan},
+    runner::ProcessRunner,
+    service::ServicePlan,
+};
+
+/// Exit code of status when the system has drifted, so
+/// drift can be told apart from blueprint failing
+pub const DRIFT_EXIT_CODE: i32 = 2;
+
+/// Error status returns when the system has drifted from the configuration
+#[derive(Debug)]
+pub struct Drift {
+    // How many packages have drifted
+    pub packages: usize,
+
+    // How many services have drifted
+    pub services: usize,
+}
+
+impl Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} packages and {} services have drifted from the configuration",
+            self.packages, self.services
+        )
+    }
+}
+
+impl std::error::Error for Drift {}
+
+pub fn status_command(file: String, section: String, profiles: Vec<String>) -> anyhow::Result<()> {
+    let LoadedConfig {
+        package_list,
+        services,
+        ..
+    } = load_config(file, section, profiles)?;
+    info!(
+        "Checking status of {} packages and {} services",
+        package_list.len(),
+        services.len()
+    );
+
+    let plans = package_list.plan_backends(&ProcessRunner)?;
+    plans.iter().for_each(|plan| plan.log_drift());
+    record_plans(&plans);
+
+    // Undeclared units are only checked for once services are managed at all
+    let service_plan = ServicePlan::new(&ProcessRunner, &services)?;
+    service_plan.log_drift();
+    record_service_plan(&service_plan);
+
+    // Drift is an error of its own so the exit code can be checked
+    let drift = Drift {
+        packages: plans.iter().map(|plan| plan.drift()).sum(),
+        services: service_plan.drift(),
+    };
+    if drift.packages > 0 || drift.services > 0 {
+        return Err(drift.into());
+    }
+
+    info!("System matches the package list and services");
+
+    Ok(())
+}
@@ -0,0 +1,333 @@
+//! Conditions on configuration entries, evaluated against facts about the
+//! host so one configuration can be shared between different machines
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+// Facts of this host, only gathered once the first condition is evaluated
+static FACTS: OnceLock<Facts> = OnceLock::new();
+
+/// Facts about the host blueprint is running on
+#[derive(Debug)]
+pub struct Facts {
+    // Hostname of the machine
+    pub hostname: String,
+
+    // ID of the distribution from /etc/os-release, such as arch or debian
+    pub distro: String,
+
+    // CPU architecture, such as x86_64 or aarch64
+    pub arch: String,
+
+    // Vendors of every GPU in the machine, such as nvidia, amd or intel
+    pub gpus: Vec<String>,
+}
+
+/// PCI vendor IDs of GPU vendors with the name used in conditions
+const GPU_VENDORS: [(&str, &str); 3] =
+    [("0x10de", "nvidia"), ("0x1002", "amd"), ("0x8086", "intel")];
+
+/// Reads the ID of the distribution from os-release, which may be quoted
+fn read_distro() -> anyhow::Result<String> {
+    let os_release = fs::read_to_string("/etc/os-release")
+        .context("While trying to read /etc/os-release to get the distro")?;
+
+    Ok(os_release
+        .lines()
+        .find_map(|line| line.strip_prefix("ID="))
+        .map(|id| id.trim_matches(['"', '\'']).to_string())
+        .unwrap_or_default())
+}
+
+/// Reads the vendor of every GPU from the DRM devices in /sys, a
+/// machine without any DRM devices simply has no GPUs
+fn read_gpus() -> Vec<String> {
+    let Ok(cards) = fs::read_dir("/sys/class/drm") else {
+        return Vec::new();
+    };
+
+    let mut gpus: Vec<String> = cards
+        .filter_map(Result::ok)
+        .filter_map(|card| fs::read_to_string(card.path().join("device/vendor")).ok())
+        .filter_map(|vendor_id| {
+            GPU_VENDORS
+                .iter()
+                .find(|(id, _)| *id == vendor_id.trim())
+                .map(|(_, vendor)| vendor.to_string())
+        })
+        .collect();
+
+    gpus.sort();
+    gpus.dedup();
+    gpus
+}
+
+impl Facts {
+    /// Gathers every fact about this host
+    fn gather() -> anyhow::Result<Self> {
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+            .context("While trying to read the hostname of the system")?
+            .trim()
+            .to_string();
+
+        Ok(Self {
+            hostname,
+            distro: read_distro()?,
+            arch: std::env::consts::ARCH.to_string(),
+            gpus: read_gpus(),
+        })
+    }
+
+    /// Gets the facts of this host, gathering them the first time
+    pub fn get() -> anyhow::Result<&'static Facts> {
+        if let Some(facts) = FACTS.get() {
+            return Ok(facts);
+        }
+
+        let facts = Facts::gather()?;
+        Ok(FACTS.get_or_init(|| facts))
+    }
+}
+
+/// One or more accepted values for a fact
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum FactValues {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl FactValues {
+    /// Every accepted value
+    fn values(&self) -> &[String] {
+        match self {
+            FactValues::One(value) => std::slice::from_ref(value),
+            FactValues::Many(values) => values,
+        }
+    }
+
+    /// Whether or not the fact is one of the accepted values
+    fn accepts(&self, fact: &str) -> bool {
+        self.values().iter().any(|value| value == fact)
+    }
+}
+
+impl Display for FactValues {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.values().join(" or "))
+    }
+}
+
+/// Condition on the facts of the host which must hold for a
+/// configuration entry to be used, every fact given must match
+/// one of its accepted values
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    // Hostnames of the machine
+    #[serde(default)]
+    pub hostname: Option<FactValues>,
+
+    // Distribution IDs from /etc/os-release
+    #[serde(default)]
+    pub distro: Option<FactValues>,
+
+    // CPU architectures
+    #[serde(default)]
+    pub arch: Option<FactValues>,
+
+    // GPU vendors, any GPU in the machine may match
+    #[serde(default)]
+    pub gpu: Option<FactValues>,
+
+    // Environment variables and the value each must have
+    #[serde(default)]
+    pub env: BTreeMap<String, FactValues>,
+}
+
+impl Condition {
+    /// Checks this condition against the facts of the host,
+    /// returning why it does not hold if it doesn't
+    pub fn unmet(&self, facts: &Facts) -> Option<String> {
+        let single_facts = [
+            ("hostname", &self.hostname, &facts.hostname),
+            ("distro", &self.distro, &facts.distro),
+            ("arch", &self.arch, &facts.arch),
+        ];
+
+        for (name, accepted, fact) in single_facts {
+            if let Some(accepted) = accepted
+                && !accepted.accepts(fact)
+            {
+                return Some(format!("{} is {:?}, not {}", name, fact, accepted));
+            }
+        }
+
+        if let Some(accepted) = &self.gpu
+            && !facts.gpus.iter().any(|gpu| accepted.accepts(gpu))
+        {
+            return Some(format!(
+                "gpu vendors are [{}], not {}",
+                facts.gpus.join(", "),
+                accepted
+            ));
+        }
+
+        for (variable, accepted) in &self.env {
+            let value = std::env::var(variable).unwrap_or_default();
+            if !accepted.accepts(&value) {
+                return Some(format!("env {} is {:?}, not {}", variable, value, accepted));
+            }
+        }
+
+        None
+    }
+}
+
+/// A configuration entry left out as its condition
+/// did not hold or none of its profiles are active
+#[derive(Debug)]
+pub struct Exclusion {
+    // Entry which was left out, such as "package htop"
+    pub entry: String,
+
+    // Why the entry was left out
+    pub reason: String,
+
+    // Configuration file declaring the entry
+    pub src: PathBuf,
+}
+
+impl Display for Exclusion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} in configuration file {:?}, as {}",
+            self.entry, self.src, self.reason
+        )
+    }
+}
+
+/// Keeps only the entries whose condition holds on this host, adding an
+/// exclusion for every entry left out. Entries are described by describe
+/// and their condition is gotten from condition
+pub fn retain_met<T>(
+    entries: &mut Vec<T>,
+    file_path: &Path,
+    excluded: &mut Vec<Exclusion>,
+    condition: impl Fn(&T) -> Option<&Condition>,
+    describe: impl Fn(&T) -> String,
+) -> anyhow::Result<()> {
+    // Facts aren't needed by configurations without any conditions
+    if !entries.iter().any(|entry| condition(entry).is_some()) {
+        return Ok(());
+    }
+
+    let facts = Facts::get()?;
+    entries.retain(|entry| {
+        let Some(reason) = condition(entry).and_then(|condition| condition.unmet(facts)) else {
+            return true;
+        };
+
+        excluded.push(Exclusion {
+            entry: describe(entry),
+            reason,
+            src: file_path.to_path_buf(),
+        });
+        false
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::package::Package;
+
+    /// Facts of a made up host
+    fn facts() -> Facts {
+        Facts {
+            hostname: "tower".to_string(),
+            distro: "arch".to_string(),
+            arch: "x86_64".to_string(),
+            gpus: vec!["amd".to_string(), "intel".to_string()],
+        }
+    }
+
+    #[test]
+    fn conditions_match_every_kind_of_fact() {
+        let home = std::env::var("HOME").unwrap_or_default();
+        let cases = [
+            (r#"hostname = "tower""#.to_string(), None),
+            (
+                r#"hostname = ["laptop", "desktop"]"#.to_string(),
+                Some(r#"hostname is "tower", not laptop or desktop"#.to_string()),
+            ),
+            (r#"distro = ["debian", "arch"]"#.to_string(), None),
+            (
+                r#"distro = "fedora""#.to_string(),
+                Some(r#"distro is "arch", not fedora"#.to_string()),
+            ),
+            (r#"arch = "x86_64""#.to_string(), None),
+            (
+                r#"arch = "aarch64""#.to_string(),
+                Some(r#"arch is "x86_64", not aarch64"#.to_string()),
+            ),
+            (r#"gpu = "intel""#.to_string(), None),
+            (
+                r#"gpu = "nvidia""#.to_string(),
+                Some("gpu vendors are [amd, intel], not nvidia".to_string()),
+            ),
+            (format!("env = {{ HOME = {:?} }}", home), None),
+            (r#"env = { BLUEPRINT_TEST_UNSET = "" }"#.to_string(), None),
+            (
+                r#"env = { BLUEPRINT_TEST_UNSET = "yes" }"#.to_string(),
+                Some(r#"env BLUEPRINT_TEST_UNSET is "", not yes"#.to_string()),
+            ),
+            // Every fact has to match, so one which doesn't fails it
+            (
+                r#"distro = "arch"
+                   hostname = "laptop""#
+                    .to_string(),
+                Some(r#"hostname is "tower", not laptop"#.to_string()),
+            ),
+        ];
+
+        for (condition, unmet) in cases {
+            let parsed: Condition = toml::from_str(&condition).unwrap();
+            assert_eq!(parsed.unmet(&facts()), unmet, "condition {}", condition);
+        }
+    }
+
+    #[test]
+    fn unknown_facts_are_an_error() {
+        let err = toml::from_str::<Condition>(r#"os = "linux""#).unwrap_err();
+
+        assert!(err.to_string().contains("unknown field `os`"));
+    }
+
+    #[test]
+    fn when_is_parsed_under_either_name() {
+        for entry in [
+            r#"name = "htop"
+               when = { distro = "arch" }"#,
+            r#"name = "htop"
+               if = { distro = "arch" }"#,
+        ] {
+            let package: Package = toml::from_str(entry).unwrap();
+            let distro = package.when.unwrap().distro.unwrap();
+
+            assert!(distro.accepts("arch"));
+            assert!(!distro.accepts("debian"));
+        }
+    }
+}
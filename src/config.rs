@@ -1,12 +1,17 @@
 //! Configuration structs and helpers for blueprint
 
-use std::sync::OnceLock;
+use std::{fmt::Display, path::PathBuf, sync::OnceLock};
 
+use anyhow::Context;
 use serde::Deserialize;
 
 use crate::{
+    condition::Exclusion,
+    file::ManagedFile,
+    hook::{Hook, HookFailure, RemovalHook},
     package::{package::PackageList, sources::PackageSource},
-    parse_config::{ConfigLink, MultiplePackage},
+    parse_config::{ConfigLink, MultiplePackage, PackageExclude},
+    service::Service,
 };
 
 /// Wrapper around oncelock config to help
@@ -48,6 +53,33 @@ pub struct Blueprint {
     // Shorthand for multiple packages under one source
     #[serde(alias = "multi_packages", alias = "install_all", default)]
     pub multi_packages: Vec<MultiplePackage>,
+
+    // Packages to subtract from the package list, even
+    // when they are declared by another file
+    #[serde(alias = "exclude", default)]
+    pub excludes: Vec<PackageExclude>,
+
+    // Commands to run at phases of every apply
+    #[serde(alias = "hook", default)]
+    pub hooks: Vec<Hook>,
+
+    // Systemd units to enable, disable or mask
+    #[serde(alias = "service", default)]
+    pub services: Vec<Service>,
+
+    // Files to symlink, copy or render into place
+    #[serde(alias = "file", alias = "link_file", default)]
+    pub files: Vec<ManagedFile>,
+
+    // Entries left out as their condition did not
+    // hold on this host or their profiles are inactive
+    #[serde(skip)]
+    pub excluded: Vec<Exclusion>,
+
+    // post_remove hooks of every package in this file,
+    // including those which were left out
+    #[serde(skip)]
+    pub removal_hooks: Vec<RemovalHook>,
 }
 
 /// Global blueprint configuration options.
@@ -58,8 +90,9 @@ pub struct Blueprint {
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
-    // Which shell to use for running package manager related commands?
-    // this should be posix compliant
+    // Which shell to use for running user supplied commands?
+    // this should be posix compliant. Package manager commands
+    // are ran directly and never go through the shell.
     #[serde(default = "default_shell")]
     pub shell: String,
 
@@ -73,6 +106,11 @@ pub struct Config {
     #[serde(default)]
     pub default_source: PackageSource,
 
+    // Profiles which are active when none are
+    // selected with --profile on the command line
+    #[serde(default)]
+    pub default_profiles: Vec<String>,
+
     // Confirm inside of blueprint whether or not to
     // continue the apply operation?
     #[serde(default = "default_is_true")]
@@ -92,6 +130,65 @@ pub struct Config {
     // as "unrequired"
     #[serde(default = "default_is_true")]
     pub remove_unrequired_software: bool,
+
+    // Packages which removing unrequired software must never
+    // remove, on top of the built-in defaults of each backend
+    #[serde(default)]
+    pub protected: Vec<String>,
+
+    // Most packages a single apply may remove
+    // before aborting, unless forced
+    #[serde(default)]
+    pub max_removals: Option<RemovalLimit>,
+
+    // Skip every prompt of blueprint and the package
+    // managers, the same as passing --yes
+    #[serde(default)]
+    pub non_interactive: bool,
+
+    // Command used to run package manager commands which
+    // need root, so blueprint itself can run unprivileged
+    #[serde(default)]
+    pub privilege_command: Option<PrivilegeCommand>,
+
+    // What happens to the apply when a hook fails,
+    // unless the hook sets its own on_failure
+    #[serde(default)]
+    pub hook_failure: HookFailure,
+
+    // Directory holding the system systemctl changes units of
+    // instead of the running system, such as a chroot
+    #[serde(default)]
+    pub systemd_root: Option<PathBuf>,
+}
+
+/// Commands which can run another command as root
+#[derive(Deserialize, Debug, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum PrivilegeCommand {
+    Sudo,
+    Doas,
+    Run0,
+}
+
+/// Limit on how many packages a single apply may remove, either
+/// an absolute count such as 25 or a percentage such as "10%"
+#[derive(Deserialize, Debug, Copy, Clone)]
+#[serde(try_from = "RemovalLimitValue")]
+pub enum RemovalLimit {
+    // At most this many packages
+    Count(usize),
+
+    // At most this percentage of the installed packages
+    Percentage(f64),
+}
+
+/// How a removal limit can be written in the configuration
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RemovalLimitValue {
+    Count(usize),
+    Text(String),
 }
 
 fn default_is_true() -> bool {
@@ -108,6 +205,74 @@ impl Default for Config {
             prompt_removal_per_source: default_is_true(),
             remove_unrequired_software: default_is_true(),
             default_source: PackageSource::default(),
+            default_profiles: Vec::new(),
+            protected: Vec::new(),
+            max_removals: None,
+            non_interactive: false,
+            privilege_command: None,
+            hook_failure: HookFailure::default(),
+            systemd_root: None,
+        }
+    }
+}
+
+impl TryFrom<RemovalLimitValue> for RemovalLimit {
+    type Error = anyhow::Error;
+
+    fn try_from(value: RemovalLimitValue) -> Result<Self, Self::Error> {
+        let text = match value {
+            RemovalLimitValue::Count(count) => return Ok(RemovalLimit::Count(count)),
+            RemovalLimitValue::Text(text) => text,
+        };
+
+        let invalid = || {
+            format!(
+                "Invalid removal limit {:?}, expected a count such as 25 or a percentage such as \"10%\"",
+                text
+            )
+        };
+
+        match text.trim().strip_suffix('%') {
+            Some(percentage) => Ok(RemovalLimit::Percentage(
+                percentage.trim().parse().with_context(invalid)?,
+            )),
+            None => Ok(RemovalLimit::Count(
+                text.trim().parse().with_context(invalid)?,
+            )),
+        }
+    }
+}
+
+impl RemovalLimit {
+    /// How many packages may be removed out of the installed packages
+    pub fn allowed(&self, installed: usize) -> usize {
+        match self {
+            RemovalLimit::Count(count) => *count,
+            RemovalLimit::Percentage(percentage) => {
+                (installed as f64 * percentage / 100.0).floor() as usize
+            }
+        }
+    }
+}
+
+impl PrivilegeCommand {
+    /// Name of the program to run
+    pub fn program(&self) -> &'static str {
+        match self {
+            PrivilegeCommand::Sudo => "sudo",
+            PrivilegeCommand::Doas => "doas",
+            PrivilegeCommand::Run0 => "run0",
+        }
+    }
+}
+
+impl Display for RemovalLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RemovalLimit::Count(count) => write!(f, "{} packages", count),
+            RemovalLimit::Percentage(percentage) => {
+                write!(f, "{}% of installed packages", percentage)
+            }
         }
     }
 }
@@ -158,3 +323,17 @@ impl GlobalConfig {
         ROOT_CONFIG.0.get().is_some()
     }
 }
+
+#[cfg(test)]
+impl GlobalConfig {
+    /// Sets the config every test runs with, it can only be set
+    /// once so whichever test runs first sets it for all of them
+    pub fn set_test_config(&self) {
+        self.set_config(Config {
+            non_interactive: true,
+            privilege_command: Some(PrivilegeCommand::Sudo),
+            systemd_root: Some(PathBuf::from("/mnt")),
+            ..Config::default()
+        });
+    }
+}
@@ -0,0 +1,945 @@
+//! Files declared in the configuration, which are symlinked, copied or
+//! rendered from a template into place once the packages are applied
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt::Display,
+    fs,
+    io::{ErrorKind, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt, symlink},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, bail};
+use log::info;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cleanpath::CleanPath,
+    condition::Condition,
+    config::ROOT_CONFIG,
+    package::history::state_home,
+    runner::{CommandRunner, PrivilegedRunner, is_root},
+};
+
+/// File under the state directory recording the files blueprint has written
+const MANAGED_FILES_FILE: &str = "blueprint/files.json";
+
+/// Suffix of the backups of conflicting files, followed by when it was made
+const BACKUP_SUFFIX: &str = "blueprint-backup";
+
+/// How a file is put into place
+#[derive(Deserialize, Serialize, Debug, PartialEq, Copy, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    // Symlink the target to the source
+    #[default]
+    Link,
+
+    // Copy the source to the target
+    Copy,
+
+    // Render the source as a template to the target
+    Template,
+}
+
+/// Permission bits of a file, written in octal such as "644"
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct FileMode(u32);
+
+/// A file put into place from the blueprint configuration
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ManagedFile {
+    // File to put into place, relative to the declaring configuration file
+    pub source: PathBuf,
+
+    // Where to put the file, relative to the declaring configuration file
+    pub target: PathBuf,
+
+    // How to put the file into place
+    #[serde(default)]
+    pub kind: FileKind,
+
+    // Permission bits of the target, copies and templates only
+    #[serde(default)]
+    pub mode: Option<FileMode>,
+
+    // Owner of the target as user or user:group, copies and templates only
+    #[serde(default)]
+    pub owner: Option<String>,
+
+    // Variables substituted into the template as {{ name }}, templates only
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+
+    // Condition on the host which must hold for this file to be used
+    #[serde(default, alias = "if")]
+    pub when: Option<Condition>,
+
+    // Profiles this file belongs to, it is only used when one of
+    // them is active. Without any profiles it is always used
+    #[serde(default)]
+    pub profiles: Vec<String>,
+
+    // What file is this file defined in for debugging info
+    #[serde(skip)]
+    pub src: PathBuf,
+}
+
+/// What putting a file into place does to its target
+#[derive(Serialize, Debug, PartialEq, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum FileAction {
+    // The target doesn't exist yet
+    Create,
+
+    // The target was written by blueprint and its source has changed since
+    Update,
+
+    // The target is up to date other than its mode or owner
+    Permissions,
+
+    // The target conflicts with an existing file, which is backed up first
+    Replace,
+}
+
+/// Change needed to bring a declared file into place
+#[derive(Debug)]
+pub struct FileChange {
+    // Where the file is put
+    pub target: PathBuf,
+
+    // File which is put into place
+    pub source: PathBuf,
+
+    // How the file is put into place
+    pub kind: FileKind,
+
+    // What happens to the target
+    pub action: FileAction,
+
+    // What the conflicting file is, for replaced targets
+    pub conflict: Option<String>,
+
+    // Content written to the target, for copies and templates
+    content: Option<Vec<u8>>,
+
+    // Permission bits to set on the target
+    mode: Option<FileMode>,
+
+    // Owner to set on the target
+    owner: Option<String>,
+}
+
+/// What applying the declared files would change on the system
+#[derive(Debug, Default)]
+pub struct FilePlan {
+    // Declared files which aren't in place
+    pub changes: Vec<FileChange>,
+
+    // Hashes of the content of every copied or rendered file, recorded
+    // once applied so later changes to their sources aren't conflicts
+    written: BTreeMap<PathBuf, String>,
+}
+
+/// Files blueprint has written, so changes to their sources update
+/// them rather than conflicting with them
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct ManagedFiles {
+    // Target -> hash of the content blueprint wrote to it
+    files: BTreeMap<PathBuf, String>,
+}
+
+impl TryFrom<String> for FileMode {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        match u32::from_str_radix(&text, 8) {
+            Ok(mode) if mode <= 0o7777 => Ok(FileMode(mode)),
+            _ => bail!(
+                "Invalid file mode {:?}, expected octal permission bits such as \"644\"",
+                text
+            ),
+        }
+    }
+}
+
+impl Display for FileMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:o}", self.0)
+    }
+}
+
+impl Display for FileKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileKind::Link => write!(f, "link"),
+            FileKind::Copy => write!(f, "copy"),
+            FileKind::Template => write!(f, "template"),
+        }
+    }
+}
+
+impl Display for FileAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileAction::Create => write!(f, "create"),
+            FileAction::Update => write!(f, "update"),
+            FileAction::Permissions => write!(f, "set permissions of"),
+            FileAction::Replace => write!(f, "replace"),
+        }
+    }
+}
+
+impl Display for ManagedFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {:?}, referenced in configuration file {:?}",
+            self.kind, self.target, self.src
+        )
+    }
+}
+
+/// Hashes file content with 64 bit FNV-1a, which is stable
+/// between builds unlike the hasher of the standard library
+fn hash_content(content: &[u8]) -> String {
+    let hash = content.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    });
+
+    format!("{:016x}", hash)
+}
+
+/// Path of the managed files record under the state directory
+fn managed_files_path() -> anyhow::Result<PathBuf> {
+    Ok(state_home()?.join(MANAGED_FILES_FILE))
+}
+
+impl ManagedFiles {
+    /// Reads the record of managed files, which is empty
+    /// if blueprint hasn't written any files yet
+    fn read() -> anyhow::Result<Self> {
+        let path = managed_files_path()?;
+
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("While trying to read managed files {:?}", path));
+            }
+        };
+
+        serde_json::from_str(&content)
+            .with_context(|| format!("While trying to parse managed files {:?}", path))
+    }
+
+    /// Writes the record of managed files
+    fn write(&self) -> anyhow::Result<()> {
+        let path = managed_files_path()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("While trying to create state directory {:?}", parent))?;
+        }
+
+        let content = serde_json::to_string_pretty(self)
+            .context("While trying to convert managed files to JSON")?;
+        fs::write(&path, content)
+            .with_context(|| format!("While trying to write managed files {:?}", path))
+    }
+}
+
+impl ManagedFile {
+    /// Resolves the source and target from the perspective of the declaring
+    /// configuration file, the same as links, and ensures the file only
+    /// uses options its kind supports
+    pub fn resolve(&mut self, file_path: &PathBuf) -> anyhow::Result<()> {
+        self.src = file_path.clean_path()?;
+        let parent = self
+            .src
+            .parent()
+            .context("Configuration file has no parent directory")?;
+        self.source = parent.join(&self.source).clean_path()?;
+        self.target = parent.join(&self.target).clean_path()?;
+
+        if self.kind == FileKind::Link && (self.mode.is_some() || self.owner.is_some()) {
+            bail!(
+                "Option mode or owner is not supported by {}, links take the permissions of their source",
+                self
+            );
+        }
+
+        if self.kind != FileKind::Template && !self.variables.is_empty() {
+            bail!(
+                "Option variables is only supported by templates, used by {}",
+                self
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Content the target should have, for copies and templates
+    fn content(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        if !self.source.exists() {
+            bail!("Source {:?} of {} does not exist", self.source, self);
+        }
+
+        let content = match self.kind {
+            FileKind::Link => return Ok(None),
+            FileKind::Copy => fs::read(&self.source),
+            FileKind::Template => {
+                return fs::read_to_string(&self.source)
+                    .with_context(|| {
+                        format!("While trying to read source {:?} of {}", self.source, self)
+                    })
+                    .and_then(|template| self.render(&template))
+                    .map(|rendered| Some(rendered.into_bytes()));
+            }
+        };
+
+        content
+            .map(Some)
+            .with_context(|| format!("While trying to read source {:?} of {}", self.source, self))
+    }
+
+    /// Substitutes the variables into every {{ name }} of the template
+    fn render(&self, template: &str) -> anyhow::Result<String> {
+        let variable = Regex::new(r"\{\{\s*([A-Za-z0-9_]+)\s*\}\}")
+            .context("While trying to compile the template variable pattern")?;
+
+        if let Some(missing) = variable
+            .captures_iter(template)
+            .map(|captures| captures[1].to_string())
+            .find(|name| !self.variables.contains_key(name))
+        {
+            bail!(
+                "Template variable {} of {} is not set in variables",
+                missing,
+                self
+            );
+        }
+
+        Ok(variable
+            .replace_all(template, |captures: &regex::Captures| {
+                self.variables[&captures[1]].clone()
+            })
+            .into_owned())
+    }
+
+    /// Whether the mode and owner of an existing target are as declared
+    fn permissions_match(
+        &self,
+        runner: &dyn CommandRunner,
+        metadata: &fs::Metadata,
+    ) -> anyhow::Result<bool> {
+        if let Some(mode) = self.mode
+            && metadata.permissions().mode() & 0o7777 != mode.0
+        {
+            return Ok(false);
+        }
+
+        let Some(owner) = &self.owner else {
+            return Ok(true);
+        };
+
+        let target = self.target.to_string_lossy().to_string();
+        let current = runner
+            .query_stdout(&[
+                "stat".to_string(),
+                "-c".to_string(),
+                "%U:%G".to_string(),
+                "--".to_string(),
+                target,
+            ])
+            .with_context(|| format!("While trying to run stat to get the owner of {}", self))?;
+
+        // Only the user is compared when no group is declared
+        let current = current.trim();
+        Ok(match owner.contains(':') {
+            true => current == owner,
+            false => current.split(':').next() == Some(owner.as_str()),
+        })
+    }
+
+    /// Works out what putting this file into place changes, if anything
+    fn plan(
+        &self,
+        runner: &dyn CommandRunner,
+        managed: &ManagedFiles,
+        content: Option<Vec<u8>>,
+    ) -> anyhow::Result<Option<FileChange>> {
+        let change = |action: FileAction, conflict: Option<String>| FileChange {
+            target: self.target.clone(),
+            source: self.source.clone(),
+            kind: self.kind,
+            action,
+            conflict,
+            content: content.clone(),
+            mode: self.mode,
+            owner: self.owner.clone(),
+        };
+
+        // Symlinks are looked at themselves rather than what they point to
+        let metadata = match fs::symlink_metadata(&self.target) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Ok(Some(change(FileAction::Create, None)));
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("While trying to inspect {}", self));
+            }
+        };
+
+        let file_type = metadata.file_type();
+        let existing = if file_type.is_symlink() {
+            let points_to = fs::read_link(&self.target)
+                .with_context(|| format!("While trying to read the symlink of {}", self))?;
+            if self.kind == FileKind::Link && points_to == self.source {
+                return Ok(None);
+            }
+            format!("is a symlink to {:?}", points_to)
+        } else if file_type.is_dir() {
+            String::from("is a directory")
+        } else if let Some(content) = &content {
+            let current = fs::read(&self.target)
+                .with_context(|| format!("While trying to read the target of {}", self))?;
+
+            if current == *content {
+                return Ok((!self.permissions_match(runner, &metadata)?)
+                    .then(|| change(FileAction::Permissions, None)));
+            }
+
+            // Files blueprint wrote are only updated if nobody has changed them since
+            match managed.files.get(&self.target) {
+                Some(hash) if *hash == hash_content(&current) => {
+                    return Ok(Some(change(FileAction::Update, None)));
+                }
+                Some(_) => String::from("has been changed since blueprint wrote it"),
+                None => String::from("is a file blueprint did not write"),
+            }
+        } else {
+            String::from("is a file")
+        };
+
+        Ok(Some(change(FileAction::Replace, Some(existing))))
+    }
+}
+
+/// Ensures blueprint can write the files with an owner, which needs root,
+/// elevated through the privilege command when not root
+pub fn privilege_command(files: &[ManagedFile]) -> anyhow::Result<Option<&'static str>> {
+    if is_root() || files.iter().all(|file| file.owner.is_none()) {
+        return Ok(None);
+    }
+
+    match ROOT_CONFIG.get_config().privilege_command {
+        Some(privilege_command) => Ok(Some(privilege_command.program())),
+        None => bail!(
+            "Writing files with an owner needs root, set privilege_command in the config or run blueprint as root"
+        ),
+    }
+}
+
+/// Path a conflicting file is moved to before being replaced
+fn backup_path(target: &Path) -> PathBuf {
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+
+    let mut backup = OsString::from(target.as_os_str());
+    backup.push(format!(".{}-{}", BACKUP_SUFFIX, created));
+    PathBuf::from(backup)
+}
+
+/// Runs a command changing a file, failing when it doesn't succeed
+fn run_file_command(
+    runner: &dyn CommandRunner,
+    command: &[String],
+    doing: &str,
+) -> anyhow::Result<()> {
+    let exit_status = runner
+        .run(command)
+        .with_context(|| format!("While trying to run {} to {}", command[0], doing))?
+        .status;
+
+    if !exit_status.success() {
+        bail!(
+            "{} did not successfully {}, returned exit code of {}, aborting",
+            command[0],
+            doing,
+            exit_status
+        );
+    }
+
+    Ok(())
+}
+
+impl FileChange {
+    /// Puts the file into place, backing up the conflicting file first
+    fn apply(&self, runner: &dyn CommandRunner) -> anyhow::Result<()> {
+        if let Some(owner) = &self.owner {
+            return self.apply_owned(runner, owner);
+        }
+
+        if self.action == FileAction::Replace {
+            let backup = backup_path(&self.target);
+            fs::rename(&self.target, &backup).with_context(|| {
+                format!("While trying to back up {:?} to {:?}", self.target, backup)
+            })?;
+            info!("Backed up {:?} to {:?}", self.target, backup);
+        }
+
+        if self.action != FileAction::Permissions {
+            if let Some(parent) = self.target.parent() {
+                fs::create_dir_all(parent).with_context(|| {
+                    format!("While trying to create the directory of {:?}", self.target)
+                })?;
+            }
+
+            match &self.content {
+                Some(content) => fs::write(&self.target, content),
+                None => symlink(&self.source, &self.target),
+            }
+            .with_context(|| {
+                format!(
+                    "While trying to {} {:?} from {:?}",
+                    self.kind, self.target, self.source
+                )
+            })?;
+        }
+
+        if let Some(mode) = self.mode {
+            fs::set_permissions(&self.target, fs::Permissions::from_mode(mode.0)).with_context(
+                || {
+                    format!(
+                        "While trying to set the mode of {:?} to {}",
+                        self.target, mode
+                    )
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Puts a copied or rendered file with an owner into place, every
+    /// change to the target runs through the runner as it needs root.
+    /// The content is staged in a temporary file installed from there
+    fn apply_owned(&self, runner: &dyn CommandRunner, owner: &str) -> anyhow::Result<()> {
+        let target = self.target.to_string_lossy().to_string();
+        let mode = self.mode.unwrap_or(FileMode(0o644)).to_string();
+        let (user, group) = match owner.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (owner, None),
+        };
+
+        if self.action == FileAction::Replace {
+            let backup = backup_path(&self.target);
+            run_file_command(
+                runner,
+                &[
+                    "mv".to_string(),
+                    "--".to_string(),
+                    target.clone(),
+                    backup.to_string_lossy().to_string(),
+                ],
+                &format!("back up {:?} to {:?}", self.target, backup),
+            )?;
+            info!("Backed up {:?} to {:?}", self.target, backup);
+        }
+
+        if self.action == FileAction::Permissions {
+            if self.mode.is_some() {
+                run_file_command(
+                    runner,
+                    &["chmod".to_string(), mode, "--".to_string(), target.clone()],
+                    &format!("set the mode of {:?}", self.target),
+                )?;
+            }
+            return run_file_command(
+                runner,
+                &[
+                    "chown".to_string(),
+                    owner.to_string(),
+                    "--".to_string(),
+                    target,
+                ],
+                &format!("set the owner of {:?} to {}", self.target, owner),
+            );
+        }
+
+        let content = self.content.as_ref().with_context(|| {
+            format!(
+                "Only copied and templated files can have an owner, but {:?} is a {}",
+                self.target, self.kind
+            )
+        })?;
+        let staged = std::env::temp_dir().join(format!(
+            "blueprint-{}-{}",
+            std::process::id(),
+            hash_content(target.as_bytes())
+        ));
+
+        // Only whoever runs blueprint can read the staged content, and
+        // a file left over from an earlier run is never followed
+        let _ = fs::remove_file(&staged);
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&staged)
+            .and_then(|mut file| file.write_all(content))
+            .with_context(|| {
+                format!(
+                    "While trying to stage the content of {:?} in {:?}",
+                    self.target, staged
+                )
+            })?;
+
+        let mut command = vec![
+            "install".to_string(),
+            "-D".to_string(),
+            "-T".to_string(),
+            "-m".to_string(),
+            mode,
+            "-o".to_string(),
+            user.to_string(),
+        ];
+        if let Some(group) = group {
+            command.extend(["-g".to_string(), group.to_string()]);
+        }
+        command.extend([
+            "--".to_string(),
+            staged.to_string_lossy().to_string(),
+            target,
+        ]);
+
+        let installed = run_file_command(
+            runner,
+            &command,
+            &format!("{} {:?} from {:?}", self.kind, self.target, self.source),
+        );
+        let _ = fs::remove_file(&staged);
+
+        installed
+    }
+}
+
+impl FilePlan {
+    /// Works out what applying the files would change
+    /// on the system, without changing anything
+    pub fn new(runner: &dyn CommandRunner, files: &[ManagedFile]) -> anyhow::Result<Self> {
+        let mut plan = FilePlan::default();
+        if files.is_empty() {
+            return Ok(plan);
+        }
+
+        let managed = ManagedFiles::read()?;
+        for file in files {
+            let content = file.content()?;
+            if let Some(content) = &content {
+                plan.written
+                    .insert(file.target.clone(), hash_content(content));
+            }
+
+            if let Some(change) = file.plan(runner, &managed, content)? {
+                plan.changes.push(change);
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// Targets which conflict with existing files
+    pub fn conflicts(&self) -> impl Iterator<Item = &FileChange> {
+        self.changes
+            .iter()
+            .filter(|change| change.action == FileAction::Replace)
+    }
+
+    /// Ensures no existing files are replaced, unless they
+    /// are to be backed up first and replaced
+    pub fn ensure_no_conflicts(&self, backup_conflicts: bool) -> anyhow::Result<()> {
+        let conflicts = self.conflicts().count();
+        if conflicts == 0 || backup_conflicts {
+            return Ok(());
+        }
+
+        bail!(
+            "{} declared files conflict with existing files, pass --backup-conflicts to back them up and replace them",
+            conflicts
+        );
+    }
+
+    /// Logs what applying the files would change
+    pub fn log(&self) {
+        if self.changes.is_empty() {
+            info!("Files are up to date, nothing to do");
+            return;
+        }
+
+        info!("Plan for files:");
+        for change in &self.changes {
+            info!(
+                "  {} {} {:?} from {:?}{}",
+                change.action,
+                change.kind,
+                change.target,
+                change.source,
+                change
+                    .conflict
+                    .as_ref()
+                    .map(|conflict| format!(
+                        ", which conflicts as it {} and is backed up with --backup-conflicts",
+                        conflict
+                    ))
+                    .unwrap_or_default()
+            );
+        }
+    }
+
+    /// Puts every file of the plan into place and records
+    /// the files blueprint has written
+    pub fn apply(&self, runner: &dyn CommandRunner, files: &[ManagedFile]) -> anyhow::Result<()> {
+        if self.changes.is_empty() {
+            info!("Files are up to date, nothing to do");
+        } else {
+            let privileged = PrivilegedRunner::new(runner, privilege_command(files)?);
+            self.changes
+                .iter()
+                .try_for_each(|change| change.apply(&privileged))?;
+
+            info!(
+                "Completed putting {} files into place, proceeding",
+                self.changes.len()
+            );
+        }
+
+        if self.written.is_empty() {
+            return Ok(());
+        }
+
+        let mut managed = ManagedFiles::read()?;
+        managed.files.extend(self.written.clone());
+        managed.write()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{RecordingRunner, argv};
+
+    /// Empty directory of its own for a test to put files in
+    fn test_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("blueprint-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    /// File of the kind declared from source to target
+    fn managed_file(kind: FileKind, source: PathBuf, target: PathBuf) -> ManagedFile {
+        ManagedFile {
+            source,
+            target,
+            kind,
+            mode: None,
+            owner: None,
+            variables: BTreeMap::new(),
+            when: None,
+            profiles: Vec::new(),
+            src: PathBuf::from("blueprint.toml"),
+        }
+    }
+
+    #[test]
+    fn templates_render_known_variables_only() {
+        let mut file = managed_file(
+            FileKind::Template,
+            PathBuf::from("motd"),
+            PathBuf::from("/etc/motd"),
+        );
+        file.variables
+            .insert("host".to_string(), "desktop".to_string());
+
+        assert_eq!(
+            file.render("Welcome to {{ host }}, {{host}}!").unwrap(),
+            "Welcome to desktop, desktop!"
+        );
+        assert!(
+            file.render("{{ host }} runs {{ distro }}")
+                .unwrap_err()
+                .to_string()
+                .contains("Template variable distro")
+        );
+    }
+
+    #[test]
+    fn sources_resolve_relative_to_the_declaring_config() {
+        let mut file = managed_file(
+            FileKind::Copy,
+            PathBuf::from("files/bashrc"),
+            PathBuf::from("../home/.bashrc"),
+        );
+
+        file.resolve(&PathBuf::from("/blueprint/hosts/desktop.toml"))
+            .unwrap();
+
+        assert_eq!(file.src, PathBuf::from("/blueprint/hosts/desktop.toml"));
+        assert_eq!(file.source, PathBuf::from("/blueprint/hosts/files/bashrc"));
+        assert_eq!(file.target, PathBuf::from("/blueprint/home/.bashrc"));
+    }
+
+    #[test]
+    fn plan_tells_conflicts_from_managed_and_linked_files() {
+        let directory = test_directory("plan");
+        let runner = RecordingRunner::default();
+        let source = directory.join("source");
+        fs::write(&source, "declared").unwrap();
+
+        // A symlink already pointing at the source is in place
+        let linked = directory.join("linked");
+        symlink(&source, &linked).unwrap();
+        let link = managed_file(FileKind::Link, source.clone(), linked);
+        assert!(
+            link.plan(&runner, &ManagedFiles::default(), None)
+                .unwrap()
+                .is_none()
+        );
+
+        // A different file blueprint didn't write conflicts
+        let target = directory.join("target");
+        fs::write(&target, "by hand").unwrap();
+        let copy = managed_file(FileKind::Copy, source.clone(), target.clone());
+        let content = Some(b"declared".to_vec());
+        let change = copy
+            .plan(&runner, &ManagedFiles::default(), content.clone())
+            .unwrap()
+            .unwrap();
+        assert_eq!(change.action, FileAction::Replace);
+        assert_eq!(
+            change.conflict.as_deref(),
+            Some("is a file blueprint did not write")
+        );
+
+        // The same file blueprint wrote itself is updated
+        let managed = ManagedFiles {
+            files: BTreeMap::from([(target.clone(), hash_content(b"by hand"))]),
+        };
+        let change = copy
+            .plan(&runner, &managed, content.clone())
+            .unwrap()
+            .unwrap();
+        assert_eq!(change.action, FileAction::Update);
+        assert_eq!(change.conflict, None);
+
+        // Unless it has been changed since blueprint wrote it
+        let managed = ManagedFiles {
+            files: BTreeMap::from([(target, hash_content(b"written"))]),
+        };
+        let change = copy.plan(&runner, &managed, content).unwrap().unwrap();
+        assert_eq!(change.action, FileAction::Replace);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn conflicts_are_only_replaced_after_backing_them_up() {
+        let directory = test_directory("backup");
+        let runner = RecordingRunner::default();
+        let source = directory.join("source");
+        let target = directory.join("target");
+        fs::write(&source, "declared").unwrap();
+        fs::write(&target, "by hand").unwrap();
+
+        let copy = managed_file(FileKind::Copy, source, target.clone());
+        let content = copy.content().unwrap();
+        let plan = FilePlan {
+            changes: vec![
+                copy.plan(&runner, &ManagedFiles::default(), content)
+                    .unwrap()
+                    .unwrap(),
+            ],
+            written: BTreeMap::new(),
+        };
+
+        assert!(plan.ensure_no_conflicts(false).is_err());
+        plan.ensure_no_conflicts(true).unwrap();
+        plan.changes[0].apply(&runner).unwrap();
+
+        let backups: Vec<PathBuf> = fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.to_string_lossy()
+                    .contains(&format!("target.{}-", BACKUP_SUFFIX))
+            })
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(fs::read_to_string(&backups[0]).unwrap(), "by hand");
+        assert_eq!(fs::read_to_string(&target).unwrap(), "declared");
+        assert!(runner.commands().is_empty());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn copies_with_an_owner_are_installed_through_the_runner() {
+        let runner = RecordingRunner::default();
+        let target = PathBuf::from("/etc/blueprint-owned.conf");
+        let change = FileChange {
+            target: target.clone(),
+            source: PathBuf::from("/blueprint/owned.conf"),
+            kind: FileKind::Copy,
+            action: FileAction::Replace,
+            conflict: Some(String::from("is a file blueprint did not write")),
+            content: Some(b"declared".to_vec()),
+            mode: Some(FileMode(0o640)),
+            owner: Some("root:wheel".to_string()),
+        };
+
+        change.apply(&runner).unwrap();
+
+        let staged = std::env::temp_dir().join(format!(
+            "blueprint-{}-{}",
+            std::process::id(),
+            hash_content(b"/etc/blueprint-owned.conf")
+        ));
+        let commands = runner.commands();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(
+            commands[0][..3],
+            argv(&["mv", "--", "/etc/blueprint-owned.conf"])
+        );
+        assert!(
+            commands[0][3].starts_with(&format!("/etc/blueprint-owned.conf.{}-", BACKUP_SUFFIX))
+        );
+        assert_eq!(
+            commands[1],
+            argv(&[
+                "install",
+                "-D",
+                "-T",
+                "-m",
+                "640",
+                "-o",
+                "root",
+                "-g",
+                "wheel",
+                "--",
+                &staged.to_string_lossy(),
+                "/etc/blueprint-owned.conf"
+            ])
+        );
+        assert!(!staged.exists());
+    }
+}
@@ -0,0 +1,595 @@
+//! User supplied commands ran around package operations, such as enabling
+//! a service after installing a package, ran through the configured shell
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    path::PathBuf,
+};
+
+use anyhow::{Context, bail};
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::{
+    config::ROOT_CONFIG,
+    package::{
+        history::Snapshot,
+        package::PackageList,
+        sources::{PackageSource, SourceBackend},
+    },
+    runner::CommandRunner,
+};
+
+/// Point during apply a global hook runs at
+#[derive(Deserialize, Debug, PartialEq, Copy, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum HookPhase {
+    // Before anything is installed or removed
+    BeforeApply,
+
+    // After installing, when any package was installed or changed version
+    AfterInstall,
+
+    // After removing, when any package was removed
+    AfterRemove,
+
+    // After everything has been applied successfully
+    AfterApply,
+}
+
+/// What happens to the apply when a hook fails
+#[derive(Deserialize, Debug, PartialEq, Copy, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HookFailure {
+    // Stop the apply with an error
+    #[default]
+    Abort,
+
+    // Warn about the failure and carry on
+    Warn,
+}
+
+/// A command ran at a phase of every apply
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    // Phase of the apply to run at
+    pub phase: HookPhase,
+
+    // Command to run through the shell
+    pub run: String,
+
+    // Only run for the packages of this source's backend
+    #[serde(default)]
+    pub source: Option<PackageSource>,
+
+    // What to do when the command fails, the
+    // hook_failure of the config if not given
+    #[serde(default)]
+    pub on_failure: Option<HookFailure>,
+
+    // What file is this hook defined in for debugging info
+    #[serde(skip)]
+    pub src: PathBuf,
+}
+
+/// A post_remove hook of a package, kept even when the package is left
+/// out of the package list as that is what gets it removed
+#[derive(Debug)]
+pub struct RemovalHook {
+    // Name of the package
+    pub name: String,
+
+    // Backend the package is installed into
+    pub backend: SourceBackend,
+
+    // Command to run through the shell
+    pub run: String,
+
+    // Configuration file declaring the package
+    pub src: PathBuf,
+}
+
+/// Every hook of the configuration
+#[derive(Debug)]
+pub struct Hooks {
+    // Global hooks keyed on phase
+    pub phases: Vec<Hook>,
+
+    // post_remove hooks of every package
+    pub removals: Vec<RemovalHook>,
+}
+
+impl Display for HookPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HookPhase::BeforeApply => write!(f, "before_apply"),
+            HookPhase::AfterInstall => write!(f, "after_install"),
+            HookPhase::AfterRemove => write!(f, "after_remove"),
+            HookPhase::AfterApply => write!(f, "after_apply"),
+        }
+    }
+}
+
+/// Runs a hook command through the configured shell with the environment
+/// variables set, a failure is handled according to the failure policy
+fn run_hook(
+    runner: &dyn CommandRunner,
+    description: &str,
+    command: &str,
+    environment: &[(&str, String)],
+    on_failure: HookFailure,
+) -> anyhow::Result<()> {
+    let config = ROOT_CONFIG.get_config();
+    info!("Running {}: {}", description, command);
+
+    // Variables are passed through env so the runner
+    // still receives a plain argument vector
+    let mut hook_command = vec![String::from("env")];
+    hook_command.extend(
+        environment
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value)),
+    );
+    hook_command.extend([
+        config.shell.clone(),
+        config.shell_exec_arg.clone(),
+        command.to_string(),
+    ]);
+
+    let exit_status = runner
+        .run(&hook_command)
+        .with_context(|| format!("While trying to run {}", description))?
+        .status;
+
+    if exit_status.success() {
+        return Ok(());
+    }
+
+    match on_failure {
+        HookFailure::Abort => bail!(
+            "{} did not successfully run, returned exit code of {}, aborting",
+            description,
+            exit_status
+        ),
+        HookFailure::Warn => {
+            warn!(
+                "{} did not successfully run, returned exit code of {}, continuing",
+                description, exit_status
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Names of packages in each backend which a phase concerns
+pub type PackagesByBackend = BTreeMap<SourceBackend, BTreeSet<String>>;
+
+/// Packages in each backend which are in the after snapshot
+/// but weren't in the before snapshot at that version
+pub fn installed_packages(before: &Snapshot, after: &Snapshot) -> PackagesByBackend {
+    after
+        .iter()
+        .map(|(backend, after)| {
+            let installed = after
+                .versions
+                .iter()
+                .filter(|(name, version)| {
+                    before
+                        .get(backend)
+                        .and_then(|before| before.versions.get(*name))
+                        != Some(*version)
+                })
+                .map(|(name, _)| name.clone())
+                .collect();
+            (*backend, installed)
+        })
+        .collect()
+}
+
+/// Packages in each backend which are in the before
+/// snapshot but aren't in the after snapshot at all
+pub fn removed_packages(before: &Snapshot, after: &Snapshot) -> PackagesByBackend {
+    before
+        .iter()
+        .map(|(backend, before)| {
+            let removed = before
+                .versions
+                .keys()
+                .filter(|name| {
+                    !after
+                        .get(backend)
+                        .is_some_and(|after| after.versions.contains_key(*name))
+                })
+                .cloned()
+                .collect();
+            (*backend, removed)
+        })
+        .collect()
+}
+
+impl Hooks {
+    /// Whether or not any hook needs to know
+    /// what installing changed on the system
+    pub fn need_installed(&self, package_list: &PackageList) -> bool {
+        package_list
+            .iter()
+            .any(|package| package.post_install.is_some())
+            || self.has_phase(HookPhase::AfterInstall)
+    }
+
+    /// Whether or not any hook needs to know
+    /// what removing changed on the system
+    pub fn need_removed(&self) -> bool {
+        !self.removals.is_empty() || self.has_phase(HookPhase::AfterRemove)
+    }
+
+    /// Whether or not there are any global hooks for the phase
+    fn has_phase(&self, phase: HookPhase) -> bool {
+        self.phases.iter().any(|hook| hook.phase == phase)
+    }
+
+    /// Runs every global hook of the phase, given the packages it concerns
+    /// in each backend. Hooks limited to a source only get the packages of
+    /// its backend, and hooks of the install and remove phases are skipped
+    /// when they concern no packages
+    pub fn run_phase(
+        &self,
+        runner: &dyn CommandRunner,
+        phase: HookPhase,
+        packages: &PackagesByBackend,
+    ) -> anyhow::Result<()> {
+        let policy = ROOT_CONFIG.get_config().hook_failure;
+
+        for hook in self.phases.iter().filter(|hook| hook.phase == phase) {
+            let hook_packages: Vec<&str> = packages
+                .iter()
+                .filter(|(backend, _)| {
+                    hook.source
+                        .is_none_or(|source| source.backend() == **backend)
+                })
+                .flat_map(|(_, names)| names.iter().map(String::as_str))
+                .collect();
+
+            if hook_packages.is_empty()
+                && matches!(phase, HookPhase::AfterInstall | HookPhase::AfterRemove)
+            {
+                continue;
+            }
+
+            run_hook(
+                runner,
+                &format!("{} hook from configuration file {:?}", phase, hook.src),
+                &hook.run,
+                &[
+                    ("BLUEPRINT_PHASE", phase.to_string()),
+                    ("BLUEPRINT_PACKAGES", hook_packages.join(" ")),
+                ],
+                hook.on_failure.unwrap_or(policy),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the pre_install hook of every package which isn't installed yet
+    pub fn run_pre_install(
+        &self,
+        runner: &dyn CommandRunner,
+        package_list: &PackageList,
+        before: &Snapshot,
+    ) -> anyhow::Result<()> {
+        let policy = ROOT_CONFIG.get_config().hook_failure;
+
+        for package in package_list.iter() {
+            let Some(command) = &package.pre_install else {
+                continue;
+            };
+
+            if before
+                .get(&package.source.backend())
+                .is_some_and(|snapshot| snapshot.versions.contains_key(&package.name))
+            {
+                continue;
+            }
+
+            run_package_hook(
+                runner,
+                "pre_install",
+                &package.name,
+                &package.src,
+                command,
+                policy,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the post_install hook of every package
+    /// which was installed or changed version
+    pub fn run_post_install(
+        &self,
+        runner: &dyn CommandRunner,
+        package_list: &PackageList,
+        installed: &PackagesByBackend,
+    ) -> anyhow::Result<()> {
+        let policy = ROOT_CONFIG.get_config().hook_failure;
+
+        for package in package_list.iter() {
+            let Some(command) = &package.post_install else {
+                continue;
+            };
+
+            if !installed
+                .get(&package.source.backend())
+                .is_some_and(|installed| installed.contains(&package.name))
+            {
+                continue;
+            }
+
+            run_package_hook(
+                runner,
+                "post_install",
+                &package.name,
+                &package.src,
+                command,
+                policy,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the post_remove hook of every package which was removed
+    pub fn run_post_remove(
+        &self,
+        runner: &dyn CommandRunner,
+        removed: &PackagesByBackend,
+    ) -> anyhow::Result<()> {
+        let policy = ROOT_CONFIG.get_config().hook_failure;
+
+        for hook in &self.removals {
+            if !removed
+                .get(&hook.backend)
+                .is_some_and(|removed| removed.contains(&hook.name))
+            {
+                continue;
+            }
+
+            run_package_hook(
+                runner,
+                "post_remove",
+                &hook.name,
+                &hook.src,
+                &hook.run,
+                policy,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs a hook of a single package
+fn run_package_hook(
+    runner: &dyn CommandRunner,
+    phase: &str,
+    name: &str,
+    src: &PathBuf,
+    command: &str,
+    policy: HookFailure,
+) -> anyhow::Result<()> {
+    run_hook(
+        runner,
+        &format!(
+            "{} hook of package {} from configuration file {:?}",
+            phase, name, src
+        ),
+        command,
+        &[
+            ("BLUEPRINT_PHASE", phase.to_string()),
+            ("BLUEPRINT_PACKAGE", name.to_string()),
+        ],
+        policy,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        package::{history::BackendSnapshot, package::Package},
+        runner::{RecordingRunner, argv},
+    };
+
+    /// Hook of the phase running the command
+    fn hook(
+        phase: HookPhase,
+        run: &str,
+        source: Option<PackageSource>,
+        on_failure: Option<HookFailure>,
+    ) -> Hook {
+        Hook {
+            phase,
+            run: run.to_string(),
+            source,
+            on_failure,
+            src: PathBuf::from("blueprint.toml"),
+        }
+    }
+
+    /// Snapshot of a single backend with the installed versions
+    fn snapshot(backend: SourceBackend, versions: &[(&str, &str)]) -> Snapshot {
+        Snapshot::from([(
+            backend,
+            BackendSnapshot {
+                versions: versions
+                    .iter()
+                    .map(|(name, version)| (name.to_string(), version.to_string()))
+                    .collect(),
+                ..BackendSnapshot::default()
+            },
+        )])
+    }
+
+    fn packages(entries: &[(SourceBackend, &[&str])]) -> PackagesByBackend {
+        entries
+            .iter()
+            .map(|(backend, names)| {
+                (
+                    *backend,
+                    names.iter().map(|name| name.to_string()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn phase_hooks_run_through_env_and_the_shell() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        let hooks = Hooks {
+            phases: vec![
+                hook(HookPhase::AfterInstall, "notify-send done", None, None),
+                hook(
+                    HookPhase::AfterInstall,
+                    "cargo cache -a",
+                    Some(PackageSource::Cargo),
+                    None,
+                ),
+                hook(HookPhase::AfterRemove, "updatedb", None, None),
+            ],
+            removals: Vec::new(),
+        };
+        let installed = packages(&[
+            (SourceBackend::ArchLocalDatabase, &["git", "htop"]),
+            (SourceBackend::CargoInstallRoot, &["ripgrep"]),
+        ]);
+
+        hooks
+            .run_phase(&runner, HookPhase::AfterInstall, &installed)
+            .unwrap();
+        hooks
+            .run_phase(&runner, HookPhase::AfterRemove, &PackagesByBackend::new())
+            .unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec![
+                argv(&[
+                    "env",
+                    "BLUEPRINT_PHASE=after_install",
+                    "BLUEPRINT_PACKAGES=git htop ripgrep",
+                    "bash",
+                    "-c",
+                    "notify-send done",
+                ]),
+                argv(&[
+                    "env",
+                    "BLUEPRINT_PHASE=after_install",
+                    "BLUEPRINT_PACKAGES=ripgrep",
+                    "bash",
+                    "-c",
+                    "cargo cache -a",
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn failing_hooks_abort_unless_they_warn() {
+        ROOT_CONFIG.set_test_config();
+        let hooks = Hooks {
+            phases: vec![
+                hook(
+                    HookPhase::BeforeApply,
+                    "false",
+                    None,
+                    Some(HookFailure::Warn),
+                ),
+                hook(HookPhase::BeforeApply, "snapper create", None, None),
+                hook(HookPhase::BeforeApply, "echo done", None, None),
+            ],
+            removals: Vec::new(),
+        };
+
+        let runner = RecordingRunner::default();
+        runner.push_output(1, "");
+        hooks
+            .run_phase(&runner, HookPhase::BeforeApply, &PackagesByBackend::new())
+            .unwrap();
+        assert_eq!(runner.commands().len(), 3);
+
+        let runner = RecordingRunner::default();
+        runner.push_output(0, "");
+        runner.push_output(1, "");
+        let error = hooks
+            .run_phase(&runner, HookPhase::BeforeApply, &PackagesByBackend::new())
+            .unwrap_err();
+        assert!(error.to_string().contains("aborting"), "{}", error);
+        assert_eq!(runner.commands().len(), 2);
+    }
+
+    #[test]
+    fn package_hooks_only_run_for_changed_packages() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        let package_list = PackageList(vec![
+            Package {
+                post_install: Some("systemctl enable --now docker".to_string()),
+                ..Package::new("docker".to_string(), PackageSource::ArchPacman)
+            },
+            Package {
+                post_install: Some("echo unchanged".to_string()),
+                ..Package::new("htop".to_string(), PackageSource::ArchPacman)
+            },
+        ]);
+        let hooks = Hooks {
+            phases: Vec::new(),
+            removals: Vec::new(),
+        };
+
+        hooks
+            .run_post_install(
+                &runner,
+                &package_list,
+                &packages(&[(SourceBackend::ArchLocalDatabase, &["docker"])]),
+            )
+            .unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec![argv(&[
+                "env",
+                "BLUEPRINT_PHASE=post_install",
+                "BLUEPRINT_PACKAGE=docker",
+                "bash",
+                "-c",
+                "systemctl enable --now docker",
+            ])]
+        );
+    }
+
+    #[test]
+    fn snapshots_tell_installed_and_removed_packages() {
+        let before = snapshot(
+            SourceBackend::ArchLocalDatabase,
+            &[("git", "2.45.1-1"), ("htop", "3.3.0-1"), ("nano", "8.0-1")],
+        );
+        let after = snapshot(
+            SourceBackend::ArchLocalDatabase,
+            &[("git", "2.45.2-1"), ("htop", "3.3.0-1"), ("vim", "9.1-1")],
+        );
+
+        assert_eq!(
+            installed_packages(&before, &after),
+            packages(&[(SourceBackend::ArchLocalDatabase, &["git", "vim"])])
+        );
+        assert_eq!(
+            removed_packages(&before, &after),
+            packages(&[(SourceBackend::ArchLocalDatabase, &["nano"])])
+        );
+    }
+}
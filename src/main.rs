@@ -1,7 +1,16 @@
 use ::log::{debug, error};
 
 use crate::{
-    commands::{apply::apply_command, init::init_command},
+    commands::{
+        apply::apply_command,
+        capture::capture_command,
+        history::history_command,
+        init::init_command,
+        lock::lock_command,
+        plan::plan_command,
+        rollback::rollback_command,
+        status::{DRIFT_EXIT_CODE, Drift, status_command},
+    },
     log::setup_logging,
 };
 
@@ -12,9 +21,19 @@ mod args;
 mod commands;
 
 // Configuration related modules
+mod condition;
 mod config;
 mod parse_config;
 
+// Commands ran around package operations
+mod hook;
+
+// Systemd units applied after packages
+mod service;
+
+// Files put into place after packages
+mod file;
+
 // Logging handling
 mod log;
 
@@ -24,21 +43,76 @@ mod package;
 // Path cleaning
 mod cleanpath;
 
+// Selecting entries by profile
+mod profile;
+
+// Running external commands
+mod runner;
+
+// Confirming with the user
+mod prompt;
+
+// Machine readable reports of commands
+mod report;
+
 fn main() {
     setup_logging();
 
     // Parse arguments from CLI
     let args = args::parse_args();
     debug!("blueprint running command: {}", args.command);
+    let command_name = args.command.to_string();
+    prompt::set_assume_yes(args.yes);
+    report::set_writes_report(args.output == args::OutputFormat::Json);
 
     // Run correct command for the type.
     let command_result = match args.command {
-        args::Commands::Init { file } => init_command(file),
-        args::Commands::Apply { file, section } => apply_command(file, section),
+        args::Commands::Init { file, overwrite } => init_command(file, overwrite),
+        args::Commands::Capture { file, overwrite } => capture_command(file, overwrite),
+        args::Commands::Apply {
+            config,
+            dry_run,
+            force,
+            backup_conflicts,
+            locked,
+        } => apply_command(
+            config.file,
+            config.section,
+            config.profiles,
+            dry_run,
+            force,
+            backup_conflicts,
+            locked,
+        ),
+        args::Commands::Plan { config } => {
+            plan_command(config.file, config.section, config.profiles)
+        }
+        args::Commands::Status { config } => {
+            status_command(config.file, config.section, config.profiles)
+        }
+        args::Commands::Lock { config, update } => {
+            lock_command(config.file, config.section, config.profiles, update)
+        }
+        args::Commands::History => history_command(),
+        args::Commands::Rollback { generation, config } => {
+            rollback_command(config.file, config.section, config.profiles, generation)
+        }
     };
 
-    // Use error logger to print error..
-    let _ = command_result.inspect_err(|err| {
+    if report::writes_report() {
+        let _ = report::write_report(&command_name, &command_result).inspect_err(|err| {
+            error!("{:?}", err);
+        });
+    }
+
+    // Use error logger to print error, and exit unsuccessfully
+    // so scripts running blueprint can tell it failed, or that
+    // the system drifted which isn't a failure of blueprint
+    if let Err(err) = command_result {
         error!("{:?}", err);
-    });
+        std::process::exit(match err.is::<Drift>() {
+            true => DRIFT_EXIT_CODE,
+            false => 1,
+        });
+    }
 }
@@ -0,0 +1,586 @@
+//! Generations recording what every apply changed on the system, kept
+//! under the state directory so an apply can later be rolled back
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, bail};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    package::{
+        package::PackageList,
+        source::cargo,
+        sources::{PackageSource, SourceBackend},
+    },
+    runner::CommandRunner,
+};
+
+/// Directory under the state directory generations are kept in
+const GENERATIONS_DIRECTORY: &str = "blueprint/generations";
+
+/// Version of the layout of generation files
+const GENERATION_VERSION: u32 = 1;
+
+/// A package installed in a backend, as reported by its source
+#[derive(Debug)]
+pub struct InventoryPackage {
+    // Name of the installed package
+    pub name: String,
+
+    // Exact version installed
+    pub version: String,
+
+    // Was this package explicitly installed, or as a dependency?
+    pub explicit: bool,
+}
+
+/// Every package installed in a backend at one point in time
+#[derive(Debug, Default)]
+pub struct BackendSnapshot {
+    // Installed packages -> their exact version
+    pub versions: BTreeMap<String, String>,
+
+    // Packages which were explicitly installed
+    pub explicit: BTreeSet<String>,
+}
+
+/// Snapshots of every backend an apply touched
+pub type Snapshot = BTreeMap<SourceBackend, BackendSnapshot>;
+
+/// Changes bringing a backend back to an earlier snapshot
+#[derive(Debug)]
+pub struct BackendRollback {
+    // Packages to install -> the exact version to install
+    pub install: BTreeMap<String, String>,
+
+    // Packages to install -> the options they were last installed
+    // with, only recorded for the sources which have any
+    pub options: BTreeMap<String, Vec<String>>,
+
+    // Packages to remove
+    pub remove: Vec<String>,
+
+    // Packages to mark as explicitly installed
+    pub mark_explicit: Vec<String>,
+
+    // Packages to mark as installed as a dependency
+    pub mark_dependency: Vec<String>,
+}
+
+/// A package of the package list which was applied
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GenerationPackage {
+    pub name: String,
+    pub source: PackageSource,
+
+    // Configuration file declaring the package
+    pub src: PathBuf,
+
+    // Options the package was installed with besides its version,
+    // such as the git repository and features of a crate
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+}
+
+/// What a generation changed in a single backend
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BackendGeneration {
+    pub backend: SourceBackend,
+
+    // Packages explicitly installed before and after the generation
+    pub explicit_before: BTreeSet<String>,
+    pub explicit_after: BTreeSet<String>,
+
+    // Packages installed or changed version -> the version installed
+    pub installed: BTreeMap<String, String>,
+
+    // Packages removed -> the version which was removed
+    pub removed: BTreeMap<String, String>,
+
+    // Every package installed before the generation -> its version,
+    // which is what versions the generation changed are put back to
+    pub versions_before: BTreeMap<String, String>,
+}
+
+/// A single numbered change to the system made by blueprint
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Generation {
+    // Version of the layout of the generation file
+    pub version: u32,
+
+    // Number of the generation, counting up from 1
+    pub number: u32,
+
+    // Seconds since the unix epoch the generation was made at
+    pub created: u64,
+
+    // Command which made the generation such as "apply"
+    pub command: String,
+
+    // Whether or not the command completed
+    pub succeeded: bool,
+
+    // Package list which was applied, empty for rollbacks
+    pub packages: Vec<GenerationPackage>,
+
+    // Changes to each backend
+    pub backends: Vec<BackendGeneration>,
+}
+
+/// State directory blueprint keeps what it has done in, $XDG_STATE_HOME
+/// falling back to ~/.local/state
+pub fn state_home() -> anyhow::Result<PathBuf> {
+    match std::env::var_os("XDG_STATE_HOME").filter(|path| !path.is_empty()) {
+        Some(state_home) => Ok(PathBuf::from(state_home)),
+        None => Ok(PathBuf::from(
+            std::env::var_os("HOME")
+                .context("Neither XDG_STATE_HOME nor HOME are set to store state under")?,
+        )
+        .join(".local/state")),
+    }
+}
+
+/// Directory generations are kept in, $XDG_STATE_HOME/blueprint/generations
+/// falling back to ~/.local/state/blueprint/generations
+fn generations_directory() -> anyhow::Result<PathBuf> {
+    Ok(state_home()?.join(GENERATIONS_DIRECTORY))
+}
+
+/// Takes a snapshot of every package installed in each of the backends
+pub fn take_snapshot(
+    runner: &dyn CommandRunner,
+    backends: impl IntoIterator<Item = SourceBackend>,
+) -> anyhow::Result<Snapshot> {
+    backends
+        .into_iter()
+        .map(|backend| {
+            let mut snapshot = BackendSnapshot::default();
+
+            for package in backend.inventory()(runner)? {
+                if package.explicit {
+                    snapshot.explicit.insert(package.name.clone());
+                }
+                snapshot.versions.insert(package.name, package.version);
+            }
+
+            Ok((backend, snapshot))
+        })
+        .collect()
+}
+
+/// Records what a command changed since the snapshot was taken as a new
+/// generation, failing to record it only warns as the system has already
+/// been changed either way
+pub fn record_generation(
+    runner: &dyn CommandRunner,
+    command: &str,
+    succeeded: bool,
+    package_list: Option<&PackageList>,
+    before: Snapshot,
+) {
+    let recorded = take_snapshot(runner, before.keys().copied()).and_then(|after| {
+        match Generation::new(command, succeeded, package_list, before, &after) {
+            Some(mut generation) => generation
+                .write()
+                .map(|path| Some((generation.number, path))),
+            None => Ok(None),
+        }
+    });
+
+    match recorded {
+        Ok(Some((number, path))) => info!("Recorded generation {} in {:?}", number, path),
+        Ok(None) => info!("Nothing on the system changed, no generation was recorded"),
+        Err(err) => warn!(
+            "Could not record a generation of this {}: {:?}",
+            command, err
+        ),
+    }
+}
+
+impl BackendGeneration {
+    /// Works out what changed in a backend between two snapshots
+    fn new(backend: SourceBackend, before: BackendSnapshot, after: &BackendSnapshot) -> Self {
+        Self {
+            backend,
+            explicit_after: after.explicit.clone(),
+            installed: after
+                .versions
+                .iter()
+                .filter(|(name, version)| before.versions.get(*name) != Some(*version))
+                .map(|(name, version)| (name.clone(), version.clone()))
+                .collect(),
+            removed: before
+                .versions
+                .iter()
+                .filter(|(name, _)| !after.versions.contains_key(*name))
+                .map(|(name, version)| (name.clone(), version.clone()))
+                .collect(),
+            explicit_before: before.explicit,
+            versions_before: before.versions,
+        }
+    }
+
+    /// Changes undoing only what this generation changed, limited to what
+    /// is still as the generation left it in the current snapshot. Packages
+    /// it removed are reinstalled, packages it added are removed, versions
+    /// it changed are put back and install reasons it changed are restored,
+    /// returning nothing when there is nothing left to undo. Reinstalled
+    /// packages get the options the latest of the earlier generations
+    /// applied them with
+    pub fn rollback_from(
+        &self,
+        current: &BackendSnapshot,
+        earlier: &[Generation],
+    ) -> Option<BackendRollback> {
+        // Removed packages which haven't been installed again since
+        let mut install: BTreeMap<String, String> = self
+            .removed
+            .iter()
+            .filter(|(name, _)| !current.versions.contains_key(*name))
+            .map(|(name, version)| (name.clone(), version.clone()))
+            .collect();
+
+        // Changed versions which haven't changed again since
+        install.extend(self.installed.iter().filter_map(|(name, version)| {
+            let before = self.versions_before.get(name)?;
+            (current.versions.get(name) == Some(version)).then(|| (name.clone(), before.clone()))
+        }));
+
+        // Added packages which are still installed
+        let remove: Vec<String> = self
+            .installed
+            .keys()
+            .filter(|name| {
+                !self.versions_before.contains_key(*name) && current.versions.contains_key(*name)
+            })
+            .cloned()
+            .collect();
+
+        // Reinstalled packages get their reason from before the generation,
+        // packages still installed only have the reasons the generation
+        // changed put back when nobody has changed them since
+        let reinstalled = |name: &String| !current.versions.contains_key(name);
+        let installed_after =
+            |name: &String| current.versions.contains_key(name) && !remove.contains(name);
+        let mark_explicit: Vec<String> = install
+            .keys()
+            .filter(|name| reinstalled(name) && self.explicit_before.contains(*name))
+            .chain(
+                self.explicit_before
+                    .difference(&self.explicit_after)
+                    .filter(|name| installed_after(name) && !current.explicit.contains(*name)),
+            )
+            .cloned()
+            .collect();
+        let mark_dependency: Vec<String> = install
+            .keys()
+            .filter(|name| reinstalled(name) && !self.explicit_before.contains(*name))
+            .chain(
+                self.explicit_after
+                    .difference(&self.explicit_before)
+                    .filter(|name| installed_after(name) && current.explicit.contains(*name)),
+            )
+            .cloned()
+            .collect();
+
+        // Earlier generations are oldest first, so the latest is searched first
+        let options: BTreeMap<String, Vec<String>> = install
+            .keys()
+            .filter_map(|name| {
+                let package = earlier
+                    .iter()
+                    .rev()
+                    .flat_map(|generation| &generation.packages)
+                    .find(|package| {
+                        package.name == *name && package.source.backend() == self.backend
+                    })?;
+                (!package.options.is_empty()).then(|| (name.clone(), package.options.clone()))
+            })
+            .collect();
+
+        let rollback = BackendRollback {
+            install,
+            options,
+            remove,
+            mark_explicit,
+            mark_dependency,
+        };
+
+        let unchanged = rollback.install.is_empty()
+            && rollback.remove.is_empty()
+            && rollback.mark_explicit.is_empty()
+            && rollback.mark_dependency.is_empty();
+        (!unchanged).then_some(rollback)
+    }
+
+    /// Whether or not the generation changed anything in the backend
+    fn changed(&self) -> bool {
+        !self.installed.is_empty()
+            || !self.removed.is_empty()
+            || self.explicit_before != self.explicit_after
+    }
+}
+
+impl Generation {
+    /// Works out what changed between the snapshots, returning
+    /// nothing when the system wasn't changed at all
+    pub fn new(
+        command: &str,
+        succeeded: bool,
+        package_list: Option<&PackageList>,
+        before: Snapshot,
+        after: &Snapshot,
+    ) -> Option<Self> {
+        let backends: Vec<BackendGeneration> = before
+            .into_iter()
+            .filter_map(|(backend, before)| {
+                Some(BackendGeneration::new(
+                    backend,
+                    before,
+                    after.get(&backend)?,
+                ))
+            })
+            .filter(BackendGeneration::changed)
+            .collect();
+
+        if backends.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            version: GENERATION_VERSION,
+            number: 0,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            command: command.to_string(),
+            succeeded,
+            packages: package_list
+                .into_iter()
+                .flat_map(|package_list| package_list.iter())
+                .map(|package| GenerationPackage {
+                    name: package.name.clone(),
+                    source: package.source,
+                    src: package.src.clone(),
+                    options: match package.source {
+                        PackageSource::Cargo => cargo::install_options(package),
+                        _ => Vec::new(),
+                    },
+                })
+                .collect(),
+            backends,
+        })
+    }
+
+    /// Path of the file of the numbered generation
+    fn path(number: u32) -> anyhow::Result<PathBuf> {
+        Ok(generations_directory()?.join(format!("{}.json", number)))
+    }
+
+    /// Numbers of every stored generation, oldest first
+    fn stored_numbers() -> anyhow::Result<Vec<u32>> {
+        let directory = generations_directory()?;
+        if !directory.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut numbers: Vec<u32> = fs::read_dir(&directory)
+            .with_context(|| format!("While trying to read generations from {:?}", directory))?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_str()?
+                    .strip_suffix(".json")?
+                    .parse()
+                    .ok()
+            })
+            .collect();
+        numbers.sort();
+
+        Ok(numbers)
+    }
+
+    /// Reads in every stored generation, oldest first
+    pub fn read_all() -> anyhow::Result<Vec<Self>> {
+        Self::stored_numbers()?
+            .into_iter()
+            .map(Self::read)
+            .collect()
+    }
+
+    /// Reads in the numbered generation
+    pub fn read(number: u32) -> anyhow::Result<Self> {
+        let path = Self::path(number)?;
+        if !path.exists() {
+            bail!(
+                "Generation {} does not exist, list the generations with blueprint history",
+                number
+            );
+        }
+
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("While trying to read generation {:?}", path))?;
+        let generation: Self = serde_json::from_str(&content)
+            .with_context(|| format!("While trying to parse generation {:?}", path))?;
+
+        if generation.version != GENERATION_VERSION {
+            bail!(
+                "Generation {:?} is version {}, but only version {} is supported",
+                path,
+                generation.version,
+                GENERATION_VERSION
+            );
+        }
+
+        Ok(generation)
+    }
+
+    /// Stores this generation numbered after the latest
+    /// stored generation, returning the path it was written to
+    pub fn write(&mut self) -> anyhow::Result<PathBuf> {
+        self.number = Self::stored_numbers()?
+            .last()
+            .map_or(1, |latest| latest + 1);
+
+        let directory = generations_directory()?;
+        fs::create_dir_all(&directory).with_context(|| {
+            format!(
+                "While trying to create generations directory {:?}",
+                directory
+            )
+        })?;
+
+        let path = Self::path(self.number)?;
+        let content = serde_json::to_string_pretty(self).with_context(|| {
+            format!("While trying to convert generation {} to JSON", self.number)
+        })?;
+        fs::write(&path, content)
+            .with_context(|| format!("While trying to write generation {:?}", path))?;
+
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Snapshot of the named packages at their versions, explicit if marked
+    fn snapshot(packages: &[(&str, &str, bool)]) -> BackendSnapshot {
+        BackendSnapshot {
+            versions: packages
+                .iter()
+                .map(|(name, version, _)| (name.to_string(), version.to_string()))
+                .collect(),
+            explicit: packages
+                .iter()
+                .filter(|(_, _, explicit)| *explicit)
+                .map(|(name, _, _)| name.to_string())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn rollback_only_undoes_the_generation() {
+        let before = snapshot(&[
+            ("removed", "1", true),
+            ("upgraded", "1", true),
+            ("kept", "1", false),
+        ]);
+        let after = snapshot(&[
+            ("added", "1", true),
+            ("upgraded", "2", true),
+            ("kept", "1", true),
+        ]);
+        let generation = BackendGeneration::new(SourceBackend::CargoInstallRoot, before, &after);
+
+        // Since then kept was upgraded and something was installed by hand
+        let current = snapshot(&[
+            ("added", "1", true),
+            ("upgraded", "2", true),
+            ("kept", "5", true),
+            ("by-hand", "1", true),
+        ]);
+        let rollback = generation.rollback_from(&current, &[]).unwrap();
+
+        assert_eq!(
+            rollback.install,
+            BTreeMap::from([
+                ("removed".to_string(), "1".to_string()),
+                ("upgraded".to_string(), "1".to_string()),
+            ])
+        );
+        assert_eq!(rollback.remove, vec!["added".to_string()]);
+        assert_eq!(rollback.mark_explicit, vec!["removed".to_string()]);
+        assert_eq!(rollback.mark_dependency, vec!["kept".to_string()]);
+    }
+
+    #[test]
+    fn rollback_of_undone_generation_is_nothing() {
+        let before = snapshot(&[("removed", "1", true)]);
+        let after = snapshot(&[("added", "1", true)]);
+        let generation = BackendGeneration::new(SourceBackend::CargoInstallRoot, before, &after);
+
+        let current = snapshot(&[("removed", "1", true)]);
+        assert!(generation.rollback_from(&current, &[]).is_none());
+    }
+
+    /// Generation applying the named packages with their options
+    fn applied(number: u32, packages: &[(&str, PackageSource, &[&str])]) -> Generation {
+        Generation {
+            version: GENERATION_VERSION,
+            number,
+            created: 0,
+            command: "apply".to_string(),
+            succeeded: true,
+            packages: packages
+                .iter()
+                .map(|(name, source, options)| GenerationPackage {
+                    name: name.to_string(),
+                    source: *source,
+                    src: PathBuf::from("blueprint.toml"),
+                    options: options.iter().map(|option| option.to_string()).collect(),
+                })
+                .collect(),
+            backends: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn rollback_reinstalls_with_the_latest_earlier_options() {
+        let before = snapshot(&[("tool", "1", true), ("plain", "1", true)]);
+        let after = snapshot(&[]);
+        let generation = BackendGeneration::new(SourceBackend::CargoInstallRoot, before, &after);
+
+        let earlier = [
+            applied(1, &[("tool", PackageSource::Cargo, &["--locked"])]),
+            applied(
+                2,
+                &[
+                    (
+                        "tool",
+                        PackageSource::Cargo,
+                        &["--git", "https://host/tool"],
+                    ),
+                    ("plain", PackageSource::Cargo, &[]),
+                ],
+            ),
+        ];
+        let rollback = generation.rollback_from(&after, &earlier).unwrap();
+
+        assert_eq!(
+            rollback.options,
+            BTreeMap::from([(
+                "tool".to_string(),
+                vec!["--git".to_string(), "https://host/tool".to_string()]
+            )])
+        );
+    }
+}
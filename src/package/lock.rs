@@ -0,0 +1,456 @@
+//! Lockfile recording the exact version of every package in the package
+//! list, so a known-good system can be reproduced later on
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    package::{
+        package::{Package, PackageList},
+        sources::{PackageSource, SourceBackend},
+        version::VersionConstraint,
+    },
+    runner::CommandRunner,
+};
+
+/// Name of the lockfile, which is kept next to the root configuration file
+const LOCKFILE_NAME: &str = "blueprint.lock";
+
+/// Version of the layout of the lockfile
+const LOCKFILE_VERSION: u32 = 1;
+
+/// Comment at the top of every written lockfile
+const LOCKFILE_HEADER: &str =
+    "# This file is generated by blueprint, update it with blueprint lock --update\n\n";
+
+/// The version of a package in the package list which is
+/// installed on the system, as reported by its backend
+#[derive(Debug)]
+pub struct InstalledVersion {
+    // Name of the declared package
+    pub name: String,
+
+    // Exact version installed as reported by the source
+    pub version: String,
+
+    // Repository, remote or registry the package came from, if known
+    pub repository: Option<String>,
+}
+
+/// Installed versions of the package list by backend and package name
+pub type InstalledVersions = HashMap<(SourceBackend, String), InstalledVersion>;
+
+/// A package of the package list along with the exact version installed
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LockedPackage {
+    // Name of the package
+    pub name: String,
+
+    // Source the package is installed from
+    pub source: PackageSource,
+
+    // Exact version which was installed
+    pub version: String,
+
+    // Repository, remote or registry the package came from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repository: Option<String>,
+
+    // Configuration file declaring the package,
+    // relative to the root configuration file
+    pub src: PathBuf,
+}
+
+/// Every package of the package list with its exact installed version
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Lockfile {
+    // Version of the layout of the lockfile
+    pub version: u32,
+
+    // Locked packages sorted by source then name
+    #[serde(rename = "package", default)]
+    pub packages: Vec<LockedPackage>,
+}
+
+/// Path of the lockfile belonging to the root configuration file
+pub fn lockfile_path(root_file: &Path) -> PathBuf {
+    root_file
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join(LOCKFILE_NAME)
+}
+
+impl LockedPackage {
+    /// Whether or not this locks the package
+    fn locks(&self, package: &Package) -> bool {
+        self.name == package.name && self.source == package.source
+    }
+}
+
+impl Lockfile {
+    /// Locks every package in the package list to the version currently
+    /// installed, erroring if any of them aren't installed. The declaring
+    /// files are recorded relative to the root configuration file
+    pub fn resolve(
+        runner: &dyn CommandRunner,
+        package_list: &PackageList,
+        root_file: &Path,
+    ) -> anyhow::Result<Self> {
+        let root_directory = root_file.parent().unwrap_or(Path::new("/"));
+        let mut installed = package_list.installed_versions(runner)?;
+
+        let not_installed: Vec<String> = package_list
+            .iter()
+            .filter(|package| {
+                !installed.contains_key(&(package.source.backend(), package.name.clone()))
+            })
+            .map(|package| package.name.clone())
+            .collect();
+        if !not_installed.is_empty() {
+            bail!(
+                "Can't lock packages which aren't installed, apply the package list first: {}",
+                not_installed.join(" ")
+            );
+        }
+
+        let mut packages: Vec<LockedPackage> = package_list
+            .iter()
+            .filter_map(|package| {
+                let installed =
+                    installed.remove(&(package.source.backend(), package.name.clone()))?;
+
+                Some(LockedPackage {
+                    name: package.name.clone(),
+                    source: package.source,
+                    version: installed.version,
+                    repository: installed.repository,
+                    src: package
+                        .src
+                        .strip_prefix(root_directory)
+                        .unwrap_or(&package.src)
+                        .to_path_buf(),
+                })
+            })
+            .collect();
+        packages.sort_by(|a, b| (a.source, &a.name).cmp(&(b.source, &b.name)));
+
+        Ok(Self {
+            version: LOCKFILE_VERSION,
+            packages,
+        })
+    }
+
+    /// Reads in the lockfile at the path
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            bail!(
+                "Lockfile {:?} does not exist, create it with blueprint lock --update",
+                path
+            );
+        }
+
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("While trying to read lockfile {:?}", path))?;
+        let lockfile: Self = toml::from_str(&content)
+            .with_context(|| format!("While trying to parse lockfile {:?}", path))?;
+
+        if lockfile.version != LOCKFILE_VERSION {
+            bail!(
+                "Lockfile {:?} is version {}, but only version {} is supported, update it with blueprint lock --update",
+                path,
+                lockfile.version,
+                LOCKFILE_VERSION
+            );
+        }
+
+        Ok(lockfile)
+    }
+
+    /// Writes this lockfile to the path
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let content = toml::to_string_pretty(self)
+            .with_context(|| format!("While trying to convert lockfile {:?} to TOML", path))?;
+
+        fs::write(path, format!("{}{}", LOCKFILE_HEADER, content))
+            .with_context(|| format!("While trying to write lockfile {:?}", path))
+    }
+
+    /// Every difference between this lockfile and the other
+    /// lockfile, describing how other differs from this one
+    pub fn differences(&self, other: &Lockfile) -> Vec<String> {
+        let mut differences = Vec::new();
+
+        for locked in &self.packages {
+            match other
+                .packages
+                .iter()
+                .find(|current| current.name == locked.name && current.source == locked.source)
+            {
+                None => differences.push(format!(
+                    "{} from source {} is locked but no longer declared",
+                    locked.name, locked.source
+                )),
+                Some(current) if current.version != locked.version => differences.push(format!(
+                    "{} from source {} is version {} instead of locked version {}",
+                    locked.name, locked.source, current.version, locked.version
+                )),
+                Some(current) if current.repository != locked.repository => {
+                    differences.push(format!(
+                        "{} from source {} is from repository {} instead of locked repository {}",
+                        locked.name,
+                        locked.source,
+                        current.repository.as_deref().unwrap_or("unknown"),
+                        locked.repository.as_deref().unwrap_or("unknown")
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+
+        for current in &other.packages {
+            if !self
+                .packages
+                .iter()
+                .any(|locked| locked.name == current.name && locked.source == current.source)
+            {
+                differences.push(format!(
+                    "{} from source {} is declared but not locked",
+                    current.name, current.source
+                ));
+            }
+        }
+
+        differences
+    }
+
+    /// Pins every package in the package list to its locked version, so
+    /// applying it installs exactly what was locked. Errors listing every
+    /// way the system would diverge from this lockfile, such as packages
+    /// missing from it or sources which can't install a locked version
+    pub fn pin(
+        &self,
+        runner: &dyn CommandRunner,
+        package_list: &mut PackageList,
+    ) -> anyhow::Result<()> {
+        let installed = package_list.installed_versions(runner)?;
+        let mut divergences = Vec::new();
+
+        for locked in &self.packages {
+            if !package_list.iter().any(|package| locked.locks(package)) {
+                divergences.push(format!(
+                    "{} from source {} is locked but no longer declared",
+                    locked.name, locked.source
+                ));
+            }
+        }
+
+        for package in package_list.iter_mut() {
+            let Some(locked) = self.packages.iter().find(|locked| locked.locks(package)) else {
+                divergences.push(format!("{} is declared but not locked", package));
+                continue;
+            };
+
+            if let Some(constraint) = &package.version
+                && !constraint.matches(&locked.version)
+            {
+                divergences.push(format!(
+                    "locked version {} does not satisfy version constraint {} of {}",
+                    locked.version, constraint, package
+                ));
+                continue;
+            }
+
+            // Pinned packages are installed at their locked version
+            // and are left alone when the rest of the system upgrades
+            if package.source.pins_versions() {
+                package.version = Some(VersionConstraint::pinned(locked.version.clone()));
+                continue;
+            }
+
+            let installed_version = installed
+                .get(&(package.source.backend(), package.name.clone()))
+                .map(|installed| installed.version.as_str());
+            if installed_version != Some(locked.version.as_str()) {
+                divergences.push(format!(
+                    "{} is {} instead of locked version {}, which source {} can't install",
+                    package,
+                    installed_version.unwrap_or("not installed"),
+                    locked.version,
+                    package.source
+                ));
+            }
+        }
+
+        if !divergences.is_empty() {
+            bail!(
+                "Refusing to apply, the system would diverge from the lockfile in {} ways:\n{}\nUpdate the lockfile with blueprint lock --update",
+                divergences.len(),
+                divergences
+                    .iter()
+                    .map(|divergence| format!("  {}", divergence))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ROOT_CONFIG, runner::RecordingRunner};
+
+    fn locked(name: &str, source: PackageSource, version: &str) -> LockedPackage {
+        LockedPackage {
+            name: name.to_string(),
+            source,
+            version: version.to_string(),
+            repository: None,
+            src: PathBuf::from("blueprint.toml"),
+        }
+    }
+
+    fn lockfile(packages: Vec<LockedPackage>) -> Lockfile {
+        Lockfile {
+            version: LOCKFILE_VERSION,
+            packages,
+        }
+    }
+
+    #[test]
+    fn differences_list_added_removed_and_changed_versions() {
+        let locked_file = lockfile(vec![
+            locked("htop", PackageSource::ArchPacman, "3.3.0-1"),
+            locked("linux", PackageSource::ArchPacman, "6.9.7-1"),
+            locked("ripgrep", PackageSource::Cargo, "14.1.0"),
+        ]);
+        let current = lockfile(vec![
+            locked("htop", PackageSource::ArchPacman, "3.3.0-1"),
+            locked("linux", PackageSource::ArchPacman, "6.10.1-1"),
+            locked("git", PackageSource::ArchPacman, "2.45.2-1"),
+        ]);
+
+        assert_eq!(
+            locked_file.differences(&current),
+            [
+                "linux from source Arch-Linux using Pacman is version 6.10.1-1 instead of locked version 6.9.7-1",
+                "ripgrep from source Cargo is locked but no longer declared",
+                "git from source Arch-Linux using Pacman is declared but not locked",
+            ]
+        );
+        assert!(locked_file.differences(&locked_file).is_empty());
+    }
+
+    #[test]
+    fn pinning_sets_the_locked_version() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        let mut package_list = PackageList(vec![Package::new(
+            "linux".to_string(),
+            PackageSource::ArchPacman,
+        )]);
+
+        lockfile(vec![locked("linux", PackageSource::ArchPacman, "6.9.7-1")])
+            .pin(&runner, &mut package_list)
+            .unwrap();
+
+        assert_eq!(
+            package_list[0].version,
+            Some(VersionConstraint::pinned("6.9.7-1".to_string()))
+        );
+    }
+
+    #[test]
+    fn pinning_a_package_without_a_lock_entry_is_an_error() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        let mut package_list = PackageList(vec![
+            Package::new("linux".to_string(), PackageSource::ArchPacman),
+            Package::new("htop".to_string(), PackageSource::ArchPacman),
+        ]);
+
+        let error = lockfile(vec![locked("linux", PackageSource::ArchPacman, "6.9.7-1")])
+            .pin(&runner, &mut package_list)
+            .unwrap_err()
+            .to_string();
+
+        assert!(
+            error.contains("diverge from the lockfile in 1 ways"),
+            "{}",
+            error
+        );
+        assert!(
+            error.contains("package htop, from source Arch-Linux using Pacman, referenced in configuration file \"\" is declared but not locked"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn lock_entries_only_lock_packages_from_their_source() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        let mut package_list = PackageList(vec![Package::new(
+            "ripgrep".to_string(),
+            PackageSource::ArchPacman,
+        )]);
+
+        let error = lockfile(vec![locked("ripgrep", PackageSource::Cargo, "14.1.0")])
+            .pin(&runner, &mut package_list)
+            .unwrap_err()
+            .to_string();
+
+        assert!(
+            error.contains("ripgrep from source Cargo is locked but no longer declared"),
+            "{}",
+            error
+        );
+        assert!(
+            error.contains("package ripgrep, from source Arch-Linux using Pacman"),
+            "{}",
+            error
+        );
+        assert_eq!(package_list[0].version, None);
+    }
+
+    #[test]
+    fn sources_without_pinning_must_already_have_the_locked_version() {
+        ROOT_CONFIG.set_test_config();
+        let lock = lockfile(vec![locked(
+            "org.gimp.GIMP",
+            PackageSource::Flatpak,
+            "abc123",
+        )]);
+        let mut package_list = PackageList(vec![Package::new(
+            "org.gimp.GIMP".to_string(),
+            PackageSource::Flatpak,
+        )]);
+
+        let runner = RecordingRunner::default();
+        runner.push_output(0, "org.gimp.GIMP\tsystem\tflathub\tabc123\n");
+        lock.pin(&runner, &mut package_list).unwrap();
+        assert_eq!(package_list[0].version, None);
+
+        let runner = RecordingRunner::default();
+        runner.push_output(0, "org.gimp.GIMP\tsystem\tflathub\tdef456\n");
+        let error = lock
+            .pin(&runner, &mut package_list)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("is def456 instead of locked version abc123, which source"),
+            "{}",
+            error
+        );
+    }
+}
@@ -6,5 +6,17 @@ pub mod package;
 // Basic platform specific configuration options/launcher
 pub mod sources;
 
+// Computed changes a package list would make to the system
+pub mod plan;
+
+// Version constraints of packages
+pub mod version;
+
+// Exact versions of packages recorded in the lockfile
+pub mod lock;
+
+// Generations of changes applied to the system
+pub mod history;
+
 // Platform specific sources.
 mod source;
@@ -1,15 +1,23 @@
 use std::{
-    collections::{HashMap, hash_map::Entry},
+    collections::{BTreeMap, btree_map::Entry},
     fmt::Display,
     ops::{Deref, DerefMut},
     path::PathBuf,
 };
 
+use anyhow::bail;
 use serde::Deserialize;
 
 use crate::{
     cleanpath::CleanPath,
-    package::sources::{PackageListFunction, PackageSource},
+    condition::Condition,
+    package::{
+        lock::InstalledVersions,
+        plan::BackendPlan,
+        sources::{PackageListFunction, PackageSource, SourceBackend},
+        version::VersionConstraint,
+    },
+    runner::CommandRunner,
 };
 
 /// An individiual package to have within the system
@@ -24,11 +32,96 @@ pub struct Package {
     #[serde(default)]
     pub source: PackageSource,
 
+    // Which remote to install this package from, only
+    // supported by the flatpak source (e.g. flathub)
+    #[serde(default)]
+    pub remote: Option<String>,
+
+    // Whether to install this package for the user or the
+    // whole system, only supported by the flatpak source
+    #[serde(default)]
+    pub scope: Option<InstallScope>,
+
+    // Version constraint the installed package must satisfy, an exact
+    // version is installed by the sources which support pinning
+    #[serde(default)]
+    pub version: Option<VersionConstraint>,
+
+    // Git repository to install this package from,
+    // only supported by the cargo source
+    #[serde(default)]
+    pub git: Option<String>,
+
+    // Branch of the git repository to install this
+    // package from, only supported by the cargo source
+    #[serde(default)]
+    pub branch: Option<String>,
+
+    // Features to enable when building this package,
+    // only supported by the cargo source
+    #[serde(default)]
+    pub features: Vec<String>,
+
+    // Build with the dependency versions locked by the
+    // package, only supported by the cargo source
+    #[serde(default)]
+    pub locked: bool,
+
+    // Condition on the host which must hold for this package to be used
+    #[serde(default, alias = "if")]
+    pub when: Option<Condition>,
+
+    // Profiles this package belongs to, it is only used when one of
+    // them is active. Without any profiles it is always used
+    #[serde(default)]
+    pub profiles: Vec<String>,
+
+    // Command to run before installing this package,
+    // only when it isn't installed yet
+    #[serde(default)]
+    pub pre_install: Option<String>,
+
+    // Command to run after this package was installed or changed version
+    #[serde(default)]
+    pub post_install: Option<String>,
+
+    // Command to run after this package was removed, such as when
+    // it is left out by its condition, profiles or an exclude
+    #[serde(default)]
+    pub post_remove: Option<String>,
+
+    // Active profiles which pulled this package in, either its own
+    // or those of the link which pulled in its file
+    #[serde(skip)]
+    pub pulled_in_by: Vec<String>,
+
     // What file is this package defined in for debugging info
     #[serde(skip)]
     pub src: PathBuf,
 }
 
+/// Where a package is installed for sources which support
+/// both per-user and system wide installations
+#[derive(Deserialize, Debug, PartialEq, Copy, Clone, Hash, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallScope {
+    // Installed only for the user running blueprint
+    User,
+
+    // Installed for every user on the system
+    System,
+}
+
+impl InstallScope {
+    /// Command line flag selecting this scope
+    pub fn flag(&self) -> &'static str {
+        match self {
+            InstallScope::User => "--user",
+            InstallScope::System => "--system",
+        }
+    }
+}
+
 /// Newtype to help handling packages better
 /// with more methods on a list
 #[derive(Deserialize, Debug, Default)]
@@ -55,10 +148,72 @@ impl Package {
         Self {
             name,
             source,
+            remote: None,
+            scope: None,
+            version: None,
+            git: None,
+            branch: None,
+            features: Vec::new(),
+            locked: false,
+            when: None,
+            profiles: Vec::new(),
+            pre_install: None,
+            post_install: None,
+            post_remove: None,
+            pulled_in_by: Vec::new(),
             src: Default::default(),
         }
     }
 
+    /// Ensures this package only uses the options
+    /// which are supported by its source
+    pub fn validate_options(&self) -> anyhow::Result<()> {
+        let pinning_sources: Vec<PackageSource> = PackageSource::ALL
+            .into_iter()
+            .filter(PackageSource::pins_versions)
+            .collect();
+
+        // Name of each option, whether it's used and the sources supporting it
+        let source_options: [(&str, bool, &[PackageSource]); 7] = [
+            ("remote", self.remote.is_some(), &[PackageSource::Flatpak]),
+            ("scope", self.scope.is_some(), &[PackageSource::Flatpak]),
+            ("version", self.version.is_some(), &pinning_sources),
+            ("git", self.git.is_some(), &[PackageSource::Cargo]),
+            ("branch", self.branch.is_some(), &[PackageSource::Cargo]),
+            (
+                "features",
+                !self.features.is_empty(),
+                &[PackageSource::Cargo],
+            ),
+            ("locked", self.locked, &[PackageSource::Cargo]),
+        ];
+
+        for (option, used, sources) in source_options {
+            if used && !sources.contains(&self.source) {
+                bail!(
+                    "The {} option is not supported by source {}, but is used by {}",
+                    option,
+                    self.source,
+                    self
+                );
+            }
+        }
+
+        if self.branch.is_some() && self.git.is_none() {
+            bail!(
+                "The branch option requires the git option, but only branch is used by {}",
+                self
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The exact version this package is pinned to, if it pins one
+    pub fn pinned_version(&self) -> Option<&str> {
+        self.version.as_ref().and_then(VersionConstraint::exact)
+    }
+
     /// Adds the path to this package for debugging tracking
     /// of the root pathw
     pub fn add_source_debug_path(self: &mut Self, file_path: &PathBuf) -> anyhow::Result<()> {
@@ -77,8 +232,9 @@ impl FromIterator<Package> for PackageList {
 
 impl PartialEq for Package {
     fn eq(&self, other: &Self) -> bool {
-        // Source file doesn't really matter.
-        self.name == other.name && self.source == other.source
+        // Source file doesn't really matter, the same package
+        // installed to another scope is a separate installation
+        self.name == other.name && self.source == other.source && self.scope == other.scope
     }
 }
 
@@ -88,7 +244,13 @@ impl Display for Package {
             f,
             "package {}, from source {}, referenced in configuration file {:?}",
             self.name, self.source, self.src
-        )
+        )?;
+
+        if !self.pulled_in_by.is_empty() {
+            write!(f, " pulled in by profile {}", self.pulled_in_by.join(", "))?;
+        }
+
+        Ok(())
     }
 }
 
@@ -110,13 +272,9 @@ impl PackageList {
         Ok(Self(result))
     }
 
-    /// Runs a function dependant on the source of packages
-    /// on all packages in the source on this package list
-    pub fn run_source_function(
-        self: &Self,
-        source_function: fn(source: &PackageSource) -> anyhow::Result<PackageListFunction>,
-    ) -> anyhow::Result<()> {
-        let mut source_map: HashMap<PackageSource, Vec<&Package>> = HashMap::new();
+    /// Splits the packages in this list up by their source
+    pub fn split_by_source(&self) -> BTreeMap<PackageSource, Vec<&Package>> {
+        let mut source_map: BTreeMap<PackageSource, Vec<&Package>> = BTreeMap::new();
 
         // Split all of the packages based on their source.
         for current_package in &self.0 {
@@ -131,11 +289,73 @@ impl PackageList {
             }
         }
 
-        // Run packagelistfunction now on each
         source_map
+    }
+
+    /// Runs a function dependant on the source of packages
+    /// on all packages in the source on this package list
+    pub fn run_source_function(
+        self: &Self,
+        runner: &dyn CommandRunner,
+        source_function: fn(source: &PackageSource) -> anyhow::Result<PackageListFunction>,
+    ) -> anyhow::Result<()> {
+        // Run packagelistfunction now on each
+        let backends = self.split_by_backend();
+        self.split_by_source()
             .iter()
-            .try_for_each(|(source, package_list)| source_function(source)?(package_list))?;
+            .try_for_each(|(source, package_list)| {
+                source_function(source)?(
+                    &source.privileged_runner(runner)?,
+                    package_list,
+                    &backends[&source.backend()].1,
+                )
+            })?;
 
         Ok(())
     }
+
+    /// Splits the packages in this list up by the backend their source
+    /// installs into, along with the source used for the whole backend
+    pub fn split_by_backend(&self) -> BTreeMap<SourceBackend, (PackageSource, Vec<&Package>)> {
+        let mut backend_map: BTreeMap<SourceBackend, (PackageSource, Vec<&Package>)> =
+            BTreeMap::new();
+
+        for current_package in &self.0 {
+            let (backend_source, packages) = backend_map
+                .entry(current_package.source.backend())
+                .or_insert((current_package.source, Vec::new()));
+
+            // Sources ordered first can handle the entire backend
+            *backend_source = (*backend_source).min(current_package.source);
+            packages.push(current_package);
+        }
+
+        backend_map
+    }
+
+    /// Gets the exact installed version of every package in
+    /// this package list which is installed on the system
+    pub fn installed_versions(
+        &self,
+        runner: &dyn CommandRunner,
+    ) -> anyhow::Result<InstalledVersions> {
+        let mut installed = InstalledVersions::new();
+
+        for (backend, (source, package_list)) in self.split_by_backend() {
+            for version in source.installed_versions()(runner, &package_list)? {
+                installed.insert((backend, version.name.clone()), version);
+            }
+        }
+
+        Ok(installed)
+    }
+
+    /// Works out what applying this package list would
+    /// change on the system for each backend, without changing anything
+    pub fn plan_backends(&self, runner: &dyn CommandRunner) -> anyhow::Result<Vec<BackendPlan>> {
+        self.split_by_backend()
+            .values()
+            .map(|(source, package_list)| source.plan_packagelist()(runner, package_list))
+            .collect()
+    }
 }
@@ -0,0 +1,297 @@
+//! Plans of what applying a package list would change
+//! on the system, computed without changing anything
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use anyhow::bail;
+use log::{info, warn};
+
+use crate::package::{
+    sources::{PackageSource, SourceBackend},
+    version::VersionMismatch,
+};
+
+/// What an apply would do to the packages
+/// installed in a single backend on the system
+#[derive(Debug)]
+pub struct BackendPlan {
+    // Backend this plan was computed for
+    pub backend: SourceBackend,
+
+    // Packages in the package list which are not installed yet,
+    // split by the source which will install them
+    pub install: BTreeMap<PackageSource, Vec<String>>,
+
+    // Installed packages in the package list which are currently
+    // only installed as a dependency of another package
+    pub mark_explicit: Vec<String>,
+
+    // Explicitly installed packages not in the package list
+    pub mark_dependency: Vec<String>,
+
+    // Installed packages which are neither in the package list
+    // nor required by anything in it
+    pub remove: Vec<String>,
+
+    // Installed packages in the package list whose
+    // version does not satisfy their version constraint
+    pub mismatched: Vec<VersionMismatch>,
+
+    // Protected packages which removal would take off the system
+    pub protected: Vec<ProtectedRemoval>,
+
+    // How many packages are installed in the backend
+    pub installed: usize,
+}
+
+/// A protected package which removing unrequired software would remove
+#[derive(Debug)]
+pub struct ProtectedRemoval {
+    // Name of the protected package
+    pub name: String,
+
+    // Other packages being removed which would pull
+    // the protected package out as their dependency
+    pub required_by: Vec<String>,
+}
+
+impl Display for ProtectedRemoval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.required_by.is_empty() {
+            return write!(f, "{}", self.name);
+        }
+
+        write!(
+            f,
+            "{} (pulled out as a dependency of {})",
+            self.name,
+            self.required_by.join(" ")
+        )
+    }
+}
+
+/// Finds every protected package of the backend in the packages to remove,
+/// along with the packages being removed which depend on it, as told by
+/// depends_on(dependent, dependency)
+pub fn protected_removals(
+    backend: SourceBackend,
+    remove: &[String],
+    depends_on: impl Fn(&str, &str) -> bool,
+) -> Vec<ProtectedRemoval> {
+    let protected = backend.protected_packages();
+
+    remove
+        .iter()
+        .filter(|name| protected.contains(name))
+        .map(|name| ProtectedRemoval {
+            name: name.clone(),
+            required_by: remove
+                .iter()
+                .filter(|dependent| *dependent != name && depends_on(dependent, name))
+                .cloned()
+                .collect(),
+        })
+        .collect()
+}
+
+/// Drops every protected package of the backend from packages about to be
+/// removed, the system may have changed since the removal was planned
+pub fn without_protected(backend: SourceBackend, remove: Vec<String>) -> Vec<String> {
+    let protected = backend.protected_packages();
+    let (kept, remove): (Vec<String>, Vec<String>) = remove
+        .into_iter()
+        .partition(|name| protected.contains(name));
+
+    if !kept.is_empty() {
+        warn!(
+            "Refusing to remove {} protected packages from {}, keeping: {}",
+            kept.len(),
+            backend,
+            kept.join(" ")
+        );
+    }
+
+    remove
+}
+
+/// Every protected package of the backend which is installed, marked as
+/// explicitly installed before removing unrequired software so neither
+/// it nor anything it depends on is taken off the system
+pub fn installed_protected<'a>(
+    backend: SourceBackend,
+    installed: impl IntoIterator<Item = &'a str>,
+) -> Vec<String> {
+    let protected = backend.protected_packages();
+
+    installed
+        .into_iter()
+        .filter(|name| protected.iter().any(|protected| protected == name))
+        .map(String::from)
+        .collect()
+}
+
+impl BackendPlan {
+    /// Creates an empty plan for this backend
+    pub fn new(backend: SourceBackend) -> Self {
+        Self {
+            backend,
+            install: BTreeMap::new(),
+            mark_explicit: Vec::new(),
+            mark_dependency: Vec::new(),
+            remove: Vec::new(),
+            mismatched: Vec::new(),
+            protected: Vec::new(),
+            installed: 0,
+        }
+    }
+
+    /// Whether or not this plan would change nothing on the system
+    pub fn is_empty(&self) -> bool {
+        self.install.is_empty()
+            && self.mark_explicit.is_empty()
+            && self.mark_dependency.is_empty()
+            && self.remove.is_empty()
+            && self.mismatched.is_empty()
+            && self.protected.is_empty()
+    }
+
+    /// Drops everything from this plan which is only
+    /// done when removing unrequired software
+    pub fn without_removal(self) -> Self {
+        Self {
+            install: self.install,
+            mismatched: self.mismatched,
+            installed: self.installed,
+            ..Self::new(self.backend)
+        }
+    }
+
+    /// Errors listing every protected package this plan would remove,
+    /// so nothing is removed when any of them would be
+    pub fn ensure_no_protected_removals(&self) -> anyhow::Result<()> {
+        if self.protected.is_empty() {
+            return Ok(());
+        }
+
+        bail!(
+            "Refusing to remove unrequired software, {} protected packages would be removed from {}:\n{}\nDeclare them in the package list to keep them",
+            self.protected.len(),
+            self.backend,
+            self.protected
+                .iter()
+                .map(|protected| format!("  {}", protected))
+                .collect::<Vec<String>>()
+                .join("\n")
+        );
+    }
+
+    /// Installed packages which were explicitly installed but aren't in the
+    /// package list, in backends without install reasons everything is explicit
+    pub fn undeclared(&self) -> Vec<String> {
+        if self.backend.tracks_install_reasons() {
+            self.mark_dependency.clone()
+        } else {
+            self.remove.clone()
+        }
+    }
+
+    /// Number of packages where the system has drifted from the package list,
+    /// not installed, explicitly installed without being declared, or declared
+    /// but only installed as a dependency
+    pub fn drift(&self) -> usize {
+        self.install.values().map(Vec::len).sum::<usize>()
+            + self.undeclared().len()
+            + self.mark_explicit.len()
+    }
+
+    /// Logs where the system has drifted from the package list in this backend
+    pub fn log_drift(&self) {
+        if self.drift() == 0 {
+            info!("{} matches the package list", self.backend);
+            return;
+        }
+
+        info!("Drift in {}:", self.backend);
+        for (source, packages) in &self.install {
+            log_plan_section(
+                &format!("declared from source {} but not installed,", source),
+                packages,
+            );
+        }
+        log_plan_section("installed explicitly but not declared,", &self.undeclared());
+        log_plan_section(
+            "declared but only installed as a dependency,",
+            &self.mark_explicit,
+        );
+    }
+
+    /// Logs the packages this plan would remove, grouped by
+    /// why they are being removed, for the user to confirm
+    pub fn log_removal(&self) {
+        info!(
+            "{} packages will be removed from {}:",
+            self.remove.len(),
+            self.backend
+        );
+
+        let undeclared_names = self.undeclared();
+        let (undeclared, unrequired): (Vec<String>, Vec<String>) = self
+            .remove
+            .iter()
+            .cloned()
+            .partition(|name| undeclared_names.contains(name));
+
+        log_plan_section("remove explicitly installed", &undeclared);
+        log_plan_section("remove no longer required", &unrequired);
+    }
+
+    /// Logs this plan out for the user to read
+    pub fn log(&self) {
+        if self.is_empty() {
+            info!("{} is up to date, nothing to do", self.backend);
+            return;
+        }
+
+        info!("Plan for {}:", self.backend);
+        for (source, packages) in &self.install {
+            log_plan_section(&format!("install from source {}", source), packages);
+        }
+        log_plan_section("mark as explicitly installed", &self.mark_explicit);
+        log_plan_section("mark as installed as dependency", &self.mark_dependency);
+        log_plan_section("remove", &self.remove);
+
+        if !self.protected.is_empty() {
+            info!(
+                "  refuse to remove {} protected packages:",
+                self.protected.len()
+            );
+            for protected in &self.protected {
+                info!("    {}", protected);
+            }
+        }
+
+        if !self.mismatched.is_empty() {
+            info!(
+                "  {} packages do not satisfy their version constraint:",
+                self.mismatched.len()
+            );
+            for mismatch in &self.mismatched {
+                info!("    {}", mismatch);
+            }
+        }
+    }
+}
+
+/// Logs a single section of a plan if it has any packages in it
+fn log_plan_section(action: &str, packages: &[String]) {
+    if packages.is_empty() {
+        return;
+    }
+
+    info!(
+        "  {} {} packages: {}",
+        action,
+        packages.len(),
+        packages.join(" ")
+    );
+}
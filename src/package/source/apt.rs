@@ -0,0 +1,603 @@
+//! Apt integration for package management on Debian based systems
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use anyhow::{Context, bail};
+use log::info;
+
+use crate::{
+    package::{
+        history::{BackendRollback, InventoryPackage},
+        lock::InstalledVersion,
+        package::Package,
+        plan::{BackendPlan, installed_protected, without_protected},
+        source::{
+            command::{new_command, package_names, pinned_package_names, without_prompts},
+            depgraph::{DependencyGraph, InstalledPackage},
+            reasons::InstallReasonCommands,
+        },
+        sources::SourceBackend,
+    },
+    runner::CommandRunner,
+};
+
+const APT_GET_COMMAND_STRING: &str = "apt-get";
+const APT_MARK_COMMAND_STRING: &str = "apt-mark";
+const APT_CACHE_COMMAND_STRING: &str = "apt-cache";
+const DPKG_QUERY_COMMAND_STRING: &str = "dpkg-query";
+
+/// Commands apt uses to snapshot and restore install reasons
+const APT_INSTALL_REASONS: InstallReasonCommands = InstallReasonCommands {
+    name: APT_MARK_COMMAND_STRING,
+    query_explicit: &[APT_MARK_COMMAND_STRING, "showmanual"],
+    new_mark_command: |arguments| new_command(APT_MARK_COMMAND_STRING, arguments),
+    mark_explicit: &["manual", "--"],
+    mark_dependency: &["auto", "--"],
+};
+
+/// Format of each line of dpkg-query output used to build the
+/// dependency graph, fields are separated by tabs. Recommends and
+/// Suggests are included as apt keeps them when autoremoving by default.
+const DPKG_QUERY_FORMAT: &str = "${db:Status-Abbrev}\\t${Package}\\t${Version}\\t${Depends}, ${Pre-Depends}, ${Recommends}, ${Suggests}\\t${Provides}\\n";
+
+/// Status file of dpkg, listed by apt-cache policy as a
+/// source of every installed version
+const DPKG_STATUS_FILE: &str = "/var/lib/dpkg/status";
+
+/// Fields of apt-cache records which keep packages installed
+const RELATIONSHIP_FIELDS: [&str; 4] = ["Depends", "Pre-Depends", "Recommends", "Suggests"];
+
+/// Install a list of software using apt
+pub fn install_packages(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+    _backend_packages: &Vec<&Package>,
+) -> anyhow::Result<()> {
+    info!("Using apt to install {} packages", package_list.len());
+
+    // Refresh the package lists first, same as pacman -Sy
+    let apt_exit_status = runner
+        .run(&new_command(APT_GET_COMMAND_STRING, &["update"]))
+        .context("While trying to run apt-get to update package lists")?
+        .status;
+
+    if !apt_exit_status.success() {
+        bail!(
+            "Apt did not successfully update package lists, returned exit code of {}, aborting",
+            apt_exit_status
+        );
+    }
+
+    // Generate apt command arguments, pinned versions are installed as
+    // package=version which may need to downgrade what's installed
+    let mut command = without_prompts(new_command(APT_GET_COMMAND_STRING, &["install"]), "-y");
+    if package_list
+        .iter()
+        .any(|package| package.pinned_version().is_some())
+    {
+        command.push("--allow-downgrades".to_string());
+    }
+    command.push("--".to_string());
+    command.extend(pinned_package_names(package_list, "="));
+
+    let apt_exit_status = runner
+        .run(&command)
+        .with_context(|| {
+            format!(
+                "While trying to run apt-get to install {} packages using apt",
+                package_list.len()
+            )
+        })?
+        .status;
+
+    if !apt_exit_status.success() {
+        bail!(
+            "Apt did not successfully run, returned exit code of {}, aborting",
+            apt_exit_status
+        );
+    }
+
+    info!(
+        "Apt completed installation, with exit code {}, proceeding",
+        apt_exit_status
+    );
+
+    Ok(())
+}
+
+/// Remove all non-package list software using apt
+pub fn remove_non_packages(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+    plan: &BackendPlan,
+) -> anyhow::Result<()> {
+    info!(
+        "Using apt to ensure only {} packages are installed",
+        package_list.len()
+    );
+
+    // Every install reason is rewritten while priming for removal,
+    // so they are put back if the removal doesn't complete
+    APT_INSTALL_REASONS.preserve_during(runner, |manual_packages| {
+        mark_and_remove(runner, package_list, manual_packages, plan)
+    })
+}
+
+/// Marks only the package list as manually installed and removes
+/// exactly the packages the plan removes using apt, given every
+/// package currently manually installed
+fn mark_and_remove(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+    manual_packages: &BTreeSet<String>,
+    plan: &BackendPlan,
+) -> anyhow::Result<()> {
+    info!("Marking all packages as automatically installed to prime for removal");
+
+    // Step 1, mark as automatically installed
+    if !manual_packages.is_empty() {
+        let mut command = new_command(APT_MARK_COMMAND_STRING, &["auto", "--"]);
+        command.extend(manual_packages.iter().cloned());
+
+        let apt_exit_status = runner
+            .run(&command)
+            .context("While trying to run apt-mark to mark packages installed on system as auto")?
+            .status;
+
+        if !apt_exit_status.success() {
+            bail!(
+                "Apt did not successfully run to mark packages installed on system as auto, returned exit code of {}, aborting",
+                apt_exit_status
+            );
+        }
+    }
+
+    // Protected packages are kept even when they aren't in the package list
+    let installed = inventory_packages(runner)?;
+    let protected = installed_protected(
+        SourceBackend::DebianDpkgDatabase,
+        installed.iter().map(|package| package.name.as_str()),
+    );
+
+    info!(
+        "Marking {} packages as manually installed to keep them on system using apt",
+        package_list.len() + protected.len()
+    );
+
+    // Step 2, mark all the package list and protected packages
+    let mut command = new_command(APT_MARK_COMMAND_STRING, &["manual", "--"]);
+    command.extend(package_names(package_list));
+    command.extend(protected);
+
+    let apt_exit_status = runner
+        .run(&command)
+        .context(
+            "While trying to run apt-mark to mark packages in blueprint as manually installed.",
+        )?
+        .status;
+
+    if !apt_exit_status.success() {
+        bail!(
+            "Apt did not successfully run to mark packages in blueprint as manually installed. returned exit code of {}, aborting",
+            apt_exit_status
+        );
+    }
+
+    // Step 3, remove exactly what the plan removes, as confirmed by the
+    // user, rather than whatever apt would consider autoremovable now
+    let remove = without_protected(
+        SourceBackend::DebianDpkgDatabase,
+        plan.remove
+            .iter()
+            .filter(|name| installed.iter().any(|package| package.name == **name))
+            .cloned()
+            .collect(),
+    );
+
+    if remove.is_empty() {
+        info!("No unrequired packages found from apt, nothing needed to be removed, returning");
+        return Ok(());
+    }
+
+    info!(
+        "Removing unrequired packages using apt: {}",
+        remove.join(" ")
+    );
+
+    // Step 4, purge every unrequired package, their dependencies which
+    // are no longer required are in the plan already
+    let mut command = without_prompts(new_command(APT_GET_COMMAND_STRING, &["purge", "--"]), "-y");
+    command.extend(remove);
+
+    let apt_exit_status = runner
+        .run(&command)
+        .context("While trying to run apt-get to purge unrequired packages")?
+        .status;
+
+    if !apt_exit_status.success() {
+        bail!(
+            "Apt did not successfully purge unrequired packages, returned exit code of {}, aborting",
+            apt_exit_status
+        );
+    }
+
+    info!("Successfully removed orphaned packages, system now contains only specified packages");
+
+    Ok(())
+}
+
+/// Parses a dpkg relationship field such as "libc6 (>= 2.34), a | b"
+/// into its dependencies, each a list of alternatives
+fn parse_relationships(value: &str) -> Vec<Vec<String>> {
+    value
+        .split(',')
+        .map(|dependency| {
+            dependency
+                .split('|')
+                .filter_map(|alternative| alternative.split_whitespace().next())
+                // Drop architecture qualifiers such as python3:any
+                .map(|name| name.split(':').next().unwrap_or(name).to_string())
+                .collect::<Vec<String>>()
+        })
+        .filter(|alternatives| !alternatives.is_empty())
+        .collect()
+}
+
+/// Reads every package installed in the dpkg database
+pub fn installed_packages(runner: &dyn CommandRunner) -> anyhow::Result<DependencyGraph> {
+    let manual_packages: HashSet<String> = explicit_packages(runner)?.into_iter().collect();
+
+    let output = runner
+        .query_stdout(&new_command(
+            DPKG_QUERY_COMMAND_STRING,
+            &["-W", "-f", DPKG_QUERY_FORMAT],
+        ))
+        .context("While trying to get installed packages from dpkg-query")?;
+
+    let installed = output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let status = fields.next()?;
+            let name = fields.next()?.to_string();
+            let version = fields.next()?.to_string();
+
+            // Second status character is the current package state
+            if status.chars().nth(1) != Some('i') {
+                return None;
+            }
+
+            Some(InstalledPackage {
+                explicit: manual_packages.contains(&name),
+                depends: parse_relationships(fields.next().unwrap_or_default()),
+                provides: parse_relationships(fields.next().unwrap_or_default())
+                    .into_iter()
+                    .flatten()
+                    .collect(),
+                name,
+                version,
+            })
+        })
+        .collect();
+
+    Ok(DependencyGraph::new(installed))
+}
+
+/// Reads the dependencies of packages which are not
+/// installed yet from the apt package lists
+fn candidate_dependencies(
+    runner: &dyn CommandRunner,
+    names: &[String],
+) -> anyhow::Result<Vec<String>> {
+    let mut query = new_command(
+        APT_CACHE_COMMAND_STRING,
+        &["show", "--no-all-versions", "--"],
+    );
+    query.extend(names.iter().cloned());
+
+    let output = runner
+        .query_stdout(&query)
+        .context("While trying to get package list information for packages to install from apt")?;
+
+    // Long values wrap onto lines starting with whitespace
+    let mut dependencies = Vec::new();
+    let mut current_field: Option<&str> = None;
+    for line in output.lines() {
+        let value = match line.split_once(':') {
+            Some((key, value)) if !line.starts_with(char::is_whitespace) => {
+                current_field = Some(key);
+                value
+            }
+            _ => line,
+        };
+
+        if current_field.is_some_and(|field| RELATIONSHIP_FIELDS.contains(&field)) {
+            dependencies.extend(parse_relationships(value).into_iter().flatten());
+        }
+    }
+
+    Ok(dependencies)
+}
+
+/// Works out what installing and removing the package
+/// list using apt would change, without changing anything
+pub fn plan_packages(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+) -> anyhow::Result<BackendPlan> {
+    let graph = installed_packages(runner)?;
+
+    // Packages about to be installed keep their already installed dependencies
+    let missing = graph.missing(package_list);
+    let install_dependencies = if missing.is_empty() {
+        Vec::new()
+    } else {
+        candidate_dependencies(runner, &missing)?
+    };
+
+    Ok(graph.plan(
+        SourceBackend::DebianDpkgDatabase,
+        package_list,
+        &install_dependencies,
+    ))
+}
+
+/// Reads the archive each installed package came from out of apt-cache
+/// policy, which lists the sources of the installed version indented
+/// under the line marked with "***"
+fn installed_origins(
+    runner: &dyn CommandRunner,
+    names: Vec<String>,
+) -> anyhow::Result<HashMap<String, String>> {
+    let mut query = new_command(APT_CACHE_COMMAND_STRING, &["policy", "--"]);
+    query.extend(names);
+
+    let output = runner
+        .query_stdout(&query)
+        .context("While trying to run apt-cache to get where installed packages came from")?;
+
+    let mut origins = HashMap::new();
+    let mut current_package: Option<&str> = None;
+    let mut in_installed_version = false;
+    for line in output.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            // Headers are "name:" or "name:architecture:"
+            current_package = line.split(':').next();
+            in_installed_version = false;
+        } else if line.starts_with(" *** ") {
+            in_installed_version = true;
+        } else if !line.starts_with("        ") {
+            in_installed_version = false;
+        } else if in_installed_version
+            && let Some(name) = current_package
+            && !origins.contains_key(name)
+        {
+            // Sources are "priority url suite/component architecture Packages",
+            // the dpkg status file only says the version is installed
+            let columns: Vec<&str> = line.split_whitespace().collect();
+            if columns.len() >= 3 && columns[1] != DPKG_STATUS_FILE {
+                origins.insert(name.to_string(), format!("{} {}", columns[1], columns[2]));
+            }
+        }
+    }
+
+    Ok(origins)
+}
+
+/// Gets the exact installed version of every package in the package
+/// list along with the archive it came from using apt
+pub fn installed_versions(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+) -> anyhow::Result<Vec<InstalledVersion>> {
+    let graph = installed_packages(runner)?;
+    let mut installed = graph.installed_versions(package_list, |_| None);
+    if installed.is_empty() {
+        return Ok(installed);
+    }
+
+    let origins = installed_origins(
+        runner,
+        installed
+            .iter()
+            .map(|version| version.name.clone())
+            .collect(),
+    )?;
+    for version in &mut installed {
+        version.repository = origins.get(&version.name).cloned();
+    }
+
+    Ok(installed)
+}
+
+/// Gets all of the packages which have been
+/// manually installed on the system
+pub fn explicit_packages(runner: &dyn CommandRunner) -> anyhow::Result<Vec<String>> {
+    Ok(runner
+        .query_stdout(&new_command(APT_MARK_COMMAND_STRING, &["showmanual"]))
+        .context("While trying to run apt-mark to get manually installed packages")?
+        .split_whitespace()
+        .map(String::from)
+        .collect())
+}
+
+/// Gets every package installed in the dpkg database
+pub fn inventory_packages(runner: &dyn CommandRunner) -> anyhow::Result<Vec<InventoryPackage>> {
+    Ok(installed_packages(runner)?.inventory())
+}
+
+/// Brings the dpkg database back to an earlier snapshot,
+/// packages are reinstalled at their earlier version using apt
+pub fn rollback_packages(
+    runner: &dyn CommandRunner,
+    rollback: &BackendRollback,
+) -> anyhow::Result<()> {
+    info!(
+        "Using apt to reinstall {} packages and remove {} packages",
+        rollback.install.len(),
+        rollback.remove.len()
+    );
+
+    // Step 1, reinstall every package at its earlier version
+    if !rollback.install.is_empty() {
+        let mut command = without_prompts(
+            new_command(
+                APT_GET_COMMAND_STRING,
+                &["install", "--allow-downgrades", "--"],
+            ),
+            "-y",
+        );
+        command.extend(
+            rollback
+                .install
+                .iter()
+                .map(|(name, version)| format!("{}={}", name, version)),
+        );
+
+        let apt_exit_status = runner
+            .run(&command)
+            .context("While trying to run apt-get to reinstall packages")?
+            .status;
+
+        if !apt_exit_status.success() {
+            bail!(
+                "Apt did not successfully reinstall packages, returned exit code of {}, aborting",
+                apt_exit_status
+            );
+        }
+    }
+
+    // Step 2, remove every package which wasn't installed before
+    let remove = without_protected(SourceBackend::DebianDpkgDatabase, rollback.remove.clone());
+    if !remove.is_empty() {
+        let mut command =
+            without_prompts(new_command(APT_GET_COMMAND_STRING, &["remove", "--"]), "-y");
+        command.extend(remove);
+
+        let apt_exit_status = runner
+            .run(&command)
+            .context("While trying to run apt-get to remove packages")?
+            .status;
+
+        if !apt_exit_status.success() {
+            bail!(
+                "Apt did not successfully remove packages, returned exit code of {}, aborting",
+                apt_exit_status
+            );
+        }
+    }
+
+    // Step 3, put the install reasons the generation changed back
+    APT_INSTALL_REASONS.set(runner, &rollback.mark_explicit, &rollback.mark_dependency)?;
+
+    info!("Apt completed rolling back, proceeding");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{
+        config::ROOT_CONFIG,
+        package::{sources::PackageSource, version::VersionConstraint},
+        runner::{RecordingRunner, argv},
+    };
+
+    #[test]
+    fn install_pins_versions_with_downgrades_allowed() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        let packages = [
+            Package::new("curl".to_string(), PackageSource::DebianApt),
+            Package {
+                version: Some(VersionConstraint::pinned("2:9.1.0016-1".to_string())),
+                ..Package::new("vim".to_string(), PackageSource::DebianApt)
+            },
+        ];
+
+        install_packages(&runner, &packages.iter().collect(), &Vec::new()).unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec![
+                argv(&["apt-get", "update"]),
+                argv(&[
+                    "apt-get",
+                    "install",
+                    "-y",
+                    "--allow-downgrades",
+                    "--",
+                    "curl",
+                    "vim=2:9.1.0016-1"
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn removal_purges_exactly_the_plan() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        runner.push_output(0, "curl\nold\n");
+        runner.push_output(0, "");
+        runner.push_output(0, "");
+        runner.push_output(
+            0,
+            "ii \tapt\t2.7.14\t\t\nii \tcurl\t8.5.0-2\t\t\nii \told\t1.0\t\t\n",
+        );
+
+        let packages = [Package::new("curl".to_string(), PackageSource::DebianApt)];
+        let plan = BackendPlan {
+            remove: argv(&["old", "apt", "gone"]),
+            ..BackendPlan::new(SourceBackend::DebianDpkgDatabase)
+        };
+
+        remove_non_packages(&runner, &packages.iter().collect(), &plan).unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec![
+                argv(&["apt-mark", "showmanual"]),
+                argv(&["apt-mark", "auto", "--", "curl", "old"]),
+                argv(&["apt-mark", "showmanual"]),
+                argv(&["dpkg-query", "-W", "-f", DPKG_QUERY_FORMAT]),
+                argv(&["apt-mark", "manual", "--", "curl", "apt"]),
+                argv(&["apt-get", "purge", "-y", "--", "old"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rollback_never_removes_protected_packages() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        let rollback = BackendRollback {
+            install: BTreeMap::from([("vim".to_string(), "2:9.1.0016-1".to_string())]),
+            options: BTreeMap::new(),
+            remove: argv(&["apt", "old"]),
+            mark_explicit: Vec::new(),
+            mark_dependency: argv(&["curl"]),
+        };
+
+        rollback_packages(&runner, &rollback).unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec![
+                argv(&[
+                    "apt-get",
+                    "install",
+                    "--allow-downgrades",
+                    "-y",
+                    "--",
+                    "vim=2:9.1.0016-1"
+                ]),
+                argv(&["apt-get", "remove", "-y", "--", "old"]),
+                argv(&["apt-mark", "auto", "--", "curl"]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,316 @@
+//! Helpers shared between the Arch Linux sources
+//! which all read from the same local pacman database
+
+use std::collections::HashMap;
+
+use anyhow::Context;
+
+use crate::{
+    package::{
+        lock::InstalledVersion,
+        package::Package,
+        plan::BackendPlan,
+        source::{
+            command::new_command,
+            depgraph::{DependencyGraph, InstalledPackage},
+        },
+        sources::SourceBackend,
+    },
+    runner::CommandRunner,
+};
+
+/// Command listing the packages of the sync repositories
+const SYNC_LIST_COMMAND_STRING: &str = "pacman";
+
+/// Strips a version constraint such as ">=1.2" or "=5.2"
+/// from a dependency/provides entry of pacman
+fn strip_version(entry: &str) -> String {
+    entry
+        .split(['<', '>', '='])
+        .next()
+        .unwrap_or(entry)
+        .to_string()
+}
+
+/// Parses a list field of pacman's package information
+/// output, where "None" is an empty list
+fn parse_list_field(value: &str) -> Vec<String> {
+    if value == "None" {
+        return Vec::new();
+    }
+
+    value.split_whitespace().map(strip_version).collect()
+}
+
+/// Parses the optional dependencies field of pacman's package information
+/// output, where each line is "name: description" followed by "[installed]"
+/// when the dependency is installed
+fn parse_optional_field(value: &str) -> Vec<String> {
+    if value == "None" {
+        return Vec::new();
+    }
+
+    value
+        .lines()
+        .filter_map(|line| line.split(':').next())
+        .map(|name| strip_version(name.trim()))
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// Parses a single package record of pacman's -Qi output
+fn parse_package_record(record: &str) -> anyhow::Result<InstalledPackage> {
+    let mut name = None;
+    let mut version = String::new();
+    let mut explicit = false;
+    let mut depends = Vec::new();
+    let mut provides = Vec::new();
+    let mut optional = Vec::new();
+
+    // Long values wrap onto lines starting with whitespace, join them
+    // back on to the field they belong to keeping each line separate
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in record.lines() {
+        match (line.starts_with(char::is_whitespace), fields.last_mut()) {
+            (true, Some((_, value))) => {
+                value.push('\n');
+                value.push_str(line.trim());
+            }
+            _ => {
+                if let Some((key, value)) = line.split_once(':') {
+                    fields.push((key.trim().to_string(), value.trim().to_string()));
+                }
+            }
+        }
+    }
+
+    for (key, value) in fields {
+        match key.as_str() {
+            "Name" => name = Some(value),
+            "Version" => version = value,
+            "Install Reason" => explicit = value.starts_with("Explicitly"),
+            "Depends On" => depends = parse_list_field(&value),
+            "Provides" => provides = parse_list_field(&value),
+            "Optional Deps" => optional = parse_optional_field(&value),
+            _ => {}
+        }
+    }
+
+    Ok(InstalledPackage {
+        name: name.with_context(|| format!("Package information has no name: {:?}", record))?,
+        version,
+        explicit,
+        // Installed optional dependencies keep a package on the system
+        // just like dependencies do, as pacman -Qt doesn't list them
+        depends: depends
+            .into_iter()
+            .chain(optional)
+            .map(|dependency| vec![dependency])
+            .collect(),
+        provides,
+    })
+}
+
+/// Parses every package record out of pacman's -Qi/-Si output
+fn parse_package_records(output: &str) -> anyhow::Result<Vec<InstalledPackage>> {
+    output
+        .split("\n\n")
+        .filter(|record| !record.trim().is_empty())
+        .map(parse_package_record)
+        .collect()
+}
+
+/// Reads every package installed in the local pacman
+/// database through the supplied pacman compatible command
+pub fn installed_packages(
+    runner: &dyn CommandRunner,
+    command: &str,
+) -> anyhow::Result<DependencyGraph> {
+    let output = runner
+        .query_stdout(&new_command(command, &["-Qi"]))
+        .with_context(|| format!("While trying to get installed packages from {}", command))?;
+
+    Ok(DependencyGraph::new(parse_package_records(&output)?))
+}
+
+/// Reads which sync repository every installed package came from, through
+/// pacman itself as AUR helpers would also list every package in the AUR
+fn installed_repositories(runner: &dyn CommandRunner) -> anyhow::Result<HashMap<String, String>> {
+    let output = runner
+        .query_stdout(&new_command(SYNC_LIST_COMMAND_STRING, &["-Sl"]))
+        .context("While trying to get the packages of every sync repository")?;
+
+    // Lines are "repository name version [installed]", where the
+    // marker includes the installed version when it differs
+    Ok(output
+        .lines()
+        .filter(|line| line.contains("[installed"))
+        .filter_map(|line| {
+            let mut columns = line.split_whitespace();
+            let repository = columns.next()?.to_string();
+            Some((columns.next()?.to_string(), repository))
+        })
+        .collect())
+}
+
+/// Gets the exact installed version of every package in the package list
+/// along with its sync repository, foreign packages such as those from the
+/// AUR have no repository
+pub fn installed_versions(
+    runner: &dyn CommandRunner,
+    command: &str,
+    package_list: &Vec<&Package>,
+) -> anyhow::Result<Vec<InstalledVersion>> {
+    let graph = installed_packages(runner, command)?;
+    let repositories = installed_repositories(runner)?;
+
+    Ok(graph.installed_versions(package_list, |name| repositories.get(name).cloned()))
+}
+
+/// Reads the dependencies of packages which are not installed yet
+/// from the sync databases through the supplied pacman compatible command
+fn sync_dependencies(
+    runner: &dyn CommandRunner,
+    command: &str,
+    names: &[String],
+) -> anyhow::Result<Vec<String>> {
+    let mut query = new_command(command, &["-Si", "--"]);
+    query.extend(names.iter().cloned());
+
+    let output = runner.query_stdout(&query).with_context(|| {
+        format!(
+            "While trying to get repository information for packages to install from {}",
+            command
+        )
+    })?;
+
+    Ok(parse_package_records(&output)?
+        .into_iter()
+        .flat_map(|package| package.depends.into_iter().flatten())
+        .collect())
+}
+
+/// Works out what running the install and removal steps on the
+/// package list would change in the local pacman database
+pub fn plan_packages(
+    runner: &dyn CommandRunner,
+    command: &str,
+    package_list: &Vec<&Package>,
+) -> anyhow::Result<BackendPlan> {
+    let graph = installed_packages(runner, command)?;
+
+    // Packages about to be installed keep their already installed dependencies
+    let missing = graph.missing(package_list);
+    let install_dependencies = if missing.is_empty() {
+        Vec::new()
+    } else {
+        sync_dependencies(runner, command, &missing)?
+    };
+
+    Ok(graph.plan(
+        SourceBackend::ArchLocalDatabase,
+        package_list,
+        &install_dependencies,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ROOT_CONFIG, package::sources::PackageSource};
+
+    const INSTALLED: &str = "\
+Name            : mpv
+Version         : 1:0.38.0-1
+Depends On      : ffmpeg  libass>=0.17
+Optional Deps   : yt-dlp: for video-sharing websites playback [installed]
+                  youtube-dl: for video-sharing websites playback
+Provides        : None
+Install Reason  : Explicitly installed
+
+Name            : ffmpeg
+Version         : 2:7.0-1
+Depends On      : None
+Optional Deps   : None
+Provides        : None
+Install Reason  : Installed as a dependency for another package
+
+Name            : libass
+Version         : 0.17.1-1
+Depends On      : None
+Optional Deps   : None
+Provides        : None
+Install Reason  : Installed as a dependency for another package
+
+Name            : yt-dlp
+Version         : 2024.04.09-1
+Depends On      : None
+Optional Deps   : None
+Provides        : None
+Install Reason  : Installed as a dependency for another package
+
+Name            : lonely
+Version         : 1.0-1
+Depends On      : None
+Optional Deps   : None
+Provides        : None
+Install Reason  : Installed as a dependency for another package
+";
+
+    #[test]
+    fn optional_dependencies_are_parsed() {
+        let packages = parse_package_records(INSTALLED).unwrap();
+
+        assert_eq!(packages[0].name, "mpv");
+        assert_eq!(
+            packages[0].depends,
+            vec![
+                vec!["ffmpeg".to_string()],
+                vec!["libass".to_string()],
+                vec!["yt-dlp".to_string()],
+                vec!["youtube-dl".to_string()],
+            ]
+        );
+        assert!(packages[0].explicit);
+        assert!(packages[1].depends.is_empty());
+    }
+
+    #[test]
+    fn protected_explicit_packages_are_not_demoted() {
+        ROOT_CONFIG.set_test_config();
+        let mut installed = parse_package_records(INSTALLED).unwrap();
+        installed.push(InstalledPackage {
+            name: "pacman".to_string(),
+            version: "6.1.0-3".to_string(),
+            explicit: true,
+            depends: Vec::new(),
+            provides: Vec::new(),
+        });
+        installed.push(InstalledPackage {
+            name: "htop".to_string(),
+            version: "3.3.0-1".to_string(),
+            explicit: true,
+            depends: Vec::new(),
+            provides: Vec::new(),
+        });
+        let graph = DependencyGraph::new(installed);
+        let packages = [Package::new("mpv".to_string(), PackageSource::ArchPacman)];
+
+        let plan = graph.plan(
+            SourceBackend::ArchLocalDatabase,
+            &packages.iter().collect(),
+            &[],
+        );
+
+        assert_eq!(plan.mark_dependency, vec!["htop".to_string()]);
+        assert_eq!(plan.undeclared(), vec!["htop".to_string()]);
+        assert_eq!(plan.drift(), 1);
+    }
+
+    #[test]
+    fn installed_optional_dependencies_are_kept() {
+        let graph = DependencyGraph::new(parse_package_records(INSTALLED).unwrap());
+
+        assert_eq!(graph.unrequired_by(["mpv"]), vec!["lonely"]);
+    }
+}
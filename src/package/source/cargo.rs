@@ -0,0 +1,426 @@
+//! Cargo integration for installing rust binary crates
+
+use anyhow::{Context, bail};
+use log::info;
+
+use crate::{
+    package::{
+        history::{BackendRollback, InventoryPackage},
+        lock::InstalledVersion,
+        package::Package,
+        plan::{BackendPlan, protected_removals, without_protected},
+        source::command::new_command,
+        sources::SourceBackend,
+        version::version_mismatches,
+    },
+    runner::CommandRunner,
+};
+
+const CARGO_COMMAND_STRING: &str = "cargo";
+
+/// Registry crates are installed from when cargo doesn't list a source
+const CARGO_DEFAULT_REGISTRY: &str = "crates.io";
+
+/// A crate installed through cargo install
+struct InstalledCrate {
+    // Name of the crate
+    name: String,
+
+    // Version of the crate installed
+    version: String,
+
+    // Where the crate was installed from, such as a git repository
+    // with its commit, or crates.io when not listed
+    source: String,
+}
+
+/// Gets every crate installed through cargo install
+fn installed_crates(runner: &dyn CommandRunner) -> anyhow::Result<Vec<InstalledCrate>> {
+    let output = runner
+        .query_stdout(&new_command(CARGO_COMMAND_STRING, &["install", "--list"]))
+        .context("While trying to run cargo to get all installed crates")?;
+
+    // Crates are listed as "name v1.2.3 (source):" followed by
+    // their indented binaries, which are skipped
+    Ok(output
+        .lines()
+        .filter(|line| !line.starts_with(char::is_whitespace))
+        .filter_map(|line| {
+            let mut columns = line.split_whitespace();
+            let name = columns.next()?.to_string();
+            let version = columns
+                .next()?
+                .trim_start_matches('v')
+                .trim_end_matches(':')
+                .to_string();
+            let source = columns
+                .next()
+                .map(|source| source.trim_start_matches('(').trim_end_matches("):"))
+                .unwrap_or(CARGO_DEFAULT_REGISTRY)
+                .to_string();
+
+            Some(InstalledCrate {
+                name,
+                version,
+                source,
+            })
+        })
+        .collect())
+}
+
+/// Cargo specific options a package is installed with besides its
+/// version, which generations record so rollbacks can replay them
+pub fn install_options(package: &Package) -> Vec<String> {
+    let mut options = Vec::new();
+
+    if let Some(git) = &package.git {
+        options.extend(["--git".to_string(), git.clone()]);
+    }
+
+    if let Some(branch) = &package.branch {
+        options.extend(["--branch".to_string(), branch.clone()]);
+    }
+
+    if !package.features.is_empty() {
+        options.extend(["--features".to_string(), package.features.join(",")]);
+    }
+
+    if package.locked {
+        options.push("--locked".to_string());
+    }
+
+    options
+}
+
+/// Generates the cargo install command for a single
+/// package including all of its cargo specific options
+fn install_command(package: &Package) -> Vec<String> {
+    let mut command = new_command(CARGO_COMMAND_STRING, &["install"]);
+
+    if let Some(version) = &package.version {
+        command.extend(["--version".to_string(), version.to_string()]);
+    }
+
+    command.extend(install_options(package));
+    command.extend(["--".to_string(), package.name.clone()]);
+
+    command
+}
+
+/// Install a list of software using cargo
+pub fn install_packages(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+    _backend_packages: &Vec<&Package>,
+) -> anyhow::Result<()> {
+    info!("Using cargo to install {} packages", package_list.len());
+
+    // Each crate has its own options, so is installed on its own
+    for package in package_list {
+        let cargo_exit_status = runner
+            .run(&install_command(package))
+            .with_context(|| format!("While trying to run cargo to install {}", package))?
+            .status;
+
+        if !cargo_exit_status.success() {
+            bail!(
+                "Cargo did not successfully install {}, returned exit code of {}, aborting",
+                package,
+                cargo_exit_status
+            );
+        }
+    }
+
+    info!("Cargo completed installation, proceeding");
+
+    Ok(())
+}
+
+/// Names of every installed crate which is not in the package list
+fn undeclared_crates(installed: Vec<InstalledCrate>, package_list: &Vec<&Package>) -> Vec<String> {
+    installed
+        .into_iter()
+        .filter(|installed| {
+            !package_list
+                .iter()
+                .any(|package| package.name == installed.name)
+        })
+        .map(|installed| installed.name)
+        .collect()
+}
+
+/// Remove all non-package list crates using cargo
+pub fn remove_non_packages(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+    _plan: &BackendPlan,
+) -> anyhow::Result<()> {
+    info!(
+        "Using cargo to ensure only {} packages are installed",
+        package_list.len()
+    );
+
+    // Step 1, get every installed crate not in the package list
+    let undeclared = without_protected(
+        SourceBackend::CargoInstallRoot,
+        undeclared_crates(installed_crates(runner)?, package_list),
+    );
+
+    if undeclared.is_empty() {
+        info!("No undeclared crates found from cargo, nothing needed to be removed, returning");
+        return Ok(());
+    }
+
+    info!(
+        "Removing undeclared crates from cargo: {}",
+        undeclared.join(" ")
+    );
+
+    // Step 2, uninstall all of them
+    let mut command = new_command(CARGO_COMMAND_STRING, &["uninstall", "--"]);
+    command.extend(undeclared);
+
+    let cargo_exit_status = runner
+        .run(&command)
+        .context("While trying to run cargo to remove undeclared crates")?
+        .status;
+
+    if !cargo_exit_status.success() {
+        bail!(
+            "Cargo did not successfully remove undeclared crates, returned exit code of {}, aborting",
+            cargo_exit_status
+        );
+    }
+
+    info!("Successfully removed orphaned packages, system now contains only specified packages");
+
+    Ok(())
+}
+
+/// Works out what installing and removing the package
+/// list using cargo would change, without changing anything
+pub fn plan_packages(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+) -> anyhow::Result<BackendPlan> {
+    let installed = installed_crates(runner)?;
+    let mut plan = BackendPlan::new(SourceBackend::CargoInstallRoot);
+    plan.installed = installed.len();
+
+    for package in package_list {
+        if !installed
+            .iter()
+            .any(|installed| installed.name == package.name)
+        {
+            plan.install
+                .entry(package.source)
+                .or_default()
+                .push(package.name.clone());
+        }
+    }
+
+    plan.mismatched = version_mismatches(package_list, |name| {
+        installed
+            .iter()
+            .find(|installed| installed.name == name)
+            .map(|installed| installed.version.as_str())
+    });
+    plan.remove = undeclared_crates(installed, package_list);
+
+    // Installed crates don't depend on each other
+    plan.protected = protected_removals(plan.backend, &plan.remove, |_, _| false);
+
+    Ok(plan)
+}
+
+/// Gets the exact installed version of every crate in the package
+/// list along with where it was installed from
+pub fn installed_versions(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+) -> anyhow::Result<Vec<InstalledVersion>> {
+    let installed = installed_crates(runner)?;
+
+    Ok(package_list
+        .iter()
+        .filter_map(|package| {
+            let installed = installed
+                .iter()
+                .find(|installed| installed.name == package.name)?;
+
+            Some(InstalledVersion {
+                name: package.name.clone(),
+                version: installed.version.clone(),
+                repository: Some(installed.source.clone()),
+            })
+        })
+        .collect())
+}
+
+/// Gets all of the crates installed through cargo install
+pub fn explicit_packages(runner: &dyn CommandRunner) -> anyhow::Result<Vec<String>> {
+    Ok(installed_crates(runner)?
+        .into_iter()
+        .map(|installed| installed.name)
+        .collect())
+}
+
+/// Gets every crate installed through cargo install, which
+/// are all explicitly installed
+pub fn inventory_packages(runner: &dyn CommandRunner) -> anyhow::Result<Vec<InventoryPackage>> {
+    Ok(installed_crates(runner)?
+        .into_iter()
+        .map(|installed| InventoryPackage {
+            name: installed.name,
+            version: installed.version,
+            explicit: true,
+        })
+        .collect())
+}
+
+/// Brings the crates in cargo's install root back to an earlier
+/// snapshot, crates are reinstalled at their earlier version with the
+/// options they were last installed with, such as their git repository
+pub fn rollback_packages(
+    runner: &dyn CommandRunner,
+    rollback: &BackendRollback,
+) -> anyhow::Result<()> {
+    info!(
+        "Using cargo to reinstall {} crates and remove {} crates",
+        rollback.install.len(),
+        rollback.remove.len()
+    );
+
+    // Step 1, reinstall every crate at its earlier version
+    for (name, version) in &rollback.install {
+        let mut command = new_command(CARGO_COMMAND_STRING, &["install", "--version", version]);
+        command.extend(rollback.options.get(name).into_iter().flatten().cloned());
+        command.extend(["--".to_string(), name.clone()]);
+
+        let cargo_exit_status = runner
+            .run(&command)
+            .with_context(|| {
+                format!(
+                    "While trying to run cargo to reinstall version {} of {}",
+                    version, name
+                )
+            })?
+            .status;
+
+        if !cargo_exit_status.success() {
+            bail!(
+                "Cargo did not successfully reinstall version {} of {}, returned exit code of {}, aborting",
+                version,
+                name,
+                cargo_exit_status
+            );
+        }
+    }
+
+    // Step 2, uninstall every crate which wasn't installed before
+    let remove = without_protected(SourceBackend::CargoInstallRoot, rollback.remove.clone());
+    if !remove.is_empty() {
+        let mut command = new_command(CARGO_COMMAND_STRING, &["uninstall", "--"]);
+        command.extend(remove);
+
+        let cargo_exit_status = runner
+            .run(&command)
+            .context("While trying to run cargo to remove crates")?
+            .status;
+
+        if !cargo_exit_status.success() {
+            bail!(
+                "Cargo did not successfully remove crates, returned exit code of {}, aborting",
+                cargo_exit_status
+            );
+        }
+    }
+
+    info!("Cargo completed rolling back, proceeding");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{
+        config::ROOT_CONFIG,
+        package::sources::PackageSource,
+        runner::{RecordingRunner, argv},
+    };
+
+    #[test]
+    fn install_passes_every_option() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        let packages = [Package {
+            git: Some("https://host/tool".to_string()),
+            branch: Some("main".to_string()),
+            features: vec!["a".to_string(), "b".to_string()],
+            locked: true,
+            ..Package::new("tool".to_string(), PackageSource::Cargo)
+        }];
+
+        install_packages(&runner, &packages.iter().collect(), &Vec::new()).unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec![argv(&[
+                "cargo",
+                "install",
+                "--git",
+                "https://host/tool",
+                "--branch",
+                "main",
+                "--features",
+                "a,b",
+                "--locked",
+                "--",
+                "tool"
+            ])]
+        );
+    }
+
+    #[test]
+    fn rollback_replays_the_recorded_options() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        let rollback = BackendRollback {
+            install: BTreeMap::from([
+                ("plain".to_string(), "1.0.0".to_string()),
+                ("tool".to_string(), "0.2.0".to_string()),
+            ]),
+            options: BTreeMap::from([(
+                "tool".to_string(),
+                argv(&["--git", "https://host/tool", "--locked"]),
+            )]),
+            remove: argv(&["added"]),
+            mark_explicit: Vec::new(),
+            mark_dependency: Vec::new(),
+        };
+
+        rollback_packages(&runner, &rollback).unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec![
+                argv(&["cargo", "install", "--version", "1.0.0", "--", "plain"]),
+                argv(&[
+                    "cargo",
+                    "install",
+                    "--version",
+                    "0.2.0",
+                    "--git",
+                    "https://host/tool",
+                    "--locked",
+                    "--",
+                    "tool"
+                ]),
+                argv(&["cargo", "uninstall", "--", "added"]),
+            ]
+        );
+    }
+}
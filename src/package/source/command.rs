@@ -0,0 +1,47 @@
+//! Helpers for building command argument vectors for different sources
+
+use crate::{package::package::Package, prompt::assume_yes};
+
+/// Creates a new command argument vector from
+/// the program and its leading arguments
+pub fn new_command(program: &str, arguments: &[&str]) -> Vec<String> {
+    std::iter::once(program)
+        .chain(arguments.iter().copied())
+        .map(String::from)
+        .collect()
+}
+
+/// Names of all of the packages in the supplied package list
+/// to be appended as arguments to a command.
+pub fn package_names(package_list: &Vec<&Package>) -> Vec<String> {
+    package_list
+        .iter()
+        .map(|package| package.name.clone())
+        .collect()
+}
+
+/// Names of all of the packages in the supplied package list, with the
+/// version appended after the separator for packages pinning an exact version
+pub fn pinned_package_names(package_list: &Vec<&Package>, separator: &str) -> Vec<String> {
+    package_list
+        .iter()
+        .map(|package| match package.pinned_version() {
+            Some(version) => format!("{}{}{}", package.name, separator, version),
+            None => package.name.clone(),
+        })
+        .collect()
+}
+
+/// Adds the flag skipping the prompts of a command when blueprint is
+/// running non-interactively, before the "--" ending its options if it has one
+pub fn without_prompts(mut command: Vec<String>, no_confirm_flag: &str) -> Vec<String> {
+    if assume_yes() {
+        let position = command
+            .iter()
+            .position(|argument| argument == "--")
+            .unwrap_or(command.len());
+        command.insert(position, no_confirm_flag.to_string());
+    }
+
+    command
+}
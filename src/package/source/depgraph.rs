@@ -0,0 +1,250 @@
+//! Dependency graph of the packages installed on the system, used
+//! to work out what a source would remove without changing anything
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::package::{
+    history::InventoryPackage,
+    lock::InstalledVersion,
+    package::Package,
+    plan::{BackendPlan, installed_protected, protected_removals},
+    sources::SourceBackend,
+    version::version_mismatches,
+};
+
+/// A single package installed on the system as reported
+/// by the package source
+#[derive(Debug)]
+pub struct InstalledPackage {
+    // Name of the installed package
+    pub name: String,
+
+    // Version of the installed package as reported by the source
+    pub version: String,
+
+    // Was this package explicitly installed, or as a dependency?
+    pub explicit: bool,
+
+    // Dependencies of this package, each entry is a list of
+    // alternatives where any one of them satisfies the dependency
+    pub depends: Vec<Vec<String>>,
+
+    // Other names this package can satisfy a dependency for
+    pub provides: Vec<String>,
+}
+
+/// All of the installed packages of a source
+/// with lookups for resolving dependencies
+#[derive(Debug)]
+pub struct DependencyGraph {
+    // Installed packages by name
+    packages: BTreeMap<String, InstalledPackage>,
+
+    // Names provided by installed packages -> the installed packages
+    providers: HashMap<String, Vec<String>>,
+}
+
+impl DependencyGraph {
+    /// Creates a new dependency graph from all of the
+    /// packages installed on the system
+    pub fn new(installed: Vec<InstalledPackage>) -> Self {
+        let mut providers: HashMap<String, Vec<String>> = HashMap::new();
+
+        for package in &installed {
+            for provided in &package.provides {
+                providers
+                    .entry(provided.clone())
+                    .or_default()
+                    .push(package.name.clone());
+            }
+        }
+
+        Self {
+            packages: installed
+                .into_iter()
+                .map(|package| (package.name.clone(), package))
+                .collect(),
+            providers,
+        }
+    }
+
+    /// Gets an installed package by its exact name
+    pub fn get(&self, name: &str) -> Option<&InstalledPackage> {
+        self.packages.get(name)
+    }
+
+    /// All of the installed packages in name order
+    pub fn installed(&self) -> impl Iterator<Item = &InstalledPackage> {
+        self.packages.values()
+    }
+
+    /// Every installed package with its exact version
+    pub fn inventory(&self) -> Vec<InventoryPackage> {
+        self.installed()
+            .map(|package| InventoryPackage {
+                name: package.name.clone(),
+                version: package.version.clone(),
+                explicit: package.explicit,
+            })
+            .collect()
+    }
+
+    /// Returns the names of all installed packages which can satisfy
+    /// the supplied name, either directly or through what they provide
+    pub fn resolve(&self, name: &str) -> Vec<&str> {
+        if let Some(package) = self.packages.get(name) {
+            return vec![package.name.as_str()];
+        }
+
+        self.providers
+            .get(name)
+            .map(|providers| providers.iter().map(String::as_str).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns every installed package required to keep the
+    /// supplied root packages on the system, including the roots.
+    ///
+    /// Every installed alternative of a dependency is kept
+    /// to match what the package managers consider "required"
+    pub fn required_by<'a>(&self, roots: impl IntoIterator<Item = &'a str>) -> BTreeSet<String> {
+        let mut required: BTreeSet<String> = BTreeSet::new();
+        let mut unvisited: Vec<&str> = roots
+            .into_iter()
+            .flat_map(|root| self.resolve(root))
+            .collect();
+
+        while let Some(current) = unvisited.pop() {
+            if !required.insert(current.to_string()) {
+                continue;
+            }
+
+            let Some(package) = self.packages.get(current) else {
+                continue;
+            };
+
+            unvisited.extend(
+                package
+                    .depends
+                    .iter()
+                    .flatten()
+                    .flat_map(|dependency| self.resolve(dependency)),
+            );
+        }
+
+        required
+    }
+
+    /// Returns every installed package which is not required
+    /// to keep the supplied root packages on the system
+    pub fn unrequired_by<'a>(&self, roots: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+        let required = self.required_by(roots);
+
+        self.packages
+            .keys()
+            .filter(|name| !required.contains(*name))
+            .cloned()
+            .collect()
+    }
+
+    /// Names of the packages in the package list which are
+    /// not satisfied by any package installed on the system
+    pub fn missing(&self, package_list: &Vec<&Package>) -> Vec<String> {
+        package_list
+            .iter()
+            .filter(|package| self.resolve(&package.name).is_empty())
+            .map(|package| package.name.clone())
+            .collect()
+    }
+
+    /// Gets the exact installed version of every package in the package list
+    /// which is installed, along with the repository it came from as told
+    /// by repository(installed name)
+    pub fn installed_versions(
+        &self,
+        package_list: &Vec<&Package>,
+        repository: impl Fn(&str) -> Option<String>,
+    ) -> Vec<InstalledVersion> {
+        package_list
+            .iter()
+            .filter_map(|package| {
+                let installed = self.get(self.resolve(&package.name).first()?)?;
+
+                Some(InstalledVersion {
+                    name: package.name.clone(),
+                    version: installed.version.clone(),
+                    repository: repository(&installed.name),
+                })
+            })
+            .collect()
+    }
+
+    /// Works out what installing the package list and removing everything
+    /// else would change in this graph. The dependencies of packages about
+    /// to be installed are kept, as they will be required after installing
+    pub fn plan(
+        &self,
+        backend: SourceBackend,
+        package_list: &Vec<&Package>,
+        install_dependencies: &[String],
+    ) -> BackendPlan {
+        let mut plan = BackendPlan::new(backend);
+        plan.installed = self.packages.len();
+
+        for package in package_list {
+            let installed = self.resolve(&package.name);
+
+            if installed.is_empty() {
+                plan.install
+                    .entry(package.source)
+                    .or_default()
+                    .push(package.name.clone());
+            }
+
+            plan.mark_explicit.extend(
+                installed
+                    .into_iter()
+                    .filter_map(|name| self.get(name))
+                    .filter(|installed| !installed.explicit)
+                    .map(|installed| installed.name.clone()),
+            );
+        }
+
+        // Everything explicit outside of the package list will be demoted,
+        // but protected packages are marked explicit again by the removal
+        let protected = installed_protected(
+            backend,
+            self.installed().map(|installed| installed.name.as_str()),
+        );
+        let declared: BTreeSet<&str> = package_list
+            .iter()
+            .flat_map(|package| self.resolve(&package.name))
+            .chain(protected.iter().map(String::as_str))
+            .collect();
+        plan.mark_dependency = self
+            .installed()
+            .filter(|installed| installed.explicit && !declared.contains(installed.name.as_str()))
+            .map(|installed| installed.name.clone())
+            .collect();
+
+        plan.remove = self.unrequired_by(
+            package_list
+                .iter()
+                .map(|package| package.name.as_str())
+                .chain(install_dependencies.iter().map(String::as_str)),
+        );
+
+        plan.mark_explicit.sort();
+        plan.mark_explicit.dedup();
+
+        plan.protected = protected_removals(backend, &plan.remove, |dependent, dependency| {
+            self.required_by([dependent]).contains(dependency)
+        });
+
+        plan.mismatched = version_mismatches(package_list, |name| {
+            self.get(name).map(|installed| installed.version.as_str())
+        });
+
+        plan
+    }
+}
@@ -0,0 +1,678 @@
+//! Dnf integration for package management on Fedora/RHEL based systems
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use anyhow::{Context, bail};
+use log::info;
+
+use crate::{
+    package::{
+        history::{BackendRollback, InventoryPackage},
+        lock::InstalledVersion,
+        package::Package,
+        plan::{BackendPlan, installed_protected, without_protected},
+        source::{
+            command::{new_command, package_names, pinned_package_names, without_prompts},
+            depgraph::{DependencyGraph, InstalledPackage},
+            reasons::InstallReasonCommands,
+        },
+        sources::SourceBackend,
+    },
+    runner::CommandRunner,
+};
+
+const DNF_COMMAND_STRING: &str = "dnf";
+const RPM_COMMAND_STRING: &str = "rpm";
+
+/// Commands dnf uses to snapshot and restore install reasons
+const DNF_INSTALL_REASONS: InstallReasonCommands = InstallReasonCommands {
+    name: DNF_COMMAND_STRING,
+    query_explicit: &[
+        DNF_COMMAND_STRING,
+        "repoquery",
+        "--userinstalled",
+        "--qf",
+        "%{name}\\n",
+    ],
+    new_mark_command: |arguments| new_command(DNF_COMMAND_STRING, arguments),
+    mark_explicit: &["mark", "user", "--"],
+    mark_dependency: &["mark", "dependency", "--"],
+};
+
+/// Format of each line of rpm output used to build the dependency graph,
+/// fields are separated by tabs and list entries by semicolons as
+/// rich dependencies such as "(a if b)" contain spaces. The epoch is
+/// only included in the version when the package has one.
+const RPM_QUERY_FORMAT: &str = "%{NAME}\\t%|EPOCH?{%{EPOCH}:}:{}|%{VERSION}-%{RELEASE}\\t[%{REQUIRENAME};]\\t[%{PROVIDENAME};]\\n";
+
+/// Query format listing every file of a package along with its name
+const RPM_FILES_QUERY_FORMAT: &str = "[%{NAME}\\t%{FILENAMES}\\n]";
+
+/// Words inside of rich dependencies which are not package names
+const RICH_DEPENDENCY_KEYWORDS: [&str; 7] =
+    ["and", "or", "if", "else", "with", "without", "unless"];
+
+/// Install a list of software using dnf
+pub fn install_packages(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+    _backend_packages: &Vec<&Package>,
+) -> anyhow::Result<()> {
+    info!("Using dnf to install {} packages", package_list.len());
+
+    // Generate dnf command arguments, pinned versions are installed
+    // as name-version which dnf upgrades or downgrades to
+    let mut command = without_prompts(new_command(DNF_COMMAND_STRING, &["install", "--"]), "-y");
+    command.extend(pinned_package_names(package_list, "-"));
+
+    let dnf_exit_status = runner
+        .run(&command)
+        .with_context(|| {
+            format!(
+                "While trying to run dnf to install {} packages using dnf",
+                package_list.len()
+            )
+        })?
+        .status;
+
+    if !dnf_exit_status.success() {
+        bail!(
+            "Dnf did not successfully run, returned exit code of {}, aborting",
+            dnf_exit_status
+        );
+    }
+
+    info!(
+        "Dnf completed installation, with exit code {}, proceeding",
+        dnf_exit_status
+    );
+
+    Ok(())
+}
+
+/// Remove all non-package list software using dnf
+pub fn remove_non_packages(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+    plan: &BackendPlan,
+) -> anyhow::Result<()> {
+    info!(
+        "Using dnf to ensure only {} packages are installed",
+        package_list.len()
+    );
+
+    // Every install reason is rewritten while priming for removal,
+    // so they are put back if the removal doesn't complete
+    DNF_INSTALL_REASONS.preserve_during(runner, |user_packages| {
+        mark_and_remove(runner, package_list, user_packages, plan)
+    })
+}
+
+/// Marks only the package list as user installed and removes
+/// exactly the packages the plan removes using dnf, given every
+/// package currently user installed
+fn mark_and_remove(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+    user_packages: &BTreeSet<String>,
+    plan: &BackendPlan,
+) -> anyhow::Result<()> {
+    info!("Marking all packages as installed as dependency to prime for removal");
+
+    // Step 1, mark as installed as dependency
+    if !user_packages.is_empty() {
+        let mut command = new_command(DNF_COMMAND_STRING, &["mark", "dependency", "--"]);
+        command.extend(user_packages.iter().cloned());
+
+        let dnf_exit_status = runner
+            .run(&command)
+            .context("While trying to run dnf to mark packages installed on system as deps")?
+            .status;
+
+        if !dnf_exit_status.success() {
+            bail!(
+                "Dnf did not successfully run to mark packages installed on system as deps, returned exit code of {}, aborting",
+                dnf_exit_status
+            );
+        }
+    }
+
+    // Protected packages are kept even when they aren't in the package list
+    let installed = inventory_packages(runner)?;
+    let protected = installed_protected(
+        SourceBackend::FedoraRpmDatabase,
+        installed.iter().map(|package| package.name.as_str()),
+    );
+
+    info!(
+        "Marking {} packages as user installed to keep them on system using dnf",
+        package_list.len() + protected.len()
+    );
+
+    // Step 2, mark all the package list and protected packages
+    let mut command = new_command(DNF_COMMAND_STRING, &["mark", "user", "--"]);
+    command.extend(package_names(package_list));
+    command.extend(protected);
+
+    let dnf_exit_status = runner
+        .run(&command)
+        .context("While trying to run dnf to mark packages in blueprint as user installed.")?
+        .status;
+
+    if !dnf_exit_status.success() {
+        bail!(
+            "Dnf did not successfully run to mark packages in blueprint as user installed. returned exit code of {}, aborting",
+            dnf_exit_status
+        );
+    }
+
+    // Step 3, remove exactly what the plan removes, as confirmed by the
+    // user, rather than whatever dnf would consider autoremovable now
+    let remove = without_protected(
+        SourceBackend::FedoraRpmDatabase,
+        plan.remove
+            .iter()
+            .filter(|name| installed.iter().any(|package| package.name == **name))
+            .cloned()
+            .collect(),
+    );
+
+    if remove.is_empty() {
+        info!("No unrequired packages found from dnf, nothing needed to be removed, returning");
+        return Ok(());
+    }
+
+    info!(
+        "Removing unrequired packages using dnf: {}",
+        remove.join(" ")
+    );
+
+    // Step 4, remove every unrequired package, their dependencies which are
+    // no longer required are in the plan already so dnf mustn't add more
+    let mut command = without_prompts(
+        new_command(
+            DNF_COMMAND_STRING,
+            &[
+                "remove",
+                "--setopt=clean_requirements_on_remove=False",
+                "--",
+            ],
+        ),
+        "-y",
+    );
+    command.extend(remove);
+
+    let dnf_exit_status = runner
+        .run(&command)
+        .context("While trying to run dnf to remove unrequired packages")?
+        .status;
+
+    if !dnf_exit_status.success() {
+        bail!(
+            "Dnf did not successfully remove unrequired packages, returned exit code of {}, aborting",
+            dnf_exit_status
+        );
+    }
+
+    info!("Successfully removed orphaned packages, system now contains only specified packages");
+
+    Ok(())
+}
+
+/// Parses a single rpm requirement into its alternatives, plain requirements
+/// are one name while rich dependencies such as "(a >= 1 or b)" may be many
+fn parse_requirement(requirement: &str) -> Vec<String> {
+    if !requirement.starts_with('(') {
+        return vec![requirement.to_string()];
+    }
+
+    let mut names = Vec::new();
+    let mut after_operator = false;
+    for token in requirement
+        .split(|character: char| character.is_whitespace() || character == '(' || character == ')')
+        .filter(|token| !token.is_empty())
+    {
+        // Tokens after a comparison are versions, not names
+        if after_operator {
+            after_operator = false;
+            continue;
+        }
+
+        if token.starts_with(['<', '>', '=']) {
+            after_operator = true;
+        } else if !RICH_DEPENDENCY_KEYWORDS.contains(&token) {
+            names.push(token.to_string());
+        }
+    }
+
+    names
+}
+
+/// Splits a semicolon separated list field of the rpm output
+fn split_list_field(value: &str) -> impl Iterator<Item = &str> {
+    value.split(';').filter(|entry| !entry.is_empty())
+}
+
+/// Finds the installed packages providing each file path required by a
+/// package, as rpm lists these separately. Every path is queried at once,
+/// the files of each provider are listed to map it back to its paths
+fn file_providers(
+    runner: &dyn CommandRunner,
+    paths: BTreeSet<&str>,
+) -> anyhow::Result<HashMap<String, Vec<String>>> {
+    let mut providers: HashMap<String, Vec<String>> = HashMap::new();
+    if paths.is_empty() {
+        return Ok(providers);
+    }
+
+    let mut query = new_command(
+        RPM_COMMAND_STRING,
+        &["-q", "--whatprovides", "--qf", RPM_FILES_QUERY_FORMAT, "--"],
+    );
+    query.extend(paths.iter().map(|path| path.to_string()));
+
+    // Unowned paths make rpm exit unsuccessfully, they provide nothing
+    // and are listed without a tab so are skipped along with the rest
+    let output = runner
+        .query(&query)
+        .context("While trying to run rpm to find the owners of required files")?;
+
+    for line in output.stdout.lines() {
+        let Some((name, file)) = line.split_once('\t') else {
+            continue;
+        };
+
+        if paths.contains(file) {
+            let provided = providers.entry(name.to_string()).or_default();
+            if !provided.iter().any(|path| path == file) {
+                provided.push(file.to_string());
+            }
+        }
+    }
+
+    Ok(providers)
+}
+
+/// Reads every package installed in the rpm database
+pub fn installed_packages(runner: &dyn CommandRunner) -> anyhow::Result<DependencyGraph> {
+    let user_packages: HashSet<String> = explicit_packages(runner)?.into_iter().collect();
+
+    let output = runner
+        .query_stdout(&new_command(
+            RPM_COMMAND_STRING,
+            &["-qa", "--qf", RPM_QUERY_FORMAT],
+        ))
+        .context("While trying to get installed packages from rpm")?;
+
+    // Name, version, requirements, provides of every line
+    let records: Vec<(&str, &str, &str, &str)> = output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            Some((
+                fields.next()?,
+                fields.next()?,
+                fields.next()?,
+                fields.next()?,
+            ))
+        })
+        .collect();
+
+    // File requirements are provided by the owner of the file
+    let mut provided_files = file_providers(
+        runner,
+        records
+            .iter()
+            .flat_map(|(_, _, requires, _)| split_list_field(requires))
+            .filter(|requirement| requirement.starts_with('/'))
+            .collect(),
+    )?;
+
+    let installed = records
+        .into_iter()
+        .map(|(name, version, requires, provides)| InstalledPackage {
+            name: name.to_string(),
+            version: version.to_string(),
+            explicit: user_packages.contains(name),
+            depends: split_list_field(requires)
+                .filter(|requirement| !requirement.starts_with("rpmlib("))
+                .map(parse_requirement)
+                .collect(),
+            provides: split_list_field(provides)
+                .map(String::from)
+                .chain(provided_files.remove(name).unwrap_or_default())
+                .collect(),
+        })
+        .collect();
+
+    Ok(DependencyGraph::new(installed))
+}
+
+/// Reads the names of packages which the packages
+/// not installed yet depend on from the repositories
+fn repository_dependencies(
+    runner: &dyn CommandRunner,
+    names: &[String],
+) -> anyhow::Result<Vec<String>> {
+    let mut query = new_command(
+        DNF_COMMAND_STRING,
+        &[
+            "repoquery",
+            "--requires",
+            "--resolve",
+            "--qf",
+            "%{name}\\n",
+            "--",
+        ],
+    );
+    query.extend(names.iter().cloned());
+
+    Ok(runner
+        .query_stdout(&query)
+        .context("While trying to get repository information for packages to install from dnf")?
+        .split_whitespace()
+        .map(String::from)
+        .collect())
+}
+
+/// Works out what installing and removing the package
+/// list using dnf would change, without changing anything
+pub fn plan_packages(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+) -> anyhow::Result<BackendPlan> {
+    let graph = installed_packages(runner)?;
+
+    // Packages about to be installed keep their already installed dependencies
+    let missing = graph.missing(package_list);
+    let install_dependencies = if missing.is_empty() {
+        Vec::new()
+    } else {
+        repository_dependencies(runner, &missing)?
+    };
+
+    Ok(graph.plan(
+        SourceBackend::FedoraRpmDatabase,
+        package_list,
+        &install_dependencies,
+    ))
+}
+
+/// Gets the exact installed version of every package in the package
+/// list along with the repository it was installed from using dnf
+pub fn installed_versions(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+) -> anyhow::Result<Vec<InstalledVersion>> {
+    let graph = installed_packages(runner)?;
+    let mut installed = graph.installed_versions(package_list, |_| None);
+    if installed.is_empty() {
+        return Ok(installed);
+    }
+
+    let mut query = new_command(
+        DNF_COMMAND_STRING,
+        &[
+            "repoquery",
+            "--installed",
+            "--qf",
+            "%{name}\\t%{from_repo}\\n",
+            "--",
+        ],
+    );
+    query.extend(installed.iter().map(|version| version.name.clone()));
+
+    let output = runner
+        .query_stdout(&query)
+        .context("While trying to run dnf to get the repositories packages were installed from")?;
+    let repositories: HashMap<&str, &str> = output
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .filter(|(_, repository)| !repository.is_empty())
+        .collect();
+
+    for version in &mut installed {
+        version.repository = repositories
+            .get(version.name.as_str())
+            .map(|repository| repository.to_string());
+    }
+
+    Ok(installed)
+}
+
+/// Gets all of the packages which have been
+/// installed by the user on the system
+pub fn explicit_packages(runner: &dyn CommandRunner) -> anyhow::Result<Vec<String>> {
+    Ok(runner
+        .query_stdout(&new_command(
+            DNF_COMMAND_STRING,
+            &["repoquery", "--userinstalled", "--qf", "%{name}\\n"],
+        ))
+        .context("While trying to run dnf to get user installed packages")?
+        .split_whitespace()
+        .map(String::from)
+        .collect())
+}
+
+/// Gets every package installed in the rpm database
+pub fn inventory_packages(runner: &dyn CommandRunner) -> anyhow::Result<Vec<InventoryPackage>> {
+    Ok(installed_packages(runner)?.inventory())
+}
+
+/// Brings the rpm database back to an earlier snapshot, packages are
+/// reinstalled as name-version which dnf upgrades or downgrades to
+pub fn rollback_packages(
+    runner: &dyn CommandRunner,
+    rollback: &BackendRollback,
+) -> anyhow::Result<()> {
+    info!(
+        "Using dnf to reinstall {} packages and remove {} packages",
+        rollback.install.len(),
+        rollback.remove.len()
+    );
+
+    // Step 1, reinstall every package at its earlier version
+    if !rollback.install.is_empty() {
+        let mut command =
+            without_prompts(new_command(DNF_COMMAND_STRING, &["install", "--"]), "-y");
+        command.extend(
+            rollback
+                .install
+                .iter()
+                .map(|(name, version)| format!("{}-{}", name, version)),
+        );
+
+        let dnf_exit_status = runner
+            .run(&command)
+            .context("While trying to run dnf to reinstall packages")?
+            .status;
+
+        if !dnf_exit_status.success() {
+            bail!(
+                "Dnf did not successfully reinstall packages, returned exit code of {}, aborting",
+                dnf_exit_status
+            );
+        }
+    }
+
+    // Step 2, remove every package which wasn't installed before
+    let remove = without_protected(SourceBackend::FedoraRpmDatabase, rollback.remove.clone());
+    if !remove.is_empty() {
+        let mut command = without_prompts(new_command(DNF_COMMAND_STRING, &["remove", "--"]), "-y");
+        command.extend(remove);
+
+        let dnf_exit_status = runner
+            .run(&command)
+            .context("While trying to run dnf to remove packages")?
+            .status;
+
+        if !dnf_exit_status.success() {
+            bail!(
+                "Dnf did not successfully remove packages, returned exit code of {}, aborting",
+                dnf_exit_status
+            );
+        }
+    }
+
+    // Step 3, put the install reasons the generation changed back
+    DNF_INSTALL_REASONS.set(runner, &rollback.mark_explicit, &rollback.mark_dependency)?;
+
+    info!("Dnf completed rolling back, proceeding");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{
+        config::ROOT_CONFIG,
+        package::{sources::PackageSource, version::VersionConstraint},
+        runner::{RecordingRunner, argv},
+    };
+
+    #[test]
+    fn install_pins_versions_as_name_version() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        let packages = [
+            Package::new("curl".to_string(), PackageSource::FedoraDnf),
+            Package {
+                version: Some(VersionConstraint::pinned("9.1.0".to_string())),
+                ..Package::new("vim-enhanced".to_string(), PackageSource::FedoraDnf)
+            },
+        ];
+
+        install_packages(&runner, &packages.iter().collect(), &Vec::new()).unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec![argv(&[
+                "dnf",
+                "install",
+                "-y",
+                "--",
+                "curl",
+                "vim-enhanced-9.1.0"
+            ])]
+        );
+    }
+
+    #[test]
+    fn removal_removes_exactly_the_plan() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        runner.push_output(0, "curl\nold\n");
+        runner.push_output(0, "");
+        runner.push_output(0, "");
+        runner.push_output(
+            0,
+            "curl\t8.6.0-1.fc40\tlibcurl;\t\ndnf\t4.19.0-1.fc40\t\t\nold\t1.0-1\t\t\n",
+        );
+
+        let packages = [Package::new("curl".to_string(), PackageSource::FedoraDnf)];
+        let plan = BackendPlan {
+            remove: argv(&["old", "dnf", "gone"]),
+            ..BackendPlan::new(SourceBackend::FedoraRpmDatabase)
+        };
+
+        remove_non_packages(&runner, &packages.iter().collect(), &plan).unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec![
+                argv(&["dnf", "repoquery", "--userinstalled", "--qf", "%{name}\\n"]),
+                argv(&["dnf", "mark", "dependency", "--", "curl", "old"]),
+                argv(&["dnf", "repoquery", "--userinstalled", "--qf", "%{name}\\n"]),
+                argv(&["rpm", "-qa", "--qf", RPM_QUERY_FORMAT]),
+                argv(&["dnf", "mark", "user", "--", "curl", "dnf"]),
+                argv(&[
+                    "dnf",
+                    "remove",
+                    "--setopt=clean_requirements_on_remove=False",
+                    "-y",
+                    "--",
+                    "old"
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rollback_never_removes_protected_packages() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        let rollback = BackendRollback {
+            install: BTreeMap::from([("vim-enhanced".to_string(), "9.1.0-1.fc40".to_string())]),
+            options: BTreeMap::new(),
+            remove: argv(&["dnf", "old"]),
+            mark_explicit: argv(&["curl"]),
+            mark_dependency: Vec::new(),
+        };
+
+        rollback_packages(&runner, &rollback).unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec![
+                argv(&["dnf", "install", "-y", "--", "vim-enhanced-9.1.0-1.fc40"]),
+                argv(&["dnf", "remove", "-y", "--", "old"]),
+                argv(&["dnf", "mark", "user", "--", "curl"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn required_files_are_mapped_to_their_providers_in_one_query() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        runner.push_output(0, "script\n");
+        runner.push_output(
+            0,
+            "script\t1.0-1\t/bin/sh;/usr/bin/env;/usr/bin/unowned;\t\n\
+             bash\t5.2-1\t\t\ncoreutils\t9.4-1\t\t\n",
+        );
+        runner.push_output(
+            1,
+            "bash\t/usr/bin/bash\nbash\t/bin/sh\ncoreutils\t/usr/bin/cat\n\
+             coreutils\t/usr/bin/env\nno package provides /usr/bin/unowned\n",
+        );
+
+        let graph = installed_packages(&runner).unwrap();
+
+        assert_eq!(
+            runner.commands()[2],
+            argv(&[
+                "rpm",
+                "-q",
+                "--whatprovides",
+                "--qf",
+                RPM_FILES_QUERY_FORMAT,
+                "--",
+                "/bin/sh",
+                "/usr/bin/env",
+                "/usr/bin/unowned"
+            ])
+        );
+        assert_eq!(graph.get("bash").unwrap().provides, argv(&["/bin/sh"]));
+        assert_eq!(
+            graph.get("coreutils").unwrap().provides,
+            argv(&["/usr/bin/env"])
+        );
+        assert_eq!(graph.resolve("/usr/bin/unowned"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn rich_dependencies_are_split_into_alternatives() {
+        assert_eq!(
+            parse_requirement("(python3-foo >= 1.2 or python3-bar)"),
+            argv(&["python3-foo", "python3-bar"])
+        );
+        assert_eq!(
+            parse_requirement("libc.so.6()(64bit)"),
+            argv(&["libc.so.6()(64bit)"])
+        );
+    }
+}
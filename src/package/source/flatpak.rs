@@ -0,0 +1,361 @@
+//! Flatpak integration for desktop application management
+
+use std::collections::BTreeMap;
+
+use anyhow::{Context, bail};
+use log::info;
+
+use crate::{
+    package::{
+        history::{BackendRollback, InventoryPackage},
+        lock::InstalledVersion,
+        package::{InstallScope, Package},
+        plan::{BackendPlan, protected_removals, without_protected},
+        source::command::{new_command, without_prompts},
+        sources::SourceBackend,
+    },
+    runner::CommandRunner,
+};
+
+const FLATPAK_COMMAND_STRING: &str = "flatpak";
+
+/// An application installed through flatpak
+struct InstalledApp {
+    // Application ID such as org.mozilla.firefox
+    name: String,
+
+    // Installation the application is in
+    scope: InstallScope,
+
+    // Remote the application was installed from
+    origin: String,
+
+    // Commit of the application which is deployed, its exact version
+    commit: String,
+}
+
+/// Whether or not the installed app is declared in the package list,
+/// packages without a scope match an app in any installation
+fn is_declared(package_list: &Vec<&Package>, app: &InstalledApp) -> bool {
+    package_list.iter().any(|package| {
+        package.name == app.name && package.scope.is_none_or(|scope| scope == app.scope)
+    })
+}
+
+/// Gets every application installed through flatpak
+/// along with the installation it is in
+fn installed_apps(runner: &dyn CommandRunner) -> anyhow::Result<Vec<InstalledApp>> {
+    let output = runner
+        .query_stdout(&new_command(
+            FLATPAK_COMMAND_STRING,
+            &[
+                "list",
+                "--app",
+                "--columns=application,installation,origin,active",
+            ],
+        ))
+        .context("While trying to run flatpak to get all installed applications")?;
+
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let mut columns = line.split_whitespace();
+            let name = columns.next()?.to_string();
+
+            // Custom installations are system wide
+            let scope = match columns.next() {
+                Some("user") => InstallScope::User,
+                _ => InstallScope::System,
+            };
+
+            Some(InstalledApp {
+                name,
+                scope,
+                origin: columns.next().unwrap_or_default().to_string(),
+                commit: columns.next().unwrap_or_default().to_string(),
+            })
+        })
+        .collect())
+}
+
+/// Install a list of software using flatpak
+pub fn install_packages(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+    _backend_packages: &Vec<&Package>,
+) -> anyhow::Result<()> {
+    info!("Using flatpak to install {} packages", package_list.len());
+
+    // Each remote and scope needs its own flatpak command
+    let mut install_groups: BTreeMap<(Option<InstallScope>, Option<&str>), Vec<String>> =
+        BTreeMap::new();
+    for package in package_list {
+        install_groups
+            .entry((package.scope, package.remote.as_deref()))
+            .or_default()
+            .push(package.name.clone());
+    }
+
+    for ((scope, remote), names) in install_groups {
+        // Generate flatpak command arguments
+        let mut command = without_prompts(new_command(FLATPAK_COMMAND_STRING, &["install"]), "-y");
+        command.extend(scope.map(|scope| scope.flag().to_string()));
+        command.push("--".to_string());
+        command.extend(remote.map(String::from));
+        command.extend(names);
+
+        let flatpak_exit_status = runner
+            .run(&command)
+            .context("While trying to run flatpak to install packages")?
+            .status;
+
+        if !flatpak_exit_status.success() {
+            bail!(
+                "Flatpak did not successfully run, returned exit code of {}, aborting",
+                flatpak_exit_status
+            );
+        }
+    }
+
+    info!("Flatpak completed installation, proceeding");
+
+    Ok(())
+}
+
+/// Remove all non-package list applications using flatpak
+pub fn remove_non_packages(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+    _plan: &BackendPlan,
+) -> anyhow::Result<()> {
+    info!(
+        "Using flatpak to ensure only {} packages are installed",
+        package_list.len()
+    );
+
+    // Step 1, get every undeclared application per installation
+    let mut undeclared_apps: BTreeMap<InstallScope, Vec<String>> = BTreeMap::new();
+    for app in installed_apps(runner)? {
+        if !is_declared(package_list, &app) {
+            undeclared_apps.entry(app.scope).or_default().push(app.name);
+        }
+    }
+
+    // Step 2, uninstall every undeclared application
+    for (scope, names) in undeclared_apps {
+        let names = without_protected(SourceBackend::FlatpakInstallations, names);
+        if names.is_empty() {
+            continue;
+        }
+
+        info!(
+            "Removing undeclared applications from flatpak: {}",
+            names.join(" ")
+        );
+
+        let mut command = without_prompts(
+            new_command(FLATPAK_COMMAND_STRING, &["uninstall", scope.flag(), "--"]),
+            "-y",
+        );
+        command.extend(names);
+
+        let flatpak_exit_status = runner
+            .run(&command)
+            .context("While trying to run flatpak to remove undeclared applications")?
+            .status;
+
+        if !flatpak_exit_status.success() {
+            bail!(
+                "Flatpak did not successfully remove undeclared applications, returned exit code of {}, aborting",
+                flatpak_exit_status
+            );
+        }
+    }
+
+    info!("Removing runtimes and extensions no longer used using flatpak");
+
+    // Step 3, remove runtimes nothing uses anymore
+    let flatpak_exit_status = runner
+        .run(&without_prompts(
+            new_command(FLATPAK_COMMAND_STRING, &["uninstall", "--unused"]),
+            "-y",
+        ))
+        .context("While trying to run flatpak to remove unused runtimes")?
+        .status;
+
+    if !flatpak_exit_status.success() {
+        bail!(
+            "Flatpak did not successfully remove unused runtimes, returned exit code of {}, aborting",
+            flatpak_exit_status
+        );
+    }
+
+    info!("Successfully removed orphaned packages, system now contains only specified packages");
+
+    Ok(())
+}
+
+/// Works out what installing and removing the package list
+/// using flatpak would change, without changing anything.
+///
+/// Unused runtimes are left out as they depend on what flatpak
+/// considers unused once the applications are removed.
+pub fn plan_packages(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+) -> anyhow::Result<BackendPlan> {
+    let installed = installed_apps(runner)?;
+    let mut plan = BackendPlan::new(SourceBackend::FlatpakInstallations);
+    plan.installed = installed.len();
+
+    for package in package_list {
+        let is_installed = installed.iter().any(|app| {
+            app.name == package.name && package.scope.is_none_or(|scope| scope == app.scope)
+        });
+
+        if !is_installed {
+            plan.install
+                .entry(package.source)
+                .or_default()
+                .push(package.name.clone());
+        }
+    }
+
+    plan.remove = installed
+        .into_iter()
+        .filter(|app| !is_declared(package_list, app))
+        .map(|app| app.name)
+        .collect();
+
+    // Applications don't depend on each other
+    plan.protected = protected_removals(plan.backend, &plan.remove, |_, _| false);
+
+    Ok(plan)
+}
+
+/// Gets the deployed commit of every application in the package
+/// list which is installed, along with the remote it came from
+pub fn installed_versions(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+) -> anyhow::Result<Vec<InstalledVersion>> {
+    let installed = installed_apps(runner)?;
+
+    Ok(package_list
+        .iter()
+        .filter_map(|package| {
+            let app = installed.iter().find(|app| {
+                app.name == package.name && package.scope.is_none_or(|scope| scope == app.scope)
+            })?;
+
+            Some(InstalledVersion {
+                name: package.name.clone(),
+                version: app.commit.clone(),
+                repository: Some(app.origin.clone()).filter(|origin| !origin.is_empty()),
+            })
+        })
+        .collect())
+}
+
+/// Gets all of the applications installed through flatpak
+pub fn explicit_packages(runner: &dyn CommandRunner) -> anyhow::Result<Vec<String>> {
+    Ok(installed_apps(runner)?
+        .into_iter()
+        .map(|app| app.name)
+        .collect())
+}
+
+/// Gets every application installed through flatpak with
+/// its deployed commit, which are all explicitly installed
+pub fn inventory_packages(runner: &dyn CommandRunner) -> anyhow::Result<Vec<InventoryPackage>> {
+    Ok(installed_apps(runner)?
+        .into_iter()
+        .map(|app| InventoryPackage {
+            name: app.name,
+            version: app.commit,
+            explicit: true,
+        })
+        .collect())
+}
+
+/// Brings the flatpak installations back to an earlier snapshot, installed
+/// applications are updated to their earlier commit. Removed applications
+/// can't be, as the remote they were installed from isn't known
+pub fn rollback_packages(
+    runner: &dyn CommandRunner,
+    rollback: &BackendRollback,
+) -> anyhow::Result<()> {
+    info!(
+        "Using flatpak to reinstall {} applications and remove {} applications",
+        rollback.install.len(),
+        rollback.remove.len()
+    );
+
+    let installed = installed_apps(runner)?;
+    let not_installed: Vec<&str> = rollback
+        .install
+        .keys()
+        .filter(|name| !installed.iter().any(|app| &&app.name == name))
+        .map(String::as_str)
+        .collect();
+    if !not_installed.is_empty() {
+        bail!(
+            "Flatpak can't roll back removed applications, install them again with flatpak install: {}",
+            not_installed.join(" ")
+        );
+    }
+
+    // Step 1, deploy the earlier commit of every application
+    for (name, commit) in &rollback.install {
+        let flatpak_exit_status = runner
+            .run(&without_prompts(
+                new_command(
+                    FLATPAK_COMMAND_STRING,
+                    &["update", &format!("--commit={}", commit), "--", name],
+                ),
+                "-y",
+            ))
+            .with_context(|| {
+                format!(
+                    "While trying to run flatpak to deploy commit {} of {}",
+                    commit, name
+                )
+            })?
+            .status;
+
+        if !flatpak_exit_status.success() {
+            bail!(
+                "Flatpak did not successfully deploy commit {} of {}, returned exit code of {}, aborting",
+                commit,
+                name,
+                flatpak_exit_status
+            );
+        }
+    }
+
+    // Step 2, uninstall every application which wasn't installed before
+    let remove = without_protected(SourceBackend::FlatpakInstallations, rollback.remove.clone());
+    if !remove.is_empty() {
+        let mut command = without_prompts(
+            new_command(FLATPAK_COMMAND_STRING, &["uninstall", "--"]),
+            "-y",
+        );
+        command.extend(remove);
+
+        let flatpak_exit_status = runner
+            .run(&command)
+            .context("While trying to run flatpak to remove applications")?
+            .status;
+
+        if !flatpak_exit_status.success() {
+            bail!(
+                "Flatpak did not successfully remove applications, returned exit code of {}, aborting",
+                flatpak_exit_status
+            );
+        }
+    }
+
+    info!("Flatpak completed rolling back, proceeding");
+
+    Ok(())
+}
@@ -6,5 +6,22 @@ pub mod paru;
 // Arch Linux -> Pacman helper
 pub mod pacman;
 
+// Debian -> Apt helper
+pub mod apt;
+
+// Fedora -> Dnf helper
+pub mod dnf;
+
+// Flatpak -> desktop application helper
+pub mod flatpak;
+
+// Cargo -> rust binary crate helper
+pub mod cargo;
+
+// Shared Arch Linux local database helpers
+mod arch;
+
 // Helpers
-mod shell;
+mod command;
+mod depgraph;
+mod reasons;
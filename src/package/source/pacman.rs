@@ -1,40 +1,216 @@
 //! Pacman integration for package management
 
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
 use anyhow::{Context, bail};
 use log::info;
 
-use crate::package::{
-    package::Package,
-    source::shell::{format_package_list_for_shell, new_shell_command},
+use crate::{
+    package::{
+        history::{BackendRollback, InventoryPackage},
+        lock::InstalledVersion,
+        package::Package,
+        plan::{BackendPlan, installed_protected, without_protected},
+        source::{
+            arch,
+            command::{new_command, package_names, without_prompts},
+            reasons::InstallReasonCommands,
+        },
+        sources::{PackageSource, SourceBackend},
+        version::{VersionConstraint, compare_versions},
+    },
+    runner::CommandRunner,
 };
 
 const PACMAN_COMMAND_STRING: &'static str = "pacman";
 
+/// Commands pacman uses to snapshot and restore install reasons
+const PACMAN_INSTALL_REASONS: InstallReasonCommands = InstallReasonCommands {
+    name: PACMAN_COMMAND_STRING,
+    query_explicit: &[PACMAN_COMMAND_STRING, "-Qqe"],
+    new_mark_command: |arguments| new_command(PACMAN_COMMAND_STRING, arguments),
+    mark_explicit: &["-D", "--asexplicit", "--"],
+    mark_dependency: &["-D", "--asdeps", "--"],
+};
+
+/// Where pacman keeps the package files it has downloaded
+const PACMAN_CACHE_DIRECTORY: &'static str = "/var/cache/pacman/pkg";
+
+/// Archive of every package version released by Arch Linux
+const ARCH_ARCHIVE_URL: &'static str = "https://archive.archlinux.org/packages";
+
+/// Gets the architecture of a package from the sync databases, falling
+/// back to the architecture of this system when it can't be found
+fn package_architecture(runner: &dyn CommandRunner, name: &str) -> anyhow::Result<String> {
+    let output = runner
+        .query(&new_command(PACMAN_COMMAND_STRING, &["-Si", "--", name]))
+        .with_context(|| {
+            format!(
+                "While trying to run pacman to get the architecture of {}",
+                name
+            )
+        })?
+        .stdout;
+
+    Ok(output
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim() == "Architecture")
+        .map(|(_, value)| value.trim().to_string())
+        .unwrap_or(std::env::consts::ARCH.to_string()))
+}
+
+/// Finds the package file in the directory starting with the prefix, which
+/// is the latest release when the prefix doesn't have a release. Package
+/// files are named name-version-release-architecture.pkg.tar.*
+fn latest_package_file(directory: &Path, prefix: &str) -> Option<PathBuf> {
+    fs::read_dir(directory)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let release = name.strip_prefix(prefix)?.split('-').next()?.to_string();
+
+            (name.contains(".pkg.tar") && !name.ends_with(".sig")).then(|| (release, entry.path()))
+        })
+        .max_by(|(first, _), (second, _)| compare_versions(first, second))
+        .map(|(_, path)| path)
+}
+
+/// Finds the package file of the pinned version of a package, from
+/// the pacman cache when it's still there or the Arch Linux Archive
+fn pinned_package_file(
+    runner: &dyn CommandRunner,
+    package: &Package,
+    version: &str,
+) -> anyhow::Result<String> {
+    let prefix = format!("{}-{}-", package.name, version);
+    if let Some(cached) = latest_package_file(Path::new(PACMAN_CACHE_DIRECTORY), &prefix) {
+        return Ok(cached.to_string_lossy().to_string());
+    }
+
+    // The archive can only be used knowing the exact release
+    if !version.contains('-') {
+        bail!(
+            "Version {} of {} is not in the pacman cache, to download it from the Arch Linux Archive the version needs its release such as {}-1",
+            version,
+            package,
+            version
+        );
+    }
+
+    let architecture = package_architecture(runner, &package.name)?;
+    let first_character = package
+        .name
+        .chars()
+        .next()
+        .with_context(|| format!("Package name of {} is empty", package))?;
+
+    Ok(format!(
+        "{}/{}/{}/{}-{}-{}.pkg.tar.zst",
+        ARCH_ARCHIVE_URL, first_character, package.name, package.name, version, architecture
+    ))
+}
+
+/// Installs every package pinned to an exact version from its package
+/// file, skipping those which already have the pinned version installed
+fn install_pinned_packages(
+    runner: &dyn CommandRunner,
+    pinned_packages: &Vec<&Package>,
+) -> anyhow::Result<()> {
+    if pinned_packages.is_empty() {
+        return Ok(());
+    }
+
+    let installed = arch::installed_packages(runner, PACMAN_COMMAND_STRING)?;
+
+    for package in pinned_packages {
+        let (Some(version), Some(constraint)) = (package.pinned_version(), &package.version) else {
+            continue;
+        };
+
+        if installed
+            .get(&package.name)
+            .is_some_and(|installed| constraint.matches(&installed.version))
+        {
+            continue;
+        }
+
+        info!(
+            "Installing {} version {} using pacman",
+            package.name, version
+        );
+
+        let package_file = pinned_package_file(runner, package, version)?;
+        let pacman_exit_status = runner
+            .run(&without_prompts(
+                new_command(PACMAN_COMMAND_STRING, &["-U", "--", &package_file]),
+                "--noconfirm",
+            ))
+            .with_context(|| {
+                format!(
+                    "While trying to run pacman to install version {} of {}",
+                    version, package
+                )
+            })?
+            .status;
+
+        if !pacman_exit_status.success() {
+            bail!(
+                "Pacman did not successfully install version {} of {}, returned exit code of {}, aborting",
+                version,
+                package,
+                pacman_exit_status
+            );
+        }
+    }
+
+    Ok(())
+}
+
 /// Install a list of software using pacman
-pub fn install_packages(package_list: &Vec<&Package>) -> anyhow::Result<()> {
+pub fn install_packages(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+    _backend_packages: &Vec<&Package>,
+) -> anyhow::Result<()> {
     info!("Using pacman to install {} packages", package_list.len());
 
-    // Generate pacman command argument
-    let command = format!(
-        "{} -Syu --needed {}",
-        PACMAN_COMMAND_STRING,
-        format_package_list_for_shell(package_list)
+    // Pinned packages are installed from their package file
+    // afterwards, and must not be upgraded along with the system
+    let (pinned_packages, packages): (Vec<&Package>, Vec<&Package>) = package_list
+        .iter()
+        .copied()
+        .partition(|package| package.pinned_version().is_some());
+
+    // Generate pacman command arguments
+    let mut command = without_prompts(
+        new_command(PACMAN_COMMAND_STRING, &["-Syu", "--needed"]),
+        "--noconfirm",
     );
+    if !pinned_packages.is_empty() {
+        command.extend([
+            "--ignore".to_string(),
+            package_names(&pinned_packages).join(","),
+        ]);
+    }
+    command.push("--".to_string());
+    command.extend(package_names(&packages));
 
-    let mut output = new_shell_command().arg(command).spawn().with_context(|| {
-        format!(
-            "While trying to spawn PACMAN instance to install {} packages using pacman",
-            package_list.len()
-        )
-    })?;
-
-    // Wait for pacman to finish
-    let pacman_exit_status = output.wait().with_context(|| {
-        format!(
-            "While trying to wait for pacman to install {} packages using pacman",
-            package_list.len()
-        )
-    })?;
+    // Run and wait for pacman to finish
+    let pacman_exit_status = runner
+        .run(&command)
+        .with_context(|| {
+            format!(
+                "While trying to run pacman to install {} packages using pacman",
+                package_list.len()
+            )
+        })?
+        .status;
 
     // Ensure output status is 0 for good :D
     if !pacman_exit_status.success() {
@@ -44,6 +220,8 @@ pub fn install_packages(package_list: &Vec<&Package>) -> anyhow::Result<()> {
         );
     }
 
+    install_pinned_packages(runner, &pinned_packages)?;
+
     info!(
         "Pacman completed installation, with exit code {}, proceeding",
         pacman_exit_status
@@ -53,35 +231,47 @@ pub fn install_packages(package_list: &Vec<&Package>) -> anyhow::Result<()> {
 }
 
 /// Remove all non-package list software using pacman
-pub fn remove_non_packages(package_list: &Vec<&Package>) -> anyhow::Result<()> {
+pub fn remove_non_packages(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+    plan: &BackendPlan,
+) -> anyhow::Result<()> {
     info!(
         "Using pacman to ensure only {} packages are installed",
         package_list.len()
     );
 
-    // Step 1, get all software installed on the system
-    let command = format!("{} -Qq", PACMAN_COMMAND_STRING);
-    let output = new_shell_command().arg(command).output().with_context(|| {
-        format!("While trying to spawn pacman to get all packages installed on system")
-    })?;
+    // Every install reason is rewritten while priming for removal,
+    // so they are put back if the removal doesn't complete
+    PACMAN_INSTALL_REASONS.preserve_during(runner, |_| mark_and_remove(runner, package_list, plan))
+}
 
-    // Remove all new lines from output
-    let all_packages = String::from_utf8(output.stdout)
-        .with_context(|| format!("While trying to convert pacman all packages output to string"))?
-        .replace('\n', " ");
+/// Marks only the package list as explicitly installed and
+/// removes every package the plan removes using pacman
+fn mark_and_remove(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+    plan: &BackendPlan,
+) -> anyhow::Result<()> {
+    // Step 1, get all software installed on the system
+    let all_packages = runner
+        .query_stdout(&new_command(PACMAN_COMMAND_STRING, &["-Qq"]))
+        .with_context(|| {
+            format!("While trying to run pacman to get all packages installed on system")
+        })?;
 
     info!("Marking all packages as installed as dependency to prime for removal",);
 
     // Step 2, mark as installed as dependency
-    let command = format!("{} -D --asdeps {}", PACMAN_COMMAND_STRING, all_packages);
-    let mut output = new_shell_command().arg(command).spawn().with_context(|| {
-        format!("While trying to spawn pacman to mark packages installed on system as deps")
-    })?;
+    let mut command = new_command(PACMAN_COMMAND_STRING, &["-D", "--asdeps", "--"]);
+    command.extend(all_packages.split_whitespace().map(String::from));
 
-    // Wait for command to complete.
-    let pacman_exit_status = output.wait().with_context(|| {
-        format!("While trying to wait for pacman to mark packages installed on system as deps")
-    })?;
+    let pacman_exit_status = runner
+        .run(&command)
+        .with_context(|| {
+            format!("While trying to run pacman to mark packages installed on system as deps")
+        })?
+        .status;
 
     // Ensure exit status was okay
     if !pacman_exit_status.success() {
@@ -91,29 +281,30 @@ pub fn remove_non_packages(package_list: &Vec<&Package>) -> anyhow::Result<()> {
         );
     }
 
+    // Protected packages are kept even when they aren't in the package list
+    let protected = installed_protected(
+        SourceBackend::ArchLocalDatabase,
+        all_packages.split_whitespace(),
+    );
+
     info!(
         "Marking {} packages as explicitly installed to keep them on system using pacman",
-        package_list.len()
+        package_list.len() + protected.len()
     );
 
-    // Step 3, mark all the package list packages
-    let command = format!(
-        "{} -D --asexplicit {}",
-        PACMAN_COMMAND_STRING,
-        format_package_list_for_shell(package_list)
-    );
-    let mut output = new_shell_command().arg(command).spawn().with_context(|| {
-        format!(
-            "While trying to spawn pacman to mark packages in blueprint as explicitly installed."
-        )
-    })?;
-
-    // Wait for command to complete.
-    let pacman_exit_status = output.wait().with_context(|| {
-        format!(
-            "While trying to spawn pacman to mark packages in blueprint as explicitly installed."
-        )
-    })?;
+    // Step 3, mark all the package list and protected packages
+    let mut command = new_command(PACMAN_COMMAND_STRING, &["-D", "--asexplicit", "--"]);
+    command.extend(package_names(package_list));
+    command.extend(protected);
+
+    let pacman_exit_status = runner
+        .run(&command)
+        .with_context(|| {
+            format!(
+                "While trying to run pacman to mark packages in blueprint as explicitly installed."
+            )
+        })?
+        .status;
 
     // Ensure exit status was okay
     if !pacman_exit_status.success() {
@@ -123,48 +314,46 @@ pub fn remove_non_packages(package_list: &Vec<&Package>) -> anyhow::Result<()> {
         );
     }
 
-    info!("Getting all orphaned packages");
-
-    // Step 4, Get all orphaned packages
-    let command = format!("{} -Qtdq", PACMAN_COMMAND_STRING);
-    let output = new_shell_command().arg(command).output().with_context(|| {
-        format!("While trying to spawn pacman to get all orphaned packages installed on system")
-    })?;
-
-    // Remove all new lines from output
-    let orphaned_packages = String::from_utf8(output.stdout)
-        .with_context(|| {
-            format!("While trying to convert pacman all orphaned packages output to string")
-        })?
-        .replace('\n', " ");
+    // Step 4, remove exactly what the plan removes, as confirmed by the
+    // user, rather than whatever pacman would consider orphaned now
+    let installed: Vec<&str> = all_packages.split_whitespace().collect();
+    let remove = without_protected(
+        SourceBackend::ArchLocalDatabase,
+        plan.remove
+            .iter()
+            .filter(|name| installed.contains(&name.as_str()))
+            .cloned()
+            .collect(),
+    );
 
-    // If there are no orphaned packages, we dont need to continue
-    if orphaned_packages.is_empty() {
-        info!("No orphaned packages found from pacman, nothing needed to be removed, returning");
+    // If there is nothing to remove, we dont need to continue
+    if remove.is_empty() {
+        info!("No unrequired packages found from pacman, nothing needed to be removed, returning");
         return Ok(());
     }
 
     info!(
-        "Removing orphaned packages from pacman: {}",
-        orphaned_packages
+        "Removing unrequired packages from pacman: {}",
+        remove.join(" ")
     );
 
-    // Step 5, Remove all orphaned packages
-    let command = format!("{} -Rns {}", PACMAN_COMMAND_STRING, orphaned_packages);
-    let mut output = new_shell_command()
-        .arg(command)
-        .spawn()
-        .with_context(|| format!("While trying to spawn pacman to remove orphaned packages"))?;
+    // Step 5, Remove every unrequired package, their dependencies which
+    // are no longer required are in the plan already
+    let mut command = without_prompts(
+        new_command(PACMAN_COMMAND_STRING, &["-Rn", "--"]),
+        "--noconfirm",
+    );
+    command.extend(remove);
 
-    // Wait for command to complete
-    let pacman_exit_status = output
-        .wait()
-        .with_context(|| format!("While trying to wait for pacman to remove orphaned packages"))?;
+    let pacman_exit_status = runner
+        .run(&command)
+        .context("While trying to run pacman to remove unrequired packages")?
+        .status;
 
     // Ensure exit status was okay
     if !pacman_exit_status.success() {
         bail!(
-            "Pacman did not successfully remove orphaned packages, returned exit code of {}, aborting",
+            "Pacman did not successfully remove unrequired packages, returned exit code of {}, aborting",
             pacman_exit_status
         );
     }
@@ -173,3 +362,209 @@ pub fn remove_non_packages(package_list: &Vec<&Package>) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Works out what installing and removing the package
+/// list using pacman would change, without changing anything
+pub fn plan_packages(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+) -> anyhow::Result<BackendPlan> {
+    arch::plan_packages(runner, PACMAN_COMMAND_STRING, package_list)
+}
+
+/// Gets the exact installed version of every package in the package
+/// list along with the repository it came from using pacman
+pub fn installed_versions(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+) -> anyhow::Result<Vec<InstalledVersion>> {
+    arch::installed_versions(runner, PACMAN_COMMAND_STRING, package_list)
+}
+
+/// Gets all of the native packages from the sync repositories,
+/// which have been explicitly installed on the system
+pub fn explicit_packages(runner: &dyn CommandRunner) -> anyhow::Result<Vec<String>> {
+    // pacman exits unsuccessfully when there are none so ignore the status
+    Ok(runner
+        .query(&new_command(PACMAN_COMMAND_STRING, &["-Qqen"]))
+        .with_context(|| {
+            format!("While trying to run pacman to get explicitly installed packages")
+        })?
+        .stdout
+        .split_whitespace()
+        .map(String::from)
+        .collect())
+}
+
+/// Gets every package installed in the local pacman database
+pub fn inventory_packages(runner: &dyn CommandRunner) -> anyhow::Result<Vec<InventoryPackage>> {
+    Ok(arch::installed_packages(runner, PACMAN_COMMAND_STRING)?.inventory())
+}
+
+/// Brings the local pacman database back to an earlier snapshot, packages
+/// are reinstalled at their earlier version from the pacman cache where
+/// possible, falling back to the Arch Linux Archive
+pub fn rollback_packages(
+    runner: &dyn CommandRunner,
+    rollback: &BackendRollback,
+) -> anyhow::Result<()> {
+    info!(
+        "Using pacman to reinstall {} packages and remove {} packages",
+        rollback.install.len(),
+        rollback.remove.len()
+    );
+
+    // Step 1, reinstall every package at its earlier version
+    let pinned_packages: Vec<Package> = rollback
+        .install
+        .iter()
+        .map(|(name, version)| Package {
+            version: Some(VersionConstraint::pinned(version.clone())),
+            ..Package::new(name.clone(), PackageSource::ArchPacman)
+        })
+        .collect();
+    install_pinned_packages(runner, &pinned_packages.iter().collect())?;
+
+    // Step 2, remove every package which wasn't installed before
+    let remove = without_protected(SourceBackend::ArchLocalDatabase, rollback.remove.clone());
+    if !remove.is_empty() {
+        let mut command = without_prompts(
+            new_command(PACMAN_COMMAND_STRING, &["-R", "--"]),
+            "--noconfirm",
+        );
+        command.extend(remove);
+
+        let pacman_exit_status = runner
+            .run(&command)
+            .context("While trying to run pacman to remove packages")?
+            .status;
+
+        if !pacman_exit_status.success() {
+            bail!(
+                "Pacman did not successfully remove packages, returned exit code of {}, aborting",
+                pacman_exit_status
+            );
+        }
+    }
+
+    // Step 3, put the install reasons the generation changed back
+    PACMAN_INSTALL_REASONS.set(runner, &rollback.mark_explicit, &rollback.mark_dependency)?;
+
+    info!("Pacman completed rolling back, proceeding");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::ROOT_CONFIG,
+        runner::{RecordingRunner, argv},
+    };
+
+    #[test]
+    fn install_runs_pacman_with_every_package() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        let packages = [
+            Package::new("firefox".to_string(), PackageSource::ArchPacman),
+            Package::new("git".to_string(), PackageSource::ArchPacman),
+        ];
+
+        install_packages(&runner, &packages.iter().collect(), &Vec::new()).unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec![argv(&[
+                "pacman",
+                "-Syu",
+                "--needed",
+                "--noconfirm",
+                "--",
+                "firefox",
+                "git"
+            ])]
+        );
+    }
+
+    #[test]
+    fn latest_cached_release_is_compared_as_a_version() {
+        let directory =
+            std::env::temp_dir().join(format!("blueprint-test-{}-cache", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        for name in [
+            "foo-1.0-9-x86_64.pkg.tar.zst",
+            "foo-1.0-10-x86_64.pkg.tar.zst",
+            "foo-1.0-11-x86_64.pkg.tar.zst.sig",
+            "foo-1.0.1-1-x86_64.pkg.tar.zst",
+        ] {
+            fs::write(directory.join(name), "").unwrap();
+        }
+
+        assert_eq!(
+            latest_package_file(&directory, "foo-1.0-"),
+            Some(directory.join("foo-1.0-10-x86_64.pkg.tar.zst"))
+        );
+        assert_eq!(latest_package_file(&directory, "foo-2.0-"), None);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn removal_removes_exactly_the_plan() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        runner.push_output(0, "firefox\nlonely\n");
+        runner.push_output(0, "base\nfirefox\nlonely\nold\n");
+
+        let packages = [Package::new(
+            "firefox".to_string(),
+            PackageSource::ArchPacman,
+        )];
+        let mut plan = BackendPlan::new(SourceBackend::ArchLocalDatabase);
+        plan.remove = argv(&["base", "gone", "lonely", "old"]);
+
+        remove_non_packages(&runner, &packages.iter().collect(), &plan).unwrap();
+
+        // Protected and no longer installed packages are never removed
+        assert_eq!(
+            runner.commands(),
+            vec![
+                argv(&["pacman", "-Qqe"]),
+                argv(&["pacman", "-Qq"]),
+                argv(&[
+                    "pacman", "-D", "--asdeps", "--", "base", "firefox", "lonely", "old"
+                ]),
+                argv(&["pacman", "-D", "--asexplicit", "--", "firefox", "base"]),
+                argv(&["pacman", "-Rn", "--noconfirm", "--", "lonely", "old"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_removal_restores_install_reasons() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        runner.push_output(0, "firefox\nlonely\n");
+        runner.push_output(0, "firefox\nlonely\n");
+        runner.push_output(0, "");
+        runner.push_output(0, "");
+        runner.push_output(1, "");
+        runner.push_output(0, "firefox\n");
+
+        let packages = [Package::new(
+            "firefox".to_string(),
+            PackageSource::ArchPacman,
+        )];
+        let mut plan = BackendPlan::new(SourceBackend::ArchLocalDatabase);
+        plan.remove = argv(&["lonely"]);
+
+        assert!(remove_non_packages(&runner, &packages.iter().collect(), &plan).is_err());
+        assert_eq!(
+            runner.commands().last(),
+            Some(&argv(&["pacman", "-D", "--asexplicit", "--", "lonely"]))
+        );
+    }
+}
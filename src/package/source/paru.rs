@@ -3,38 +3,88 @@
 use anyhow::{Context, bail};
 use log::info;
 
-use crate::package::{
-    package::Package,
-    source::shell::{format_package_list_for_shell, new_shell_command},
+use crate::{
+    config::ROOT_CONFIG,
+    package::{
+        lock::InstalledVersion,
+        package::Package,
+        plan::{BackendPlan, installed_protected, without_protected},
+        source::{
+            arch,
+            command::{new_command, package_names, without_prompts},
+            reasons::InstallReasonCommands,
+        },
+        sources::SourceBackend,
+    },
+    runner::CommandRunner,
 };
 
 const PARU_COMMAND_STRING: &'static str = "paru";
 
-/// Install a list of software using paru
-pub fn install_packages(package_list: &Vec<&Package>) -> anyhow::Result<()> {
+/// Commands paru uses to snapshot and restore install reasons, marking
+/// runs pacman through paru so it is given the privilege command as well
+const PARU_INSTALL_REASONS: InstallReasonCommands = InstallReasonCommands {
+    name: PARU_COMMAND_STRING,
+    query_explicit: &[PARU_COMMAND_STRING, "-Qqe"],
+    new_mark_command: new_paru_command,
+    mark_explicit: &["-D", "--asexplicit", "--"],
+    mark_dependency: &["-D", "--asdeps", "--"],
+};
+
+/// Creates a new paru command which changes the system, paru elevates
+/// itself for pacman so is told to use the configured privilege command
+fn new_paru_command(arguments: &[&str]) -> Vec<String> {
+    let mut command = new_command(PARU_COMMAND_STRING, &[]);
+
+    if let Some(privilege_command) = ROOT_CONFIG.get_config().privilege_command {
+        command.extend([
+            "--sudo".to_string(),
+            privilege_command.program().to_string(),
+        ]);
+    }
+
+    command.extend(arguments.iter().map(|argument| argument.to_string()));
+    command
+}
+
+/// Install a list of software using paru, leaving the packages
+/// pinned through pacman out of the system upgrade
+pub fn install_packages(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+    backend_packages: &Vec<&Package>,
+) -> anyhow::Result<()> {
     info!("Using paru to install {} packages", package_list.len());
 
-    // Generate paru command argument
-    let command = format!(
-        "{} -Syu --needed {}",
-        PARU_COMMAND_STRING,
-        format_package_list_for_shell(package_list)
-    );
+    // Pinned packages are installed from their package file by
+    // pacman afterwards, and must not be upgraded along with the system
+    let pinned_packages: Vec<&Package> = backend_packages
+        .iter()
+        .copied()
+        .filter(|package| package.pinned_version().is_some())
+        .collect();
 
-    let mut output = new_shell_command().arg(command).spawn().with_context(|| {
-        format!(
-            "While trying to spawn PARU instance to install {} packages using paru",
-            package_list.len()
-        )
-    })?;
+    // Generate paru command arguments
+    let mut command = without_prompts(new_paru_command(&["-Syu", "--needed"]), "--noconfirm");
+    if !pinned_packages.is_empty() {
+        command.extend([
+            "--ignore".to_string(),
+            package_names(&pinned_packages).join(","),
+        ]);
+    }
+    command.push("--".to_string());
+    command.extend(package_names(package_list));
 
-    // Wait for paru to finish
-    let paru_exit_status = output.wait().with_context(|| {
-        format!(
-            "While trying to wait for paru to install {} packages using paru",
-            package_list.len()
-        )
-    })?;
+    // Run and wait for paru to finish
+    let paru_exit_status = runner
+        .run(&command)
+        .with_context(|| {
+            format!(
+                "While trying to run paru to install {} packages using paru",
+                package_list.len()
+            )
+        })?
+        .status;
 
     // Ensure output status is 0 for good :D
     if !paru_exit_status.success() {
@@ -53,35 +103,47 @@ pub fn install_packages(package_list: &Vec<&Package>) -> anyhow::Result<()> {
 }
 
 /// Remove all non-package list software using paru
-pub fn remove_non_packages(package_list: &Vec<&Package>) -> anyhow::Result<()> {
+pub fn remove_non_packages(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+    plan: &BackendPlan,
+) -> anyhow::Result<()> {
     info!(
         "Using paru to ensure only {} packages are installed",
         package_list.len()
     );
 
-    // Step 1, get all software installed on the system
-    let command = format!("{} -Qq", PARU_COMMAND_STRING);
-    let output = new_shell_command().arg(command).output().with_context(|| {
-        format!("While trying to spawn paru to get all packages installed on system")
-    })?;
+    // Every install reason is rewritten while priming for removal,
+    // so they are put back if the removal doesn't complete
+    PARU_INSTALL_REASONS.preserve_during(runner, |_| mark_and_remove(runner, package_list, plan))
+}
 
-    // Remove all new lines from output
-    let all_packages = String::from_utf8(output.stdout)
-        .with_context(|| format!("While trying to convert paru all packages output to string"))?
-        .replace('\n', " ");
+/// Marks only the package list as explicitly installed and
+/// removes every package the plan removes using paru
+fn mark_and_remove(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+    plan: &BackendPlan,
+) -> anyhow::Result<()> {
+    // Step 1, get all software installed on the system
+    let all_packages = runner
+        .query_stdout(&new_command(PARU_COMMAND_STRING, &["-Qq"]))
+        .with_context(|| {
+            format!("While trying to run paru to get all packages installed on system")
+        })?;
 
     info!("Marking all packages as installed as dependency to prime for removal",);
 
     // Step 2, mark as installed as dependency
-    let command = format!("{} -D --asdeps {}", PARU_COMMAND_STRING, all_packages);
-    let mut output = new_shell_command().arg(command).spawn().with_context(|| {
-        format!("While trying to spawn paru to mark packages installed on system as deps")
-    })?;
+    let mut command = new_paru_command(&["-D", "--asdeps", "--"]);
+    command.extend(all_packages.split_whitespace().map(String::from));
 
-    // Wait for command to complete.
-    let paru_exit_status = output.wait().with_context(|| {
-        format!("While trying to wait for paru to mark packages installed on system as deps")
-    })?;
+    let paru_exit_status = runner
+        .run(&command)
+        .with_context(|| {
+            format!("While trying to run paru to mark packages installed on system as deps")
+        })?
+        .status;
 
     // Ensure exit status was okay
     if !paru_exit_status.success() {
@@ -91,25 +153,30 @@ pub fn remove_non_packages(package_list: &Vec<&Package>) -> anyhow::Result<()> {
         );
     }
 
+    // Protected packages are kept even when they aren't in the package list
+    let protected = installed_protected(
+        SourceBackend::ArchLocalDatabase,
+        all_packages.split_whitespace(),
+    );
+
     info!(
         "Marking {} packages as explicitly installed to keep them on system using paru",
-        package_list.len()
+        package_list.len() + protected.len()
     );
 
-    // Step 3, mark all the package list packages
-    let command = format!(
-        "{} -D --asexplicit {}",
-        PARU_COMMAND_STRING,
-        format_package_list_for_shell(package_list)
-    );
-    let mut output = new_shell_command().arg(command).spawn().with_context(|| {
-        format!("While trying to spawn paru to mark packages in blueprint as explicitly installed.")
-    })?;
+    // Step 3, mark all the package list and protected packages
+    let mut command = new_paru_command(&["-D", "--asexplicit", "--"]);
+    command.extend(package_names(package_list));
+    command.extend(protected);
 
-    // Wait for command to complete.
-    let paru_exit_status = output.wait().with_context(|| {
-        format!("While trying to spawn paru to mark packages in blueprint as explicitly installed.")
-    })?;
+    let paru_exit_status = runner
+        .run(&command)
+        .with_context(|| {
+            format!(
+                "While trying to run paru to mark packages in blueprint as explicitly installed."
+            )
+        })?
+        .status;
 
     // Ensure exit status was okay
     if !paru_exit_status.success() {
@@ -119,48 +186,43 @@ pub fn remove_non_packages(package_list: &Vec<&Package>) -> anyhow::Result<()> {
         );
     }
 
-    info!("Getting all orphaned packages");
-
-    // Step 4, Get all orphaned packages
-    let command = format!("{} -Qtdq", PARU_COMMAND_STRING);
-    let output = new_shell_command().arg(command).output().with_context(|| {
-        format!("While trying to spawn paru to get all orphaned packages installed on system")
-    })?;
-
-    // Remove all new lines from output
-    let orphaned_packages = String::from_utf8(output.stdout)
-        .with_context(|| {
-            format!("While trying to convert paru all orphaned packages output to string")
-        })?
-        .replace('\n', " ");
+    // Step 4, remove exactly what the plan removes, as confirmed by the
+    // user, rather than whatever paru would consider orphaned now
+    let installed: Vec<&str> = all_packages.split_whitespace().collect();
+    let remove = without_protected(
+        SourceBackend::ArchLocalDatabase,
+        plan.remove
+            .iter()
+            .filter(|name| installed.contains(&name.as_str()))
+            .cloned()
+            .collect(),
+    );
 
-    // If there are no orphaned packages, we dont need to continue
-    if orphaned_packages.is_empty() {
-        info!("No orphaned packages found from paru, nothing needed to be removed, returning");
+    // If there is nothing to remove, we dont need to continue
+    if remove.is_empty() {
+        info!("No unrequired packages found from paru, nothing needed to be removed, returning");
         return Ok(());
     }
 
     info!(
-        "Removing orphaned packages from paru: {}",
-        orphaned_packages
+        "Removing unrequired packages from paru: {}",
+        remove.join(" ")
     );
 
-    // Step 5, Remove all orphaned packages
-    let command = format!("{} -Rns {}", PARU_COMMAND_STRING, orphaned_packages);
-    let mut output = new_shell_command()
-        .arg(command)
-        .spawn()
-        .with_context(|| format!("While trying to spawn paru to remove orphaned packages"))?;
+    // Step 5, Remove every unrequired package, their dependencies which
+    // are no longer required are in the plan already
+    let mut command = without_prompts(new_paru_command(&["-Rn", "--"]), "--noconfirm");
+    command.extend(remove);
 
-    // Wait for command to complete
-    let paru_exit_status = output
-        .wait()
-        .with_context(|| format!("While trying to wait for paru to remove orphaned packages"))?;
+    let paru_exit_status = runner
+        .run(&command)
+        .context("While trying to run paru to remove unrequired packages")?
+        .status;
 
     // Ensure exit status was okay
     if !paru_exit_status.success() {
         bail!(
-            "Paru did not successfully remove orphaned packages, returned exit code of {}, aborting",
+            "Paru did not successfully remove unrequired packages, returned exit code of {}, aborting",
             paru_exit_status
         );
     }
@@ -169,3 +231,185 @@ pub fn remove_non_packages(package_list: &Vec<&Package>) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Works out what installing and removing the package
+/// list using paru would change, without changing anything
+pub fn plan_packages(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+) -> anyhow::Result<BackendPlan> {
+    arch::plan_packages(runner, PARU_COMMAND_STRING, package_list)
+}
+
+/// Gets the exact installed version of every package in the package
+/// list along with the repository it came from using paru
+pub fn installed_versions(
+    runner: &dyn CommandRunner,
+    package_list: &Vec<&Package>,
+) -> anyhow::Result<Vec<InstalledVersion>> {
+    arch::installed_versions(runner, PARU_COMMAND_STRING, package_list)
+}
+
+/// Gets all of the foreign packages, such as from the AUR,
+/// which have been explicitly installed on the system
+pub fn explicit_packages(runner: &dyn CommandRunner) -> anyhow::Result<Vec<String>> {
+    // paru exits unsuccessfully when there are none so ignore the status
+    Ok(runner
+        .query(&new_command(PARU_COMMAND_STRING, &["-Qqem"]))
+        .with_context(|| format!("While trying to run paru to get explicitly installed packages"))?
+        .stdout
+        .split_whitespace()
+        .map(String::from)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::ROOT_CONFIG,
+        package::{sources::PackageSource, version::VersionConstraint},
+        runner::{RecordingRunner, argv},
+    };
+
+    #[test]
+    fn install_elevates_through_the_privilege_command() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        let packages = [Package::new(
+            "paru-bin".to_string(),
+            PackageSource::ArchParu,
+        )];
+
+        install_packages(&runner, &packages.iter().collect(), &Vec::new()).unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec![argv(&[
+                "paru",
+                "--sudo",
+                "sudo",
+                "-Syu",
+                "--needed",
+                "--noconfirm",
+                "--",
+                "paru-bin"
+            ])]
+        );
+    }
+
+    #[test]
+    fn install_ignores_packages_pinned_through_pacman() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        let packages = [
+            Package::new("paru-bin".to_string(), PackageSource::ArchParu),
+            Package {
+                version: Some(VersionConstraint::pinned("6.9.7-1".to_string())),
+                ..Package::new("linux".to_string(), PackageSource::ArchPacman)
+            },
+            Package::new("git".to_string(), PackageSource::ArchPacman),
+        ];
+
+        install_packages(
+            &runner,
+            &packages[..1].iter().collect(),
+            &packages.iter().collect(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec![argv(&[
+                "paru",
+                "--sudo",
+                "sudo",
+                "-Syu",
+                "--needed",
+                "--noconfirm",
+                "--ignore",
+                "linux",
+                "--",
+                "paru-bin"
+            ])]
+        );
+    }
+
+    #[test]
+    fn removal_removes_exactly_the_plan() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        runner.push_output(0, "paru-bin\n");
+        runner.push_output(0, "paru-bin\nold-aur\nsudo\n");
+
+        let packages = [Package::new(
+            "paru-bin".to_string(),
+            PackageSource::ArchParu,
+        )];
+        let mut plan = BackendPlan::new(SourceBackend::ArchLocalDatabase);
+        plan.remove = argv(&["old-aur"]);
+
+        remove_non_packages(&runner, &packages.iter().collect(), &plan).unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            vec![
+                argv(&["paru", "-Qqe"]),
+                argv(&["paru", "-Qq"]),
+                argv(&[
+                    "paru", "--sudo", "sudo", "-D", "--asdeps", "--", "paru-bin", "old-aur", "sudo"
+                ]),
+                argv(&[
+                    "paru",
+                    "--sudo",
+                    "sudo",
+                    "-D",
+                    "--asexplicit",
+                    "--",
+                    "paru-bin",
+                    "sudo"
+                ]),
+                argv(&[
+                    "paru",
+                    "--sudo",
+                    "sudo",
+                    "-Rn",
+                    "--noconfirm",
+                    "--",
+                    "old-aur"
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_removal_restores_install_reasons_through_the_privilege_command() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        runner.push_output(0, "paru-bin\nold-aur\n");
+        runner.push_output(0, "paru-bin\nold-aur\n");
+        runner.push_output(1, "");
+        runner.push_output(0, "");
+
+        let packages = [Package::new(
+            "paru-bin".to_string(),
+            PackageSource::ArchParu,
+        )];
+        let plan = BackendPlan::new(SourceBackend::ArchLocalDatabase);
+
+        assert!(remove_non_packages(&runner, &packages.iter().collect(), &plan).is_err());
+        assert_eq!(
+            runner.commands().last(),
+            Some(&argv(&[
+                "paru",
+                "--sudo",
+                "sudo",
+                "-D",
+                "--asexplicit",
+                "--",
+                "old-aur",
+                "paru-bin"
+            ]))
+        );
+    }
+}
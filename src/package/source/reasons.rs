@@ -0,0 +1,153 @@
+//! Snapshots of the explicitly installed packages of a backend, used to put
+//! the install reasons back when removing unrequired software doesn't complete
+//! and when rolling back to an earlier generation
+
+use std::collections::BTreeSet;
+
+use anyhow::{Context, bail};
+use log::{error, info, warn};
+
+use crate::{package::source::command::new_command, runner::CommandRunner};
+
+/// Commands a source uses to query and change install reasons
+pub struct InstallReasonCommands<'a> {
+    // Name of the source for logging
+    pub name: &'a str,
+
+    // Query listing the name of every package explicitly installed
+    pub query_explicit: &'a [&'a str],
+
+    // Creates a command changing install reasons from its arguments,
+    // sources elevating themselves are given their privilege option here
+    pub new_mark_command: fn(arguments: &[&str]) -> Vec<String>,
+
+    // Arguments marking the packages appended to them as explicitly installed
+    pub mark_explicit: &'a [&'a str],
+
+    // Arguments marking the packages appended to them as installed as a dependency
+    pub mark_dependency: &'a [&'a str],
+}
+
+impl InstallReasonCommands<'_> {
+    /// Gets every package currently explicitly installed, the status is
+    /// ignored as some sources exit unsuccessfully when there are none
+    fn explicit_packages(&self, runner: &dyn CommandRunner) -> anyhow::Result<BTreeSet<String>> {
+        Ok(runner
+            .query(&new_command(
+                self.query_explicit[0],
+                &self.query_explicit[1..],
+            ))
+            .with_context(|| {
+                format!(
+                    "While trying to run {} to get explicitly installed packages",
+                    self.name
+                )
+            })?
+            .stdout
+            .split_whitespace()
+            .map(String::from)
+            .collect())
+    }
+
+    /// Runs a command marking the packages with an install reason
+    fn mark(
+        &self,
+        runner: &dyn CommandRunner,
+        mark_arguments: &[&str],
+        packages: &[String],
+    ) -> anyhow::Result<()> {
+        if packages.is_empty() {
+            return Ok(());
+        }
+
+        let mut command = (self.new_mark_command)(mark_arguments);
+        command.extend(packages.iter().cloned());
+
+        let exit_status = runner
+            .run(&command)
+            .with_context(|| {
+                format!(
+                    "While trying to run {} to restore install reasons",
+                    self.name
+                )
+            })?
+            .status;
+
+        if !exit_status.success() {
+            bail!(
+                "{} did not successfully restore install reasons, returned exit code of {}",
+                self.name,
+                exit_status
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Puts the install reasons back to the snapshot of explicitly
+    /// installed packages, returning the packages which were changed
+    fn restore(
+        &self,
+        runner: &dyn CommandRunner,
+        snapshot: &BTreeSet<String>,
+    ) -> anyhow::Result<Vec<String>> {
+        let current = self.explicit_packages(runner)?;
+
+        let to_explicit: Vec<String> = snapshot.difference(&current).cloned().collect();
+        let to_dependency: Vec<String> = current.difference(snapshot).cloned().collect();
+
+        self.mark(runner, self.mark_explicit, &to_explicit)?;
+        self.mark(runner, self.mark_dependency, &to_dependency)?;
+
+        Ok(to_explicit.into_iter().chain(to_dependency).collect())
+    }
+
+    /// Marks the packages with the install reasons they should have
+    pub fn set(
+        &self,
+        runner: &dyn CommandRunner,
+        explicit: &[String],
+        dependency: &[String],
+    ) -> anyhow::Result<()> {
+        self.mark(runner, self.mark_explicit, explicit)?;
+        self.mark(runner, self.mark_dependency, dependency)
+    }
+
+    /// Snapshots the explicitly installed packages and then runs the
+    /// removal, which is given the snapshot. If the removal doesn't complete
+    /// the install reasons are restored to the snapshot before erroring.
+    pub fn preserve_during(
+        &self,
+        runner: &dyn CommandRunner,
+        removal: impl FnOnce(&BTreeSet<String>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let snapshot = self.explicit_packages(runner)?;
+
+        let removal_error = match removal(&snapshot) {
+            Ok(()) => return Ok(()),
+            Err(removal_error) => removal_error,
+        };
+
+        warn!(
+            "Removal using {} did not complete, restoring the original install reasons",
+            self.name
+        );
+
+        match self.restore(runner, &snapshot) {
+            Ok(restored) if restored.is_empty() => {
+                info!("No install reasons had been changed, nothing was restored");
+            }
+            Ok(restored) => info!(
+                "Restored the original install reasons of {} packages: {}",
+                restored.len(),
+                restored.join(" ")
+            ),
+            Err(restore_error) => error!(
+                "Could not restore the original install reasons using {}: {:?}",
+                self.name, restore_error
+            ),
+        }
+
+        Err(removal_error)
+    }
+}
@@ -3,18 +3,28 @@
 
 use std::fmt::Display;
 
-use inquire::Confirm;
-use serde::Deserialize;
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     config::ROOT_CONFIG,
     package::{
+        history::{BackendRollback, InventoryPackage},
+        lock::InstalledVersion,
         package::Package,
-        source::{pacman, paru},
+        plan::BackendPlan,
+        source::{apt, cargo, dnf, flatpak, pacman, paru},
     },
+    prompt::confirm,
+    runner::{CommandRunner, PrivilegedRunner, is_root},
 };
 
-#[derive(Deserialize, Debug, PartialEq, Copy, Clone, Hash, Eq, PartialOrd, Ord)]
+/// Where packages are installed from on the system.
+///
+/// Sources sharing a backend are ordered so the source capable
+/// of handling every package in that backend comes first, it is
+/// the one used for operations across the whole backend.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Copy, Clone, Hash, Eq, PartialOrd, Ord)]
 pub enum PackageSource {
     // Use the Arch Linux "Paru" package manager as the source
     // for these packages
@@ -25,6 +35,184 @@ pub enum PackageSource {
     // for these packages
     #[serde(rename = "archlinux-pacman")]
     ArchPacman,
+
+    // Use the Debian "Apt" package manager as the source
+    // for these packages
+    #[serde(rename = "debian-apt")]
+    DebianApt,
+
+    // Use the Fedora "Dnf" package manager as the source
+    // for these packages
+    #[serde(rename = "fedora-dnf")]
+    FedoraDnf,
+
+    // Use "Flatpak" as the source for these
+    // packages, which are desktop applications
+    #[serde(rename = "flatpak")]
+    Flatpak,
+
+    // Use "cargo install" as the source for these
+    // packages, which are rust binary crates
+    #[serde(rename = "cargo")]
+    Cargo,
+}
+
+/// Backends which package sources install packages into, sources
+/// sharing a backend share one database of installed packages so
+/// removal has to consider the packages of all of them at once.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Copy, Clone, Hash, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourceBackend {
+    // The local pacman database on Arch Linux, shared
+    // by pacman and the AUR helpers wrapping it
+    ArchLocalDatabase,
+
+    // The dpkg database on Debian based systems
+    DebianDpkgDatabase,
+
+    // The rpm database on Fedora/RHEL based systems
+    FedoraRpmDatabase,
+
+    // The user and system flatpak installations
+    FlatpakInstallations,
+
+    // The crates installed into cargo's install root
+    CargoInstallRoot,
+}
+
+impl Display for SourceBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SourceBackend::ArchLocalDatabase => write!(f, "Arch-Linux local pacman database"),
+            SourceBackend::DebianDpkgDatabase => write!(f, "Debian dpkg database"),
+            SourceBackend::FedoraRpmDatabase => write!(f, "Fedora rpm database"),
+            SourceBackend::FlatpakInstallations => write!(f, "Flatpak installations"),
+            SourceBackend::CargoInstallRoot => write!(f, "Cargo install root"),
+        }
+    }
+}
+
+/// Privileges the commands of a package source which change
+/// the system need, its queries always run as the invoking user
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Privilege {
+    // Has to run as root, elevated through the
+    // privilege command when blueprint isn't root
+    Elevated,
+
+    // Has to run as the invoking user,
+    // elevating itself where it needs to
+    User,
+
+    // Can run as root or as the invoking user
+    Any,
+}
+
+impl SourceBackend {
+    /// Packages which removal must never touch by default,
+    /// as removing them would leave the system unusable
+    pub fn default_protected(&self) -> &'static [&'static str] {
+        match self {
+            SourceBackend::ArchLocalDatabase => &[
+                "base",
+                "filesystem",
+                "glibc",
+                "pacman",
+                "systemd",
+                "sudo",
+                "linux",
+                "linux-lts",
+                "linux-zen",
+                "linux-hardened",
+                "linux-firmware",
+                "grub",
+                "efibootmgr",
+            ],
+            SourceBackend::DebianDpkgDatabase => &[
+                "base-files",
+                "libc6",
+                "dpkg",
+                "apt",
+                "init",
+                "systemd",
+                "sudo",
+                "linux-image-amd64",
+                "linux-image-arm64",
+                "linux-image-generic",
+                "grub-pc",
+                "grub-efi-amd64",
+                "shim-signed",
+            ],
+            SourceBackend::FedoraRpmDatabase => &[
+                "filesystem",
+                "glibc",
+                "rpm",
+                "dnf",
+                "systemd",
+                "sudo",
+                "kernel",
+                "kernel-core",
+                "grub2-pc",
+                "grub2-efi-x64",
+                "shim-x64",
+            ],
+            SourceBackend::FlatpakInstallations | SourceBackend::CargoInstallRoot => &[],
+        }
+    }
+
+    /// Whether or not this backend records if packages were installed
+    /// explicitly or as a dependency, without it every package is explicit
+    pub fn tracks_install_reasons(&self) -> bool {
+        !matches!(
+            self,
+            SourceBackend::FlatpakInstallations | SourceBackend::CargoInstallRoot
+        )
+    }
+
+    /// The source which can handle every package in this backend on its own
+    pub fn native_source(&self) -> PackageSource {
+        match self {
+            SourceBackend::ArchLocalDatabase => PackageSource::ArchPacman,
+            SourceBackend::DebianDpkgDatabase => PackageSource::DebianApt,
+            SourceBackend::FedoraRpmDatabase => PackageSource::FedoraDnf,
+            SourceBackend::FlatpakInstallations => PackageSource::Flatpak,
+            SourceBackend::CargoInstallRoot => PackageSource::Cargo,
+        }
+    }
+
+    /// Should return the appropriate function to get every
+    /// package installed in this backend with its exact version
+    pub fn inventory(&self) -> PackageInventoryFunction {
+        match self {
+            SourceBackend::ArchLocalDatabase => pacman::inventory_packages,
+            SourceBackend::DebianDpkgDatabase => apt::inventory_packages,
+            SourceBackend::FedoraRpmDatabase => dnf::inventory_packages,
+            SourceBackend::FlatpakInstallations => flatpak::inventory_packages,
+            SourceBackend::CargoInstallRoot => cargo::inventory_packages,
+        }
+    }
+
+    /// Should return the appropriate function to bring
+    /// this backend back to an earlier snapshot
+    pub fn rollback(&self) -> PackageRollbackFunction {
+        match self {
+            SourceBackend::ArchLocalDatabase => pacman::rollback_packages,
+            SourceBackend::DebianDpkgDatabase => apt::rollback_packages,
+            SourceBackend::FedoraRpmDatabase => dnf::rollback_packages,
+            SourceBackend::FlatpakInstallations => flatpak::rollback_packages,
+            SourceBackend::CargoInstallRoot => cargo::rollback_packages,
+        }
+    }
+
+    /// Every package which removal must never touch in this backend,
+    /// the built-in defaults along with those protected in the config
+    pub fn protected_packages(&self) -> Vec<String> {
+        self.default_protected()
+            .iter()
+            .map(|name| name.to_string())
+            .chain(ROOT_CONFIG.get_config().protected.iter().cloned())
+            .collect()
+    }
 }
 
 impl Display for PackageSource {
@@ -32,15 +220,61 @@ impl Display for PackageSource {
         match self {
             PackageSource::ArchParu => write!(f, "Arch-Linux using Paru"),
             PackageSource::ArchPacman => write!(f, "Arch-Linux using Pacman"),
+            PackageSource::DebianApt => write!(f, "Debian using Apt"),
+            PackageSource::FedoraDnf => write!(f, "Fedora using Dnf"),
+            PackageSource::Flatpak => write!(f, "Flatpak"),
+            PackageSource::Cargo => write!(f, "Cargo"),
         }
     }
 }
 
-/// Function types for generic operation on a package.
-pub type PackageListFunction = fn(package: &Vec<&Package>) -> anyhow::Result<()>;
+/// Function types for generic operation on a package, also given every
+/// package of the same backend as sources sharing it upgrade each other's
+pub type PackageListFunction = fn(
+    runner: &dyn CommandRunner,
+    package: &Vec<&Package>,
+    backend_packages: &Vec<&Package>,
+) -> anyhow::Result<()>;
+
+/// Function types for removing everything not in the package list, given
+/// the plan of the backend which says what the user agreed to remove
+pub type PackageRemovalFunction = fn(
+    runner: &dyn CommandRunner,
+    package: &Vec<&Package>,
+    plan: &BackendPlan,
+) -> anyhow::Result<()>;
+
+/// Function types for working out what an operation
+/// would change on a package list without changing anything
+pub type PackagePlanFunction =
+    fn(runner: &dyn CommandRunner, package: &Vec<&Package>) -> anyhow::Result<BackendPlan>;
+
+/// Function types for querying the names of packages a source
+/// has explicitly installed on the system
+pub type PackageQueryFunction = fn(runner: &dyn CommandRunner) -> anyhow::Result<Vec<String>>;
+
+/// Function types for getting the exact version of every package in
+/// the package list which is installed, along with where it came from
+pub type PackageVersionFunction = fn(
+    runner: &dyn CommandRunner,
+    package: &Vec<&Package>,
+) -> anyhow::Result<Vec<InstalledVersion>>;
+
+/// Function types for getting every package installed
+/// in a backend along with its exact version
+pub type PackageInventoryFunction =
+    fn(runner: &dyn CommandRunner) -> anyhow::Result<Vec<InventoryPackage>>;
+
+/// Function types for bringing a backend back to an earlier snapshot
+pub type PackageRollbackFunction =
+    fn(runner: &dyn CommandRunner, rollback: &BackendRollback) -> anyhow::Result<()>;
 
 /// Empty for "nothing" results
-pub fn empty_package_list_fn(_: &Vec<&Package>) -> anyhow::Result<()> {
+pub fn empty_package_list_fn(
+    _: &dyn CommandRunner,
+    _: &Vec<&Package>,
+    _: &Vec<&Package>,
+) -> anyhow::Result<()> {
     Ok(())
 }
 
@@ -55,23 +289,97 @@ impl Default for PackageSource {
 }
 
 impl PackageSource {
-    /// Should return the appropriate function
-    /// to remove all non-packagelist packages.
-    pub fn remove_all_non_packagelist(self: &Self) -> anyhow::Result<PackageListFunction> {
-        if ROOT_CONFIG.get_config().prompt_removal_per_source {
-            let continue_this =
-                Confirm::new(format!("Continue with removing packages from source {}?, No will skip to the next source", self).as_str())
-                    .with_default(true)
-                    .prompt()?;
+    /// Every package source supported by blueprint
+    pub const ALL: [PackageSource; 6] = [
+        PackageSource::ArchParu,
+        PackageSource::ArchPacman,
+        PackageSource::DebianApt,
+        PackageSource::FedoraDnf,
+        PackageSource::Flatpak,
+        PackageSource::Cargo,
+    ];
 
-            if !continue_this {
-                return Ok(empty_package_list_fn);
+    /// Which backend this source installs its packages into
+    pub fn backend(&self) -> SourceBackend {
+        match self {
+            PackageSource::ArchParu | PackageSource::ArchPacman => SourceBackend::ArchLocalDatabase,
+            PackageSource::DebianApt => SourceBackend::DebianDpkgDatabase,
+            PackageSource::FedoraDnf => SourceBackend::FedoraRpmDatabase,
+            PackageSource::Flatpak => SourceBackend::FlatpakInstallations,
+            PackageSource::Cargo => SourceBackend::CargoInstallRoot,
+        }
+    }
+
+    /// Whether or not this source can install an exact version of a package
+    pub fn pins_versions(&self) -> bool {
+        matches!(
+            self,
+            PackageSource::ArchPacman
+                | PackageSource::DebianApt
+                | PackageSource::FedoraDnf
+                | PackageSource::Cargo
+        )
+    }
+
+    /// Privileges the commands this source runs to change the system need
+    pub fn privilege(&self) -> Privilege {
+        match self {
+            PackageSource::ArchPacman | PackageSource::DebianApt | PackageSource::FedoraDnf => {
+                Privilege::Elevated
             }
+
+            // Paru refuses to run as root and elevates itself for pacman
+            PackageSource::ArchParu => Privilege::User,
+
+            // Flatpak elevates itself through polkit for system installations
+            // and cargo installs into the install root of whoever runs it
+            PackageSource::Flatpak | PackageSource::Cargo => Privilege::Any,
         }
+    }
 
+    /// Ensures blueprint is running with privileges this source can
+    /// work with, returning the privilege command to elevate through
+    pub fn privilege_command(&self) -> anyhow::Result<Option<&'static str>> {
+        let privilege_command = ROOT_CONFIG.get_config().privilege_command;
+
+        match (self.privilege(), is_root()) {
+            (Privilege::Elevated, false) => match privilege_command {
+                Some(privilege_command) => Ok(Some(privilege_command.program())),
+                None => bail!(
+                    "Source {} needs root to change the system, set privilege_command in the config or run blueprint as root",
+                    self
+                ),
+            },
+            (Privilege::User, true) => bail!(
+                "Source {} must run as the invoking user, run blueprint without root and set privilege_command in the config",
+                self
+            ),
+            _ => Ok(None),
+        }
+    }
+
+    /// Wraps the runner so the commands of this source
+    /// run with the privileges this source needs
+    pub fn privileged_runner<'a>(
+        &self,
+        runner: &'a dyn CommandRunner,
+    ) -> anyhow::Result<PrivilegedRunner<'a>> {
+        Ok(PrivilegedRunner::new(runner, self.privilege_command()?))
+    }
+
+    /// Should return the appropriate function
+    /// to remove all non-packagelist packages.
+    ///
+    /// Is given the packages of every source on the same backend, as they
+    /// share installed packages, along with the plan for that backend
+    pub fn remove_all_non_packagelist(&self) -> PackageRemovalFunction {
         match self {
-            PackageSource::ArchParu => Ok(paru::remove_non_packages),
-            PackageSource::ArchPacman => Ok(pacman::remove_non_packages),
+            PackageSource::ArchParu => paru::remove_non_packages,
+            PackageSource::ArchPacman => pacman::remove_non_packages,
+            PackageSource::DebianApt => apt::remove_non_packages,
+            PackageSource::FedoraDnf => dnf::remove_non_packages,
+            PackageSource::Flatpak => flatpak::remove_non_packages,
+            PackageSource::Cargo => cargo::remove_non_packages,
         }
     }
 
@@ -80,10 +388,13 @@ impl PackageSource {
     pub fn install_all_packagelist(self: &Self) -> anyhow::Result<PackageListFunction> {
         // Confirm or skip this source
         if ROOT_CONFIG.get_config().prompt_install_per_source {
-            let continue_this =
-                Confirm::new(format!("Continue with installing packages from source {}?, No will skip to the next source", self).as_str())
-                    .with_default(true)
-                    .prompt()?;
+            let continue_this = confirm(
+                &format!(
+                    "Continue with installing packages from source {}?, No will skip to the next source",
+                    self
+                ),
+                true,
+            )?;
 
             if !continue_this {
                 return Ok(empty_package_list_fn);
@@ -93,6 +404,55 @@ impl PackageSource {
         Ok(match self {
             PackageSource::ArchParu => paru::install_packages,
             PackageSource::ArchPacman => pacman::install_packages,
+            PackageSource::DebianApt => apt::install_packages,
+            PackageSource::FedoraDnf => dnf::install_packages,
+            PackageSource::Flatpak => flatpak::install_packages,
+            PackageSource::Cargo => cargo::install_packages,
         })
     }
+
+    /// Should return the appropriate function to work out
+    /// what installing and removing would change on the system
+    ///
+    /// Is given the packages of every source on the
+    /// same backend, as they share installed packages
+    pub fn plan_packagelist(&self) -> PackagePlanFunction {
+        match self {
+            PackageSource::ArchParu => paru::plan_packages,
+            PackageSource::ArchPacman => pacman::plan_packages,
+            PackageSource::DebianApt => apt::plan_packages,
+            PackageSource::FedoraDnf => dnf::plan_packages,
+            PackageSource::Flatpak => flatpak::plan_packages,
+            PackageSource::Cargo => cargo::plan_packages,
+        }
+    }
+
+    /// Should return the appropriate function to get the exact
+    /// installed version of every package in the package list
+    ///
+    /// Is given the packages of every source on the
+    /// same backend, as they share installed packages
+    pub fn installed_versions(&self) -> PackageVersionFunction {
+        match self {
+            PackageSource::ArchParu => paru::installed_versions,
+            PackageSource::ArchPacman => pacman::installed_versions,
+            PackageSource::DebianApt => apt::installed_versions,
+            PackageSource::FedoraDnf => dnf::installed_versions,
+            PackageSource::Flatpak => flatpak::installed_versions,
+            PackageSource::Cargo => cargo::installed_versions,
+        }
+    }
+
+    /// Should return the appropriate function to get
+    /// all packages explicitly installed through this source
+    pub fn explicit_packages(&self) -> PackageQueryFunction {
+        match self {
+            PackageSource::ArchParu => paru::explicit_packages,
+            PackageSource::ArchPacman => pacman::explicit_packages,
+            PackageSource::DebianApt => apt::explicit_packages,
+            PackageSource::FedoraDnf => dnf::explicit_packages,
+            PackageSource::Flatpak => flatpak::explicit_packages,
+            PackageSource::Cargo => cargo::explicit_packages,
+        }
+    }
 }
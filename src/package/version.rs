@@ -0,0 +1,342 @@
+//! Version constraints on packages and comparing
+//! the versions reported by package sources
+
+use std::{cmp::Ordering, fmt::Display, path::PathBuf};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::package::package::Package;
+
+/// Comparison a version constraint makes against the installed version
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum VersionOperator {
+    Exact,
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+}
+
+/// Constraint on the version of a package such as "1.2.3" which
+/// pins an exact version, or ">=6.6" which allows a range of them
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct VersionConstraint {
+    // How the installed version is compared to the version
+    pub operator: VersionOperator,
+
+    // Version compared against
+    pub version: String,
+}
+
+/// A declared package whose installed version
+/// does not satisfy its version constraint
+#[derive(Debug)]
+pub struct VersionMismatch {
+    // Name of the package
+    pub name: String,
+
+    // Version currently installed on the system
+    pub installed: String,
+
+    // Constraint the installed version does not satisfy
+    pub constraint: VersionConstraint,
+
+    // What file the package is defined in
+    pub src: PathBuf,
+}
+
+/// A single segment of a version string
+#[derive(Debug, PartialEq, Eq)]
+enum VersionSegment<'a> {
+    // A tilde, which sorts before everything (pre-releases)
+    Tilde,
+    Numeric(&'a str),
+    Alphabetic(&'a str),
+}
+
+impl TryFrom<String> for VersionConstraint {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let value = value.trim();
+
+        // Longest operators first so ">=" isn't read as ">"
+        let (operator, version) = [
+            (">=", VersionOperator::GreaterOrEqual),
+            ("<=", VersionOperator::LessOrEqual),
+            ("==", VersionOperator::Exact),
+            (">", VersionOperator::Greater),
+            ("<", VersionOperator::Less),
+            ("=", VersionOperator::Exact),
+        ]
+        .into_iter()
+        .find_map(|(prefix, operator)| {
+            value
+                .strip_prefix(prefix)
+                .map(|version| (operator, version.trim()))
+        })
+        .unwrap_or((VersionOperator::Exact, value));
+
+        if version.is_empty() || version.contains(char::is_whitespace) {
+            bail!(
+                "Invalid version constraint {:?}, expected a version such as \"1.2.3\" optionally prefixed by one of =, >=, <=, > or <",
+                value
+            );
+        }
+
+        Ok(Self {
+            operator,
+            version: version.to_string(),
+        })
+    }
+}
+
+impl From<VersionConstraint> for String {
+    fn from(value: VersionConstraint) -> Self {
+        value.to_string()
+    }
+}
+
+impl Display for VersionOperator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VersionOperator::Exact => write!(f, "="),
+            VersionOperator::Greater => write!(f, ">"),
+            VersionOperator::GreaterOrEqual => write!(f, ">="),
+            VersionOperator::Less => write!(f, "<"),
+            VersionOperator::LessOrEqual => write!(f, "<="),
+        }
+    }
+}
+
+impl Display for VersionConstraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.operator {
+            // Exact versions are written bare, as most package managers expect
+            VersionOperator::Exact => write!(f, "{}", self.version),
+            operator => write!(f, "{}{}", operator, self.version),
+        }
+    }
+}
+
+impl Display for VersionMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} has version {} installed, which does not satisfy {} from configuration file {:?}",
+            self.name, self.installed, self.constraint, self.src
+        )
+    }
+}
+
+impl VersionConstraint {
+    /// Constraint pinning exactly the version
+    pub fn pinned(version: String) -> Self {
+        Self {
+            operator: VersionOperator::Exact,
+            version,
+        }
+    }
+
+    /// The exact version this constraint pins, if it pins one
+    pub fn exact(&self) -> Option<&str> {
+        (self.operator == VersionOperator::Exact).then_some(self.version.as_str())
+    }
+
+    /// Whether or not the installed version satisfies this constraint.
+    ///
+    /// Parts of the installed version which the constraint leaves out
+    /// (the epoch "1:" and release "-2") are ignored when comparing.
+    pub fn matches(&self, installed: &str) -> bool {
+        let mut installed = installed;
+
+        if !self.version.contains(':') {
+            installed = installed
+                .split_once(':')
+                .map_or(installed, |(_, rest)| rest);
+        }
+
+        if !self.version.contains('-') {
+            installed = installed
+                .rsplit_once('-')
+                .map_or(installed, |(rest, _)| rest);
+        }
+
+        let ordering = compare_versions(installed, &self.version);
+
+        match self.operator {
+            VersionOperator::Exact => ordering == Ordering::Equal,
+            VersionOperator::Greater => ordering == Ordering::Greater,
+            VersionOperator::GreaterOrEqual => ordering != Ordering::Less,
+            VersionOperator::Less => ordering == Ordering::Less,
+            VersionOperator::LessOrEqual => ordering != Ordering::Greater,
+        }
+    }
+}
+
+/// Splits a version string into its segments, dropping separators
+fn version_segments(version: &str) -> Vec<VersionSegment<'_>> {
+    let mut segments = Vec::new();
+    let mut remaining = version;
+
+    while let Some(first) = remaining.chars().next() {
+        let segment_length = if first.is_ascii_digit() {
+            remaining
+                .find(|character: char| !character.is_ascii_digit())
+                .unwrap_or(remaining.len())
+        } else if first.is_ascii_alphabetic() {
+            remaining
+                .find(|character: char| !character.is_ascii_alphabetic())
+                .unwrap_or(remaining.len())
+        } else {
+            if first == '~' {
+                segments.push(VersionSegment::Tilde);
+            }
+
+            remaining = &remaining[first.len_utf8()..];
+            continue;
+        };
+
+        let (segment, rest) = remaining.split_at(segment_length);
+        segments.push(if first.is_ascii_digit() {
+            VersionSegment::Numeric(segment.trim_start_matches('0'))
+        } else {
+            VersionSegment::Alphabetic(segment)
+        });
+        remaining = rest;
+    }
+
+    segments
+}
+
+/// Compares two versions the way rpm and pacman do, numeric segments are
+/// compared as numbers, and numbers are newer than letters. Trailing
+/// letters or a tilde mark a pre-release, which is older than no suffix.
+pub fn compare_versions(first: &str, second: &str) -> Ordering {
+    let first = version_segments(first);
+    let second = version_segments(second);
+
+    for index in 0..first.len().max(second.len()) {
+        let ordering = match (first.get(index), second.get(index)) {
+            (Some(first), Some(second)) => match (first, second) {
+                (VersionSegment::Tilde, VersionSegment::Tilde) => Ordering::Equal,
+                (VersionSegment::Tilde, _) => Ordering::Less,
+                (_, VersionSegment::Tilde) => Ordering::Greater,
+                (VersionSegment::Numeric(first), VersionSegment::Numeric(second)) => first
+                    .len()
+                    .cmp(&second.len())
+                    .then_with(|| first.cmp(second)),
+                (VersionSegment::Numeric(_), VersionSegment::Alphabetic(_)) => Ordering::Greater,
+                (VersionSegment::Alphabetic(_), VersionSegment::Numeric(_)) => Ordering::Less,
+                (VersionSegment::Alphabetic(first), VersionSegment::Alphabetic(second)) => {
+                    first.cmp(second)
+                }
+            },
+
+            // Only one version has segments left, numbers
+            // make it newer while anything else is a pre-release
+            (Some(VersionSegment::Numeric(_)), None) => Ordering::Greater,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(VersionSegment::Numeric(_))) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        };
+
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+
+    Ordering::Equal
+}
+
+/// Finds every declared package whose installed version does not satisfy
+/// its constraint, using the supplied lookup of installed versions
+pub fn version_mismatches<'a>(
+    package_list: &Vec<&Package>,
+    installed_version: impl Fn(&str) -> Option<&'a str>,
+) -> Vec<VersionMismatch> {
+    package_list
+        .iter()
+        .filter_map(|package| {
+            let constraint = package.version.as_ref()?;
+            let installed = installed_version(&package.name)?;
+
+            (!constraint.matches(installed)).then(|| VersionMismatch {
+                name: package.name.clone(),
+                installed: installed.to_string(),
+                constraint: constraint.clone(),
+                src: package.src.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Errors listing every mismatch if there are any
+pub fn ensure_no_mismatches(mismatches: &[VersionMismatch]) -> anyhow::Result<()> {
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+
+    bail!(
+        "{} packages do not satisfy their version constraint:\n{}",
+        mismatches.len(),
+        mismatches
+            .iter()
+            .map(|mismatch| format!("  {}", mismatch))
+            .collect::<Vec<String>>()
+            .join("\n")
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Constraint parsed the same way as from the configuration
+    fn constraint(value: &str) -> VersionConstraint {
+        VersionConstraint::try_from(value.to_string()).unwrap()
+    }
+
+    #[test]
+    fn versions_compare_segment_by_segment() {
+        assert_eq!(compare_versions("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(compare_versions("2", "1.99"), Ordering::Greater);
+        assert_eq!(compare_versions("1.0.1", "1.0"), Ordering::Greater);
+        assert_eq!(compare_versions("1.01", "1.1"), Ordering::Equal);
+        assert_eq!(compare_versions("1.0-1", "1.0.1"), Ordering::Equal);
+        assert_eq!(compare_versions("1.0a", "1.0"), Ordering::Less);
+        assert_eq!(compare_versions("1.0~rc1", "1.0"), Ordering::Less);
+        assert_eq!(compare_versions("1.0~rc1", "1.0~rc2"), Ordering::Less);
+        assert_eq!(compare_versions("1.0", "1.0a"), Ordering::Greater);
+    }
+
+    #[test]
+    fn constraints_are_parsed_with_their_operator() {
+        assert_eq!(
+            constraint(">= 6.6").operator,
+            VersionOperator::GreaterOrEqual
+        );
+        assert_eq!(constraint(">= 6.6").version, "6.6");
+        assert_eq!(constraint("<2").operator, VersionOperator::Less);
+        assert_eq!(constraint("==1.2.3").exact(), Some("1.2.3"));
+        assert_eq!(constraint("1.2.3").exact(), Some("1.2.3"));
+        assert!(VersionConstraint::try_from(String::from(">=")).is_err());
+        assert!(VersionConstraint::try_from(String::from("1.2 3")).is_err());
+    }
+
+    #[test]
+    fn constraints_ignore_what_they_leave_out() {
+        assert!(constraint("2.0").matches("1:2.0-3"));
+        assert!(!constraint("2.0-2").matches("2.0-3"));
+        assert!(constraint("1:2.0").matches("1:2.0-3"));
+        assert!(!constraint("2:2.0").matches("1:2.0-3"));
+        assert!(constraint(">=6.6").matches("6.6.1-1"));
+        assert!(constraint(">=6.6").matches("6.6-1"));
+        assert!(!constraint(">6.6").matches("6.6-1"));
+        assert!(constraint("<7").matches("6.9.12.arch1-1"));
+        assert!(!constraint("<=6.6").matches("6.10-1"));
+    }
+}
@@ -1,18 +1,28 @@
 //! Parsing configuration files for blueprint
 
 use anyhow::{Context, bail};
-use log::warn;
+use log::{info, warn};
 use serde::Deserialize;
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     fs,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use crate::{
     cleanpath::CleanPath,
+    condition::{Condition, Exclusion, retain_met},
     config::*,
-    package::{package::Package, sources::PackageSource},
+    file::ManagedFile,
+    hook::{Hooks, RemovalHook},
+    package::{
+        package::{InstallScope, Package, PackageList},
+        sources::PackageSource,
+        version::VersionConstraint,
+    },
+    profile::ActiveProfiles,
+    report::record_packages,
+    service::Service,
 };
 
 /// Links to other blueprint configuration files
@@ -24,7 +34,22 @@ use crate::{
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct ConfigLink {
+    // File, directory or glob pattern of the files to link to,
+    // directories link to every toml file directly inside them
     file: PathBuf,
+
+    // Whether or not a link to nothing is skipped rather than an error
+    #[serde(default)]
+    optional: bool,
+
+    // Condition on the host which must hold for this link to be followed
+    #[serde(default, alias = "if")]
+    when: Option<Condition>,
+
+    // Profiles this link belongs to, it is only followed when
+    // one of them is active. Without any profiles it always is
+    #[serde(default)]
+    profiles: Vec<String>,
 }
 
 /// Multiple packages shorthand, will be flattened into
@@ -38,24 +63,215 @@ pub struct MultiplePackage {
     // Wher to source these packages?
     #[serde(default)]
     pub source: PackageSource,
+
+    // Which remote to install these packages from, flatpak only
+    #[serde(default)]
+    pub remote: Option<String>,
+
+    // Install these packages for the user or system, flatpak only
+    #[serde(default)]
+    pub scope: Option<InstallScope>,
+
+    // Version constraint these packages must satisfy
+    #[serde(default)]
+    pub version: Option<VersionConstraint>,
+
+    // Git repository to install these packages from, cargo only
+    #[serde(default)]
+    pub git: Option<String>,
+
+    // Branch of the git repository to install from, cargo only
+    #[serde(default)]
+    pub branch: Option<String>,
+
+    // Features to enable when building these packages, cargo only
+    #[serde(default)]
+    pub features: Vec<String>,
+
+    // Build with the dependency versions locked, cargo only
+    #[serde(default)]
+    pub locked: bool,
+
+    // Condition on the host which must hold for these packages to be used
+    #[serde(default, alias = "if")]
+    pub when: Option<Condition>,
+
+    // Profiles these packages belong to
+    #[serde(default)]
+    pub profiles: Vec<String>,
 }
 
-/// Is this link to another file (from origin_file) valid?
-fn validate_link(file_path: &PathBuf, origin_file: &PathBuf) -> anyhow::Result<()> {
-    // Check if path exists, else error.
-    if !file_path.exists() {
+impl MultiplePackage {
+    /// Decomposes this shorthand into every
+    /// individual package it declares
+    pub fn into_packages(self) -> Vec<Package> {
+        self.names
+            .iter()
+            .map(|package_name| Package {
+                remote: self.remote.clone(),
+                scope: self.scope,
+                version: self.version.clone(),
+                git: self.git.clone(),
+                branch: self.branch.clone(),
+                features: self.features.clone(),
+                locked: self.locked,
+                profiles: self.profiles.clone(),
+                ..Package::new(package_name.clone(), self.source)
+            })
+            .collect()
+    }
+}
+
+/// Packages to subtract from the package list after every file
+/// has been flattened together, such as those declared by a
+/// shared file which this machine must not have
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PackageExclude {
+    // Names of the packages to exclude
+    pub names: Vec<String>,
+
+    // Only exclude the packages from this source, any source if not given
+    #[serde(default)]
+    pub source: Option<PackageSource>,
+
+    // What file is this exclude defined in for debugging info
+    #[serde(skip)]
+    pub src: PathBuf,
+}
+
+impl PackageExclude {
+    /// Whether or not this exclude matches the package
+    fn matches(&self, package: &Package) -> bool {
+        self.names.contains(&package.name)
+            && self.source.is_none_or(|source| source == package.source)
+    }
+}
+
+/// Removes every package matched by the excludes from the package list,
+/// adding an exclusion for each. Gives the names excluded which matched
+/// nothing, along with the file excluding them
+fn apply_excludes<'a>(
+    package_list: &mut PackageList,
+    excludes: &'a [PackageExclude],
+    excluded: &mut Vec<Exclusion>,
+) -> Vec<(&'a str, &'a Path)> {
+    let mut unmatched = Vec::new();
+    for exclude in excludes {
+        let mut matched: HashSet<String> = HashSet::new();
+        package_list.retain(|package| {
+            if !exclude.matches(package) {
+                return true;
+            }
+
+            matched.insert(package.name.clone());
+            excluded.push(Exclusion {
+                entry: format!("package {} from {:?}", package.name, package.src),
+                reason: String::from("it is excluded by [[exclude]]"),
+                src: exclude.src.clone(),
+            });
+            false
+        });
+
+        unmatched.extend(
+            exclude
+                .names
+                .iter()
+                .filter(|name| !matched.contains(*name))
+                .map(|name| (name.as_str(), exclude.src.as_path())),
+        );
+    }
+
+    unmatched
+}
+
+/// Gets every toml file directly inside of the directory
+fn directory_configs(directory: &PathBuf) -> anyhow::Result<Vec<PathBuf>> {
+    let entries = fs::read_dir(directory)
+        .with_context(|| format!("While trying to read linked directory {:?}", directory))?;
+
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("While trying to read linked directory {:?}", directory))?
+            .path();
+
+        if path.is_file()
+            && path
+                .extension()
+                .is_some_and(|extension| extension == "toml")
+        {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Gets every file matching the glob pattern
+fn glob_configs(pattern: &PathBuf) -> anyhow::Result<Vec<PathBuf>> {
+    let pattern_str = pattern.to_string_lossy();
+
+    let mut files = Vec::new();
+    for path in glob::glob(&pattern_str)
+        .with_context(|| format!("While trying to parse link glob pattern {:?}", pattern))?
+    {
+        let path =
+            path.with_context(|| format!("While trying to match link glob pattern {:?}", pattern))?;
+
+        if path.is_file() {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Resolves the linked path (from origin_file) into every configuration
+/// file it links to in sorted order, erroring if it links to
+/// nothing unless the link is optional
+fn resolve_link(
+    linked_path: &PathBuf,
+    link: &ConfigLink,
+    origin_file: &PathBuf,
+) -> anyhow::Result<Vec<PathBuf>> {
+    // An existing path is never a glob pattern, even
+    // if its name contains one of the glob characters
+    let is_glob = linked_path.to_string_lossy().contains(['*', '?', '[']);
+
+    let mut files = if linked_path.is_dir() {
+        directory_configs(linked_path)?
+    } else if linked_path.exists() {
+        vec![linked_path.clone()]
+    } else if is_glob {
+        glob_configs(linked_path)?
+    } else {
+        Vec::new()
+    };
+
+    if files.is_empty() {
+        if link.optional {
+            info!(
+                "Skipping optional link {:?} in configuration file {:?}, it does not link to any files",
+                linked_path, origin_file
+            );
+            return Ok(files);
+        }
+
         bail!(
-            "File {:?} referenced by link in configuration file {:?} does not exist",
-            file_path,
+            "Link to {:?} in configuration file {:?} does not link to any files, set optional to skip it when missing",
+            linked_path,
             origin_file
         );
     }
 
-    Ok(())
+    // Same order no matter how the filesystem lists them
+    files.sort();
+    files.iter().map(CleanPath::clean_path).collect()
 }
 
-/// Parses an individual configuration file
-fn parse_single_config(file_path: &PathBuf, section: &String) -> anyhow::Result<Blueprint> {
+/// Reads in an individual configuration file
+fn read_single_config(file_path: &PathBuf, section: &String) -> anyhow::Result<Blueprint> {
     // Read in content and try parse using toml
     let file_content = fs::read_to_string(&file_path)
         .with_context(|| format!("While trying to read configuration file {:?}", file_path))?;
@@ -67,25 +283,156 @@ fn parse_single_config(file_path: &PathBuf, section: &String) -> anyhow::Result<
                 format!("While trying to parse configuration file through quill scope extraction")
             })?;
 
-    let mut config: Blueprint = toml::from_str(&file_content)
-        .with_context(|| format!("While trying to parse configuration file {:?}", file_path))?;
+    toml::from_str(&file_content)
+        .with_context(|| format!("While trying to parse configuration file {:?}", file_path))
+}
+
+/// Prepares an individual configuration file which has been read in, leaving
+/// out entries whose conditions don't hold or whose profiles aren't active.
+/// Inherited is the profiles which pulled this file in through a link
+fn parse_single_config(
+    mut config: Blueprint,
+    file_path: &PathBuf,
+    profiles: &mut ActiveProfiles,
+    inherited: &[String],
+) -> anyhow::Result<Blueprint> {
+    // Packages left out are removed like any other undeclared
+    // package, so their removal hooks are kept regardless
+    let src = file_path.clean_path()?;
+    config.removal_hooks = config
+        .packages
+        .iter()
+        .filter_map(|package| {
+            Some(RemovalHook {
+                name: package.name.clone(),
+                backend: package.source.backend(),
+                run: package.post_remove.clone()?,
+                src: src.clone(),
+            })
+        })
+        .collect();
+
+    // Leave out every entry outside of the active profiles first,
+    // so the profiles of entries left out by conditions are still known
+    profiles.retain_active(
+        &mut config.packages.0,
+        file_path,
+        &mut config.excluded,
+        |package| &package.profiles,
+        |package| format!("package {}", package.name),
+    );
+    profiles.retain_active(
+        &mut config.multi_packages,
+        file_path,
+        &mut config.excluded,
+        |multi_package| &multi_package.profiles,
+        |multi_package| format!("packages {}", multi_package.names.join(" ")),
+    );
+    profiles.retain_active(
+        &mut config.links,
+        file_path,
+        &mut config.excluded,
+        |link| &link.profiles,
+        |link| format!("link to {:?}", link.file),
+    );
+    profiles.retain_active(
+        &mut config.services,
+        file_path,
+        &mut config.excluded,
+        |service| &service.profiles,
+        |service| format!("service {}", service.name),
+    );
+    profiles.retain_active(
+        &mut config.files,
+        file_path,
+        &mut config.excluded,
+        |file| &file.profiles,
+        |file| format!("file {:?}", file.target),
+    );
+
+    // Then every entry whose condition doesn't hold on this host
+    retain_met(
+        &mut config.packages.0,
+        file_path,
+        &mut config.excluded,
+        |package| package.when.as_ref(),
+        |package| format!("package {}", package.name),
+    )?;
+    retain_met(
+        &mut config.multi_packages,
+        file_path,
+        &mut config.excluded,
+        |multi_package| multi_package.when.as_ref(),
+        |multi_package| format!("packages {}", multi_package.names.join(" ")),
+    )?;
+    retain_met(
+        &mut config.links,
+        file_path,
+        &mut config.excluded,
+        |link| link.when.as_ref(),
+        |link| format!("link to {:?}", link.file),
+    )?;
+    retain_met(
+        &mut config.services,
+        file_path,
+        &mut config.excluded,
+        |service| service.when.as_ref(),
+        |service| format!("service {}", service.name),
+    )?;
+    retain_met(
+        &mut config.files,
+        file_path,
+        &mut config.excluded,
+        |file| file.when.as_ref(),
+        |file| format!("file {:?}", file.target),
+    )?;
 
     // Decompose sources into the packages
     while let Some(multi_package) = config.multi_packages.pop() {
         // Decompose into the existing packages list..
-        config.packages.extend(
-            multi_package
-                .names
-                .into_iter()
-                .map(|package_name| Package::new(package_name, multi_package.source)),
-        )
+        config.packages.extend(multi_package.into_packages())
     }
 
-    // Add dir to the packages for debugging tracking.
+    // Add dir and profiles to the packages for debugging tracking.
+    config.packages.iter_mut().try_for_each(|package| {
+        package.pulled_in_by = profiles.pulled_in_by(&package.profiles, inherited);
+        package.add_source_debug_path(file_path)
+    })?;
+
+    // Add dir to the excludes, hooks and services for debugging tracking.
     config
-        .packages
+        .excludes
+        .iter_mut()
+        .try_for_each(|exclude| -> anyhow::Result<()> {
+            exclude.src = file_path.clean_path()?;
+            Ok(())
+        })?;
+    config
+        .hooks
+        .iter_mut()
+        .try_for_each(|hook| -> anyhow::Result<()> {
+            hook.src = file_path.clean_path()?;
+            Ok(())
+        })?;
+    config
+        .services
+        .iter_mut()
+        .try_for_each(|service| -> anyhow::Result<()> {
+            service.src = file_path.clean_path()?;
+            Ok(())
+        })?;
+
+    // Files are resolved from the perspective of this file, like links
+    config
+        .files
         .iter_mut()
-        .try_for_each(|package| package.add_source_debug_path(file_path))?;
+        .try_for_each(|file| file.resolve(file_path))?;
+
+    // Packages may only use options their source supports
+    config
+        .packages
+        .iter()
+        .try_for_each(Package::validate_options)?;
 
     Ok(config)
 }
@@ -94,12 +441,15 @@ fn parse_single_config(file_path: &PathBuf, section: &String) -> anyhow::Result<
 /// the unprocessed configs vecdeque if they are not
 /// already in the config_map supplied.
 ///
-/// The current path is supplied for logging purposes.
+/// The current path is supplied for logging purposes, linked files
+/// inherit the profiles which pulled in the link or the current file.
 fn process_links(
-    unprocessed_configs: &mut VecDeque<PathBuf>,
+    unprocessed_configs: &mut VecDeque<(PathBuf, Vec<String>)>,
     current_path: &PathBuf,
     links: &Vec<ConfigLink>,
     config_map: &mut HashMap<PathBuf, Blueprint>,
+    profiles: &ActiveProfiles,
+    inherited: &[String],
 ) -> anyhow::Result<()> {
     for link in links {
         // Create this linked path from the perspective of this path
@@ -108,10 +458,16 @@ fn process_links(
             .context("Configuration file has no parent directory")?;
         let linked_path = parent.join(&link.file).clean_path()?;
 
-        // Add this unprocessed path to the list for later checking..
-        validate_link(&linked_path, &current_path)?;
-        if !config_map.contains_key(&linked_path) && !unprocessed_configs.contains(&linked_path) {
-            unprocessed_configs.push_back(linked_path);
+        // Add every linked file to the list for later checking..
+        for linked_file in resolve_link(&linked_path, link, current_path)? {
+            if !config_map.contains_key(&linked_file)
+                && !unprocessed_configs
+                    .iter()
+                    .any(|(unprocessed_path, _)| *unprocessed_path == linked_file)
+            {
+                let pulled_in_by = profiles.pulled_in_by(&link.profiles, inherited);
+                unprocessed_configs.push_back((linked_file, pulled_in_by));
+            }
         }
     }
 
@@ -122,11 +478,14 @@ fn process_links(
 /// the expected config in blueprint
 ///
 /// The result is all of the included blueprint files together in a vec.
-/// which are all of the "linked" ones, and the first half of the tuple is the root.
+/// which are all of the "linked" ones, the first of the tuple is the root
+/// and the last is the active profiles, the selected profiles or the
+/// default profiles of the root if none are selected.
 pub fn parse_config(
     file_path: PathBuf,
     section: String,
-) -> anyhow::Result<(Blueprint, BlueprintConfigs)> {
+    selected_profiles: Vec<String>,
+) -> anyhow::Result<(Blueprint, BlueprintConfigs, ActiveProfiles)> {
     if !file_path.exists() {
         bail!(
             "Supplied root configuration file {:?} does not exist",
@@ -138,19 +497,39 @@ pub fn parse_config(
     // a config has already been included to break recursive-deps
     let mut config_map: HashMap<PathBuf, Blueprint> = HashMap::new();
 
+    // Order the configs were processed in, so linked
+    // files are always flattened in the same order
+    let mut config_order: Vec<PathBuf> = Vec::new();
+
     // Track unprocessed linked configs, our root is unprocessed
-    let mut unprocessed_configs: VecDeque<PathBuf> = VecDeque::new();
-    unprocessed_configs.push_back(file_path.clone());
+    let mut unprocessed_configs: VecDeque<(PathBuf, Vec<String>)> = VecDeque::new();
+    unprocessed_configs.push_back((file_path.clone(), Vec::new()));
+
+    // Known once the root has been read in
+    let mut active_profiles: Option<ActiveProfiles> = None;
 
     // Go over all unprocessed configs
-    while let Some(current_path) = unprocessed_configs.pop_front() {
+    while let Some((current_path, inherited)) = unprocessed_configs.pop_front() {
         // Already processed, skip
         if config_map.contains_key(&current_path) {
             continue;
         }
 
+        let config = read_single_config(&current_path, &section)?;
+
+        // The root is always read first, so its default
+        // profiles are known before anything is left out
+        let profiles = active_profiles.get_or_insert_with(|| {
+            let default_profiles = config
+                .config
+                .as_ref()
+                .map(|config| config.default_profiles.as_slice())
+                .unwrap_or_default();
+            ActiveProfiles::new(selected_profiles.clone(), default_profiles)
+        });
+
         // Process this config, add its other configs to the unproc list
-        let config = parse_single_config(&current_path, &section)?;
+        let config = parse_single_config(config, &current_path, profiles, &inherited)?;
 
         // Warn about unsued config
         if !(current_path == file_path) && config.config.is_some() {
@@ -166,13 +545,379 @@ pub fn parse_config(
             &current_path,
             &config.links,
             &mut config_map,
+            profiles,
+            &inherited,
         )?;
+        config_order.push(current_path.clone());
         config_map.insert(current_path, config);
     }
 
     // Get root back from config_map, shouldn't ever not exist (doesn't make sense)
-    Ok((
-        config_map.remove(&file_path).unwrap(),
-        config_map.into_values().collect(),
-    ))
+    let root = config_map.remove(&file_path).unwrap();
+    let configs = config_order
+        .iter()
+        .filter_map(|path| config_map.remove(path))
+        .collect();
+
+    let active_profiles = active_profiles.unwrap();
+    active_profiles.ensure_declared()?;
+
+    Ok((root, configs, active_profiles))
+}
+
+/// Everything loaded from the root configuration file and its links
+pub struct LoadedConfig {
+    // Package list of all of the files with duplicates removed
+    pub package_list: PackageList,
+
+    // Entries left out as their condition did not
+    // hold on this host or their profiles are inactive
+    pub excluded: Vec<Exclusion>,
+
+    // Profiles which were active
+    pub profiles: ActiveProfiles,
+
+    // Hooks of all of the files
+    pub hooks: Hooks,
+
+    // Services of all of the files with duplicates removed
+    pub services: Vec<Service>,
+
+    // Files declared by all of the files with duplicates removed
+    pub files: Vec<ManagedFile>,
+
+    // Cleaned path of the root configuration file
+    pub root_file: PathBuf,
+}
+
+/// Parses the root configuration file supplied along with all of its
+/// links, fills in the global config from the root and returns the
+/// package list of all of the files with duplicates removed, along
+/// with every entry left out by its condition or profiles
+pub fn load_config(
+    file: String,
+    section: String,
+    profiles: Vec<String>,
+) -> anyhow::Result<LoadedConfig> {
+    // Validate file path
+    let path = PathBuf::from(file).clean_path()?;
+
+    // Parse configs to config structs.
+    let (root, mut configs, profiles) = parse_config(path.clone(), section, profiles)?;
+
+    // Fill in global root config from root
+    let global_config = root.config.unwrap_or_default();
+    ROOT_CONFIG.set_config(global_config);
+
+    // Grab all of the packages, excludes, hooks and excluded entries
+    let mut excluded = root.excluded;
+    excluded.extend(
+        configs
+            .0
+            .iter_mut()
+            .flat_map(|config| config.excluded.drain(..)),
+    );
+    let mut excludes = root.excludes;
+    excludes.extend(
+        configs
+            .0
+            .iter_mut()
+            .flat_map(|config| config.excludes.drain(..)),
+    );
+    let mut hooks = Hooks {
+        phases: root.hooks,
+        removals: root.removal_hooks,
+    };
+    hooks.phases.extend(
+        configs
+            .0
+            .iter_mut()
+            .flat_map(|config| config.hooks.drain(..)),
+    );
+    hooks.removals.extend(
+        configs
+            .0
+            .iter_mut()
+            .flat_map(|config| config.removal_hooks.drain(..)),
+    );
+    let mut services = root.services;
+    services.extend(
+        configs
+            .0
+            .iter_mut()
+            .flat_map(|config| config.services.drain(..)),
+    );
+    let services = remove_duplicate_services(services)?;
+    let mut files = root.files;
+    files.extend(
+        configs
+            .0
+            .iter_mut()
+            .flat_map(|config| config.files.drain(..)),
+    );
+    let files = remove_duplicate_files(files)?;
+    let mut package_list = configs.flatten_data();
+    package_list.extend(root.packages.0);
+    let mut package_list = package_list.remove_duplicates()?;
+
+    // Excluded packages are undeclared, so may be removed like any other
+    for (name, src) in apply_excludes(&mut package_list, &excludes, &mut excluded) {
+        warn!(
+            "[[exclude]] of {} in configuration file {:?} does not match any package",
+            name, src
+        );
+    }
+
+    record_packages(&package_list);
+
+    Ok(LoadedConfig {
+        package_list,
+        excluded,
+        profiles,
+        hooks,
+        services,
+        files,
+        root_file: path,
+    })
+}
+
+/// Removes services declared more than once, failing when
+/// the same unit is declared with different states
+fn remove_duplicate_services(services: Vec<Service>) -> anyhow::Result<Vec<Service>> {
+    let mut unique: Vec<Service> = Vec::with_capacity(services.len());
+
+    for service in services {
+        let Some(existing) = unique
+            .iter()
+            .find(|existing| existing.name == service.name && existing.user == service.user)
+        else {
+            unique.push(service);
+            continue;
+        };
+
+        if existing.enabled != service.enabled || existing.masked != service.masked {
+            bail!("Conflicting declarations of {} and {}", existing, service);
+        }
+    }
+
+    Ok(unique)
+}
+
+/// Removes files declared more than once, failing when the
+/// same target is declared to be put into place differently
+fn remove_duplicate_files(files: Vec<ManagedFile>) -> anyhow::Result<Vec<ManagedFile>> {
+    let mut unique: Vec<ManagedFile> = Vec::with_capacity(files.len());
+
+    for file in files {
+        let Some(existing) = unique
+            .iter()
+            .find(|existing| existing.target == file.target)
+        else {
+            unique.push(file);
+            continue;
+        };
+
+        if existing.source != file.source
+            || existing.kind != file.kind
+            || existing.mode != file.mode
+            || existing.owner != file.owner
+            || existing.variables != file.variables
+        {
+            bail!("Conflicting declarations of {} and {}", existing, file);
+        }
+    }
+
+    Ok(unique)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory to link to files in
+    fn test_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("blueprint-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    /// Link to the file, as declared in a configuration file
+    fn link(file: PathBuf, optional: bool) -> ConfigLink {
+        ConfigLink {
+            file,
+            optional,
+            when: None,
+            profiles: Vec::new(),
+        }
+    }
+
+    /// Names of the files the link to linked_path resolves into
+    fn resolved(linked_path: &PathBuf, optional: bool) -> anyhow::Result<Vec<String>> {
+        let files = resolve_link(
+            linked_path,
+            &link(linked_path.clone(), optional),
+            &PathBuf::from("/etc/blueprint/blueprint.toml"),
+        )?;
+
+        Ok(files
+            .iter()
+            .map(|file| file.file_name().unwrap().to_string_lossy().into_owned())
+            .collect())
+    }
+
+    #[test]
+    fn directories_link_to_their_toml_files_in_sorted_order() {
+        let directory = test_directory("link-directory");
+        for name in ["b.toml", "a.toml", "c.toml", "notes.txt"] {
+            fs::write(directory.join(name), "").unwrap();
+        }
+        fs::create_dir(directory.join("nested.toml")).unwrap();
+
+        assert_eq!(
+            resolved(&directory, false).unwrap(),
+            ["a.toml", "b.toml", "c.toml"]
+        );
+    }
+
+    #[test]
+    fn globs_link_to_every_file_they_match_in_sorted_order() {
+        let directory = test_directory("link-glob");
+        for name in ["host-b.toml", "host-a.toml", "other.toml"] {
+            fs::write(directory.join(name), "").unwrap();
+        }
+
+        assert_eq!(
+            resolved(&directory.join("host-*.toml"), false).unwrap(),
+            ["host-a.toml", "host-b.toml"]
+        );
+        assert_eq!(
+            resolved(&directory.join("host-[b].toml"), false).unwrap(),
+            ["host-b.toml"]
+        );
+    }
+
+    #[test]
+    fn existing_files_are_not_globbed() {
+        let directory = test_directory("link-literal");
+        for name in ["[old].toml", "o.toml"] {
+            fs::write(directory.join(name), "").unwrap();
+        }
+
+        assert_eq!(
+            resolved(&directory.join("[old].toml"), false).unwrap(),
+            ["[old].toml"]
+        );
+    }
+
+    #[test]
+    fn links_to_nothing_are_an_error_unless_optional() {
+        let directory = test_directory("link-missing");
+
+        for missing in [directory.join("missing.toml"), directory.join("*.toml")] {
+            let error = resolved(&missing, false).unwrap_err();
+            assert!(
+                error.to_string().contains("does not link to any files"),
+                "{}",
+                error
+            );
+            assert!(resolved(&missing, true).unwrap().is_empty());
+        }
+    }
+
+    /// Package declared in the configuration file src
+    fn declared(name: &str, source: PackageSource, src: &str) -> Package {
+        Package {
+            src: PathBuf::from(src),
+            ..Package::new(name.to_string(), source)
+        }
+    }
+
+    fn exclude(names: &[&str], source: Option<PackageSource>) -> PackageExclude {
+        PackageExclude {
+            names: names.iter().map(|name| name.to_string()).collect(),
+            source,
+            src: PathBuf::from("/etc/blueprint/blueprint.toml"),
+        }
+    }
+
+    fn names(package_list: &PackageList) -> Vec<&str> {
+        package_list
+            .iter()
+            .map(|package| package.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn excludes_remove_packages_declared_by_linked_files() {
+        let mut package_list = PackageList(vec![
+            declared(
+                "htop",
+                PackageSource::ArchPacman,
+                "/etc/blueprint/blueprint.toml",
+            ),
+            declared(
+                "steam",
+                PackageSource::ArchPacman,
+                "/etc/blueprint/gaming.toml",
+            ),
+        ]);
+        let excludes = [exclude(&["steam"], None)];
+        let mut excluded = Vec::new();
+
+        let unmatched = apply_excludes(&mut package_list, &excludes, &mut excluded);
+
+        assert!(unmatched.is_empty());
+        assert_eq!(names(&package_list), ["htop"]);
+        assert_eq!(excluded.len(), 1);
+        assert_eq!(
+            excluded[0].entry,
+            r#"package steam from "/etc/blueprint/gaming.toml""#
+        );
+        assert_eq!(excluded[0].reason, "it is excluded by [[exclude]]");
+        assert_eq!(excluded[0].src, Path::new("/etc/blueprint/blueprint.toml"));
+    }
+
+    #[test]
+    fn excludes_with_a_source_only_remove_packages_from_it() {
+        let mut package_list = PackageList(vec![
+            declared(
+                "ripgrep",
+                PackageSource::ArchPacman,
+                "/etc/blueprint/cli.toml",
+            ),
+            declared("ripgrep", PackageSource::Cargo, "/etc/blueprint/cli.toml"),
+        ]);
+        let excludes = [exclude(&["ripgrep"], Some(PackageSource::Cargo))];
+        let mut excluded = Vec::new();
+
+        let unmatched = apply_excludes(&mut package_list, &excludes, &mut excluded);
+
+        assert!(unmatched.is_empty());
+        assert_eq!(package_list.len(), 1);
+        assert_eq!(package_list[0].source, PackageSource::ArchPacman);
+        assert_eq!(excluded.len(), 1);
+    }
+
+    #[test]
+    fn excludes_matching_nothing_are_given_back() {
+        let mut package_list = PackageList(vec![declared(
+            "htop",
+            PackageSource::ArchPacman,
+            "/etc/blueprint/blueprint.toml",
+        )]);
+        let excludes = [
+            exclude(&["htop", "steam"], None),
+            exclude(&["htop"], Some(PackageSource::Cargo)),
+        ];
+        let mut excluded = Vec::new();
+
+        let unmatched = apply_excludes(&mut package_list, &excludes, &mut excluded);
+
+        let src = Path::new("/etc/blueprint/blueprint.toml");
+        assert_eq!(unmatched, [("steam", src), ("htop", src)]);
+        assert!(package_list.is_empty());
+        assert_eq!(excluded.len(), 1);
+    }
 }
@@ -0,0 +1,207 @@
+//! Named profiles selecting which configuration entries are used, entries
+//! without any profiles are always used
+
+use std::{collections::BTreeSet, path::Path};
+
+use anyhow::bail;
+
+use crate::condition::Exclusion;
+
+/// Profiles which are active for this run of blueprint
+#[derive(Debug)]
+pub struct ActiveProfiles {
+    active: Vec<String>,
+
+    // Every profile declared by an entry seen so far
+    declared: BTreeSet<String>,
+}
+
+impl ActiveProfiles {
+    /// Uses the profiles from the command line,
+    /// or the defaults if none were given
+    pub fn new(selected: Vec<String>, defaults: &[String]) -> Self {
+        let active = if selected.is_empty() {
+            defaults.to_vec()
+        } else {
+            selected
+        };
+
+        Self {
+            active,
+            declared: BTreeSet::new(),
+        }
+    }
+
+    /// Fails if an active profile is not declared by
+    /// any entry seen, as it is most likely misspelled
+    pub fn ensure_declared(&self) -> anyhow::Result<()> {
+        let unknown: Vec<&str> = self
+            .active
+            .iter()
+            .filter(|profile| !self.declared.contains(*profile))
+            .map(String::as_str)
+            .collect();
+
+        if !unknown.is_empty() {
+            bail!(
+                "Unknown profiles {}, no entry of the configuration declares them",
+                unknown.join(", ")
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Profiles which pull in an entry declaring these profiles, an entry
+    /// without profiles is pulled in by the profiles it was inherited from
+    pub fn pulled_in_by(&self, profiles: &[String], inherited: &[String]) -> Vec<String> {
+        if profiles.is_empty() {
+            return inherited.to_vec();
+        }
+
+        profiles
+            .iter()
+            .filter(|profile| self.active.contains(profile))
+            .cloned()
+            .collect()
+    }
+
+    /// Keeps only the entries without profiles or in an active profile,
+    /// adding an exclusion for every entry left out. Entries are described
+    /// by describe and their profiles are gotten from profiles, which are
+    /// recorded as declared
+    pub fn retain_active<T>(
+        &mut self,
+        entries: &mut Vec<T>,
+        file_path: &Path,
+        excluded: &mut Vec<Exclusion>,
+        profiles: impl Fn(&T) -> &[String],
+        describe: impl Fn(&T) -> String,
+    ) {
+        entries.retain(|entry| {
+            let profiles = profiles(entry);
+            self.declared.extend(profiles.iter().cloned());
+            if profiles.is_empty() || profiles.iter().any(|profile| self.active.contains(profile)) {
+                return true;
+            }
+
+            excluded.push(Exclusion {
+                entry: describe(entry),
+                reason: format!("none of its profiles {} are active", profiles.join(", ")),
+                src: file_path.to_path_buf(),
+            });
+            false
+        });
+    }
+}
+
+impl std::fmt::Display for ActiveProfiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.active.is_empty() {
+            return write!(f, "none");
+        }
+
+        write!(f, "{}", self.active.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    /// Entries named by their first element, with the profiles they declare
+    fn entries() -> Vec<(&'static str, Vec<String>)> {
+        vec![
+            ("htop", Vec::new()),
+            ("steam", strings(&["gaming"])),
+            ("slack", strings(&["work", "dev"])),
+        ]
+    }
+
+    /// Names of the entries kept by the profiles and the exclusions added
+    fn retained(profiles: &mut ActiveProfiles) -> (Vec<&'static str>, Vec<Exclusion>) {
+        let mut entries = entries();
+        let mut excluded = Vec::new();
+        profiles.retain_active(
+            &mut entries,
+            Path::new("/etc/blueprint.toml"),
+            &mut excluded,
+            |(_, profiles)| profiles,
+            |(name, _)| format!("package {}", name),
+        );
+
+        (
+            entries.into_iter().map(|(name, _)| name).collect(),
+            excluded,
+        )
+    }
+
+    #[test]
+    fn defaults_are_active_when_none_are_selected() {
+        let mut profiles = ActiveProfiles::new(Vec::new(), &strings(&["gaming"]));
+        let (kept, excluded) = retained(&mut profiles);
+
+        assert_eq!(kept, ["htop", "steam"]);
+        assert_eq!(excluded.len(), 1);
+        assert_eq!(excluded[0].entry, "package slack");
+        assert_eq!(
+            excluded[0].reason,
+            "none of its profiles work, dev are active"
+        );
+        assert_eq!(excluded[0].src, Path::new("/etc/blueprint.toml"));
+        assert!(profiles.ensure_declared().is_ok());
+        assert_eq!(profiles.to_string(), "gaming");
+    }
+
+    #[test]
+    fn selected_profiles_replace_the_defaults() {
+        let mut profiles = ActiveProfiles::new(strings(&["dev"]), &strings(&["gaming"]));
+        let (kept, excluded) = retained(&mut profiles);
+
+        assert_eq!(kept, ["htop", "slack"]);
+        assert_eq!(excluded.len(), 1);
+        assert_eq!(excluded[0].entry, "package steam");
+        assert!(profiles.ensure_declared().is_ok());
+    }
+
+    #[test]
+    fn entries_without_profiles_are_always_kept() {
+        let mut profiles = ActiveProfiles::new(Vec::new(), &[]);
+        let (kept, _) = retained(&mut profiles);
+
+        assert_eq!(kept, ["htop"]);
+        assert_eq!(profiles.to_string(), "none");
+        assert!(profiles.ensure_declared().is_ok());
+    }
+
+    #[test]
+    fn unknown_profiles_are_an_error() {
+        let mut profiles = ActiveProfiles::new(strings(&["wrok", "dev"]), &[]);
+        let (kept, _) = retained(&mut profiles);
+        assert_eq!(kept, ["htop", "slack"]);
+
+        let error = profiles.ensure_declared().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unknown profiles wrok, no entry of the configuration declares them"
+        );
+    }
+
+    #[test]
+    fn entries_without_profiles_inherit_those_of_the_link() {
+        let profiles = ActiveProfiles::new(strings(&["work"]), &[]);
+
+        assert_eq!(
+            profiles.pulled_in_by(&[], &strings(&["gaming"])),
+            ["gaming"]
+        );
+        assert_eq!(
+            profiles.pulled_in_by(&strings(&["work", "dev"]), &[]),
+            ["work"]
+        );
+    }
+}
@@ -0,0 +1,53 @@
+//! Confirming things with the user, which can be skipped
+//! entirely when blueprint is ran non-interactively
+
+use std::{
+    io::IsTerminal,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::bail;
+use inquire::Confirm;
+
+use crate::config::ROOT_CONFIG;
+
+// Whether or not --yes was passed on the command line
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether or not every prompt should be
+/// assumed to be answered yes from the command line
+pub fn set_assume_yes(assume_yes: bool) {
+    ASSUME_YES.store(assume_yes, Ordering::Relaxed);
+}
+
+/// Whether or not blueprint is running non-interactively, either
+/// from --yes or the non_interactive option of the root config
+pub fn assume_yes() -> bool {
+    ASSUME_YES.load(Ordering::Relaxed)
+        || (ROOT_CONFIG.is_initialised() && ROOT_CONFIG.get_config().non_interactive)
+}
+
+/// Errors if blueprint would need to prompt the user, either itself
+/// or through a package source, without a terminal to prompt in
+pub fn ensure_can_prompt() -> anyhow::Result<()> {
+    if !assume_yes() && !std::io::stdin().is_terminal() {
+        bail!(
+            "Standard input is not a terminal so prompts can't be answered, pass --yes or set non_interactive in the config to run without prompting"
+        );
+    }
+
+    Ok(())
+}
+
+/// Asks the user to confirm the message, answering with the default
+/// straight away when running non-interactively, so prompts which
+/// default to no are never agreed to without the user
+pub fn confirm(message: &str, default: bool) -> anyhow::Result<bool> {
+    if assume_yes() {
+        return Ok(default);
+    }
+
+    ensure_can_prompt()?;
+
+    Ok(Confirm::new(message).with_default(default).prompt()?)
+}
@@ -0,0 +1,358 @@
+//! Machine readable report of what a command did, collected while
+//! the command runs and written to standard output with --output json
+//!
+//! The layout of the report is versioned by REPORT_SCHEMA_VERSION, which
+//! must be bumped whenever a field is renamed, removed or changes meaning.
+
+use std::{
+    collections::BTreeMap,
+    process::ExitStatus,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::{
+    file::{FileAction, FileKind, FilePlan},
+    package::{
+        package::PackageList,
+        plan::BackendPlan,
+        sources::{PackageSource, SourceBackend},
+    },
+    service::{ServiceAction, ServicePlan},
+};
+
+/// Version of the layout of the report
+pub const REPORT_SCHEMA_VERSION: u32 = 1;
+
+// Report of the command currently running
+static REPORT: Mutex<Report> = Mutex::new(Report::new());
+
+// Whether the report is written to standard output once the command is done
+static WRITES_REPORT: AtomicBool = AtomicBool::new(false);
+
+/// Everything a command did which is included in the report
+#[derive(Serialize, Debug)]
+struct Report {
+    // Version of the layout of the report
+    schema_version: u32,
+
+    // Command which was ran such as "apply"
+    command: String,
+
+    // Package list resolved from the configuration
+    packages: Vec<PackageReport>,
+
+    // Computed actions for each backend
+    plans: Vec<PlanReport>,
+
+    // Computed actions for the declared services, if any
+    services: Option<ServicesReport>,
+
+    // Computed actions for the declared files, if any
+    files: Option<FilesReport>,
+
+    // Every external command ran, in order
+    commands: Vec<CommandReport>,
+
+    // Whether or not the command succeeded
+    outcome: OutcomeReport,
+}
+
+/// A package of the resolved package list
+#[derive(Serialize, Debug)]
+struct PackageReport {
+    name: String,
+    source: PackageSource,
+
+    // Configuration file declaring the package
+    src: String,
+
+    // Version constraint of the package, if any
+    version: Option<String>,
+
+    // Active profiles which pulled in the package
+    profiles: Vec<String>,
+}
+
+/// Actions computed for a single backend
+#[derive(Serialize, Debug)]
+struct PlanReport {
+    backend: SourceBackend,
+
+    // Packages to install, split by the source installing them
+    install: BTreeMap<PackageSource, Vec<String>>,
+
+    // Packages to mark as explicitly installed
+    mark_explicit: Vec<String>,
+
+    // Packages to mark as installed as a dependency
+    mark_dependency: Vec<String>,
+
+    // Packages to remove
+    remove: Vec<String>,
+
+    // Declared packages whose installed version doesn't satisfy their constraint
+    mismatched: Vec<MismatchReport>,
+
+    // Protected packages removal would take off the system
+    protected: Vec<ProtectedReport>,
+
+    // How many packages are installed in the backend
+    installed: usize,
+}
+
+/// A declared package whose installed version doesn't satisfy its constraint
+#[derive(Serialize, Debug)]
+struct MismatchReport {
+    name: String,
+    installed: String,
+    constraint: String,
+
+    // Configuration file declaring the package
+    src: String,
+}
+
+/// A protected package removal would take off the system
+#[derive(Serialize, Debug)]
+struct ProtectedReport {
+    name: String,
+
+    // Packages being removed which would pull it out as their dependency
+    required_by: Vec<String>,
+}
+
+/// Actions computed for the declared services
+#[derive(Serialize, Debug)]
+struct ServicesReport {
+    // Declared units which aren't in their declared state
+    changes: Vec<ServiceChangeReport>,
+
+    // Declared units systemctl could not find
+    missing: Vec<String>,
+
+    // System units enabled by the admin without being declared
+    undeclared: Vec<String>,
+}
+
+/// Changes to bring a declared unit to its declared state
+#[derive(Serialize, Debug)]
+struct ServiceChangeReport {
+    name: String,
+    user: bool,
+
+    // State systemctl reported for the unit
+    state: String,
+
+    // Actions to run, in order
+    actions: Vec<ServiceAction>,
+}
+
+/// Actions computed for the declared files
+#[derive(Serialize, Debug)]
+struct FilesReport {
+    // Declared files which aren't in place
+    changes: Vec<FileChangeReport>,
+}
+
+/// Change to bring a declared file into place
+#[derive(Serialize, Debug)]
+struct FileChangeReport {
+    target: String,
+    source: String,
+    kind: FileKind,
+    action: FileAction,
+
+    // What the conflicting file is, for replaced targets
+    conflict: Option<String>,
+}
+
+/// An external command ran by a package source
+#[derive(Serialize, Debug)]
+struct CommandReport {
+    // Argument vector of the command
+    command: Vec<String>,
+
+    // Whether the command only read from the system
+    query: bool,
+
+    // Exit code, missing if the command was killed by a signal
+    exit_code: Option<i32>,
+
+    // How long the command took to run
+    duration_ms: u128,
+}
+
+/// Final outcome of the command
+#[derive(Serialize, Debug)]
+struct OutcomeReport {
+    success: bool,
+
+    // Error the command failed with including its context
+    error: Option<String>,
+}
+
+impl Report {
+    /// Creates an empty report
+    const fn new() -> Self {
+        Self {
+            schema_version: REPORT_SCHEMA_VERSION,
+            command: String::new(),
+            packages: Vec::new(),
+            plans: Vec::new(),
+            services: None,
+            files: None,
+            commands: Vec::new(),
+            outcome: OutcomeReport {
+                success: true,
+                error: None,
+            },
+        }
+    }
+}
+
+/// Sets whether or not the report is written to standard output
+/// once the command is done, from --output json
+pub fn set_writes_report(writes_report: bool) {
+    WRITES_REPORT.store(writes_report, Ordering::Relaxed);
+}
+
+/// Whether or not the report is written to standard output, so
+/// nothing else may write to it
+pub fn writes_report() -> bool {
+    WRITES_REPORT.load(Ordering::Relaxed)
+}
+
+/// Runs the supplied function on the report of the current command
+fn with_report<T>(function: impl FnOnce(&mut Report) -> T) -> T {
+    // A poisoned report is still a valid report
+    let mut report = REPORT
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    function(&mut report)
+}
+
+/// Records the package list the command resolved from the configuration
+pub fn record_packages(package_list: &PackageList) {
+    with_report(|report| {
+        report.packages = package_list
+            .iter()
+            .map(|package| PackageReport {
+                name: package.name.clone(),
+                source: package.source,
+                src: package.src.to_string_lossy().to_string(),
+                version: package.version.as_ref().map(ToString::to_string),
+                profiles: package.pulled_in_by.clone(),
+            })
+            .collect();
+    });
+}
+
+/// Records the actions computed for each backend
+pub fn record_plans(plans: &[BackendPlan]) {
+    let plans = plans
+        .iter()
+        .map(|plan| PlanReport {
+            backend: plan.backend,
+            install: plan.install.clone(),
+            mark_explicit: plan.mark_explicit.clone(),
+            mark_dependency: plan.mark_dependency.clone(),
+            remove: plan.remove.clone(),
+            mismatched: plan
+                .mismatched
+                .iter()
+                .map(|mismatch| MismatchReport {
+                    name: mismatch.name.clone(),
+                    installed: mismatch.installed.clone(),
+                    constraint: mismatch.constraint.to_string(),
+                    src: mismatch.src.to_string_lossy().to_string(),
+                })
+                .collect(),
+            protected: plan
+                .protected
+                .iter()
+                .map(|protected| ProtectedReport {
+                    name: protected.name.clone(),
+                    required_by: protected.required_by.clone(),
+                })
+                .collect(),
+            installed: plan.installed,
+        })
+        .collect();
+
+    with_report(|report| report.plans = plans);
+}
+
+/// Records the actions computed for the declared services
+pub fn record_service_plan(plan: &ServicePlan) {
+    let services = ServicesReport {
+        changes: plan
+            .changes
+            .iter()
+            .map(|change| ServiceChangeReport {
+                name: change.name.clone(),
+                user: change.user,
+                state: change.state.clone(),
+                actions: change.actions.clone(),
+            })
+            .collect(),
+        missing: plan.missing.clone(),
+        undeclared: plan.undeclared.clone(),
+    };
+
+    with_report(|report| report.services = Some(services));
+}
+
+/// Records the actions computed for the declared files
+pub fn record_file_plan(plan: &FilePlan) {
+    let files = FilesReport {
+        changes: plan
+            .changes
+            .iter()
+            .map(|change| FileChangeReport {
+                target: change.target.to_string_lossy().to_string(),
+                source: change.source.to_string_lossy().to_string(),
+                kind: change.kind,
+                action: change.action,
+                conflict: change.conflict.clone(),
+            })
+            .collect(),
+    };
+
+    with_report(|report| report.files = Some(files));
+}
+
+/// Records an external command which has ran along with how long it took
+pub fn record_command(command: &[String], query: bool, status: ExitStatus, duration: Duration) {
+    with_report(|report| {
+        report.commands.push(CommandReport {
+            command: command.to_vec(),
+            query,
+            exit_code: status.code(),
+            duration_ms: duration.as_millis(),
+        })
+    });
+}
+
+/// Writes the report of the command with its outcome to standard output
+pub fn write_report(command: &str, result: &anyhow::Result<()>) -> anyhow::Result<()> {
+    let content = with_report(|report| {
+        report.command = command.to_string();
+        report.outcome = OutcomeReport {
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|err| format!("{:#}", err)),
+        };
+
+        serde_json::to_string_pretty(report)
+    })
+    .context("While trying to convert the report to JSON")?;
+
+    println!("{}", content);
+
+    Ok(())
+}
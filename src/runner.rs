@@ -0,0 +1,282 @@
+//! Running external commands such as package managers for blueprint
+//!
+//! Commands are always supplied as argument vectors and are never
+//! interpreted by a shell, so nothing in them needs quoting.
+
+use std::{
+    fs, io,
+    os::{fd::AsFd, unix::fs::MetadataExt},
+    process::{Command, ExitStatus, Stdio},
+    time::Instant,
+};
+
+#[cfg(test)]
+use std::{cell::RefCell, collections::VecDeque, os::unix::process::ExitStatusExt};
+
+use anyhow::{Context, bail};
+
+use crate::{
+    prompt::assume_yes,
+    report::{record_command, writes_report},
+};
+
+/// Result of a command which has ran to completion
+#[derive(Debug)]
+pub struct CommandOutput {
+    // Exit status of the command
+    pub status: ExitStatus,
+
+    // Captured standard output, empty for interactive commands
+    pub stdout: String,
+}
+
+/// Something which can run commands for the package sources
+pub trait CommandRunner {
+    /// Runs a command attached to the terminal so the user can
+    /// interact with it, waiting for it to complete
+    fn run(&self, command: &[String]) -> anyhow::Result<CommandOutput>;
+
+    /// Runs a command which only reads from the system,
+    /// capturing its standard output
+    fn query(&self, command: &[String]) -> anyhow::Result<CommandOutput>;
+
+    /// Runs a query command returning its standard output,
+    /// errors if the command does not exit successfully.
+    fn query_stdout(&self, command: &[String]) -> anyhow::Result<String> {
+        let output = self.query(command)?;
+
+        if !output.status.success() {
+            bail!(
+                "Query command {:?} did not successfully run, returned exit code of {}",
+                command.join(" "),
+                output.status
+            );
+        }
+
+        Ok(output.stdout)
+    }
+}
+
+/// Runs commands as real processes on the system
+pub struct ProcessRunner;
+
+impl CommandRunner for ProcessRunner {
+    fn run(&self, command: &[String]) -> anyhow::Result<CommandOutput> {
+        let mut process = new_process(command)?;
+
+        // Debconf asks its own questions outside of apt's prompts
+        if assume_yes() {
+            process.env("DEBIAN_FRONTEND", "noninteractive");
+        }
+
+        // Standard output only holds the report when it is written,
+        // so what the command prints goes to standard error instead
+        if writes_report() {
+            let stderr = io::stderr()
+                .as_fd()
+                .try_clone_to_owned()
+                .context("While trying to duplicate standard error for the command")?;
+            process.stdout(Stdio::from(stderr));
+        }
+
+        let started = Instant::now();
+        let status = process
+            .status()
+            .with_context(|| format!("While trying to run command {:?}", command.join(" ")))?;
+        record_command(command, false, status, started.elapsed());
+
+        Ok(CommandOutput {
+            status,
+            stdout: String::new(),
+        })
+    }
+
+    fn query(&self, command: &[String]) -> anyhow::Result<CommandOutput> {
+        // Force the C locale so output is stable to parse
+        let started = Instant::now();
+        let output = new_process(command)?
+            .env("LC_ALL", "C")
+            .stdin(Stdio::null())
+            .output()
+            .with_context(|| format!("While trying to run query {:?}", command.join(" ")))?;
+        record_command(command, true, output.status, started.elapsed());
+
+        Ok(CommandOutput {
+            status: output.status,
+            stdout: String::from_utf8(output.stdout).with_context(|| {
+                format!(
+                    "While trying to convert output of {:?} to string",
+                    command.join(" ")
+                )
+            })?,
+        })
+    }
+}
+
+/// Runs the commands of another runner through a privilege command
+/// such as sudo, queries only read so they are never elevated
+pub struct PrivilegedRunner<'a> {
+    // Runner actually running the commands
+    runner: &'a dyn CommandRunner,
+
+    // Privilege command to put in front of commands, if any
+    privilege_command: Option<&'static str>,
+}
+
+impl<'a> PrivilegedRunner<'a> {
+    /// Creates a runner elevating every command ran through
+    /// the privilege command, or running them as is without one
+    pub fn new(runner: &'a dyn CommandRunner, privilege_command: Option<&'static str>) -> Self {
+        Self {
+            runner,
+            privilege_command,
+        }
+    }
+}
+
+impl CommandRunner for PrivilegedRunner<'_> {
+    fn run(&self, command: &[String]) -> anyhow::Result<CommandOutput> {
+        match self.privilege_command {
+            Some(privilege_command) => {
+                let mut elevated = vec![privilege_command.to_string()];
+
+                // Privilege commands reset the environment, so debconf
+                // is told not to prompt through env on the other side
+                if assume_yes() {
+                    elevated.extend([
+                        "env".to_string(),
+                        "DEBIAN_FRONTEND=noninteractive".to_string(),
+                    ]);
+                }
+
+                elevated.extend(command.iter().cloned());
+                self.runner.run(&elevated)
+            }
+            None => self.runner.run(command),
+        }
+    }
+
+    fn query(&self, command: &[String]) -> anyhow::Result<CommandOutput> {
+        self.runner.query(command)
+    }
+}
+
+/// Whether or not blueprint is running as root, going by
+/// the owner of its own process information
+pub fn is_root() -> bool {
+    fs::metadata("/proc/self").is_ok_and(|metadata| metadata.uid() == 0)
+}
+
+/// Creates a new process from an argument vector
+fn new_process(command: &[String]) -> anyhow::Result<Command> {
+    let (program, arguments) = command
+        .split_first()
+        .context("Attempted to run an empty command")?;
+
+    let mut process = Command::new(program);
+    process.args(arguments);
+
+    Ok(process)
+}
+
+/// Records every command instead of running it,
+/// answering with queued outputs.
+///
+/// Used to exercise the package sources without
+/// a real package manager on the system.
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordingRunner {
+    // Every command "ran" in order
+    commands: RefCell<Vec<Vec<String>>>,
+
+    // Outputs to answer commands with in order,
+    // once empty every command succeeds with no output
+    outputs: RefCell<VecDeque<CommandOutput>>,
+}
+
+#[cfg(test)]
+impl RecordingRunner {
+    /// Queues up an output for the next unanswered command
+    pub fn push_output(&self, code: i32, stdout: &str) {
+        self.outputs.borrow_mut().push_back(CommandOutput {
+            status: ExitStatus::from_raw(code << 8),
+            stdout: stdout.to_string(),
+        });
+    }
+
+    /// All of the commands recorded so far
+    pub fn commands(&self) -> Vec<Vec<String>> {
+        self.commands.borrow().clone()
+    }
+
+    /// Records the command and answers with the next queued output
+    fn record(&self, command: &[String]) -> CommandOutput {
+        self.commands.borrow_mut().push(command.to_vec());
+
+        self.outputs
+            .borrow_mut()
+            .pop_front()
+            .unwrap_or_else(|| CommandOutput {
+                status: ExitStatus::from_raw(0),
+                stdout: String::new(),
+            })
+    }
+}
+
+#[cfg(test)]
+impl CommandRunner for RecordingRunner {
+    fn run(&self, command: &[String]) -> anyhow::Result<CommandOutput> {
+        Ok(self.record(command))
+    }
+
+    fn query(&self, command: &[String]) -> anyhow::Result<CommandOutput> {
+        Ok(self.record(command))
+    }
+}
+
+/// Argument vector of a command from string literals, to
+/// compare against the commands a RecordingRunner recorded
+#[cfg(test)]
+pub fn argv(arguments: &[&str]) -> Vec<String> {
+    arguments
+        .iter()
+        .map(|argument| argument.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ROOT_CONFIG;
+
+    #[test]
+    fn elevated_commands_keep_debconf_noninteractive() {
+        ROOT_CONFIG.set_test_config();
+        let recording = RecordingRunner::default();
+
+        PrivilegedRunner::new(&recording, Some("sudo"))
+            .run(&argv(&["apt-get", "install", "-y", "--", "curl"]))
+            .unwrap();
+        PrivilegedRunner::new(&recording, None)
+            .run(&argv(&["apt-get", "autoremove", "-y"]))
+            .unwrap();
+
+        assert_eq!(
+            recording.commands(),
+            vec![
+                argv(&[
+                    "sudo",
+                    "env",
+                    "DEBIAN_FRONTEND=noninteractive",
+                    "apt-get",
+                    "install",
+                    "-y",
+                    "--",
+                    "curl"
+                ]),
+                argv(&["apt-get", "autoremove", "-y"]),
+            ]
+        );
+    }
+}
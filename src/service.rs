@@ -0,0 +1,534 @@
+//! Systemd units declared in the configuration, which are enabled,
+//! disabled or masked through systemctl once the packages are applied
+
+use std::{fmt::Display, path::PathBuf};
+
+use anyhow::{Context, bail};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    condition::Condition,
+    config::ROOT_CONFIG,
+    runner::{CommandRunner, PrivilegedRunner, is_root},
+};
+
+const SYSTEMCTL_COMMAND_STRING: &str = "systemctl";
+
+/// A systemd unit and the state it should be in
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Service {
+    // Name of the unit, such as sshd.service
+    pub name: String,
+
+    // Whether the unit should be enabled or disabled
+    #[serde(default = "default_is_true")]
+    pub enabled: bool,
+
+    // Whether the unit should be masked so it can't be
+    // started at all, takes precedence over enabled
+    #[serde(default)]
+    pub masked: bool,
+
+    // Whether this is a user unit rather than a system unit
+    #[serde(default)]
+    pub user: bool,
+
+    // Condition on the host which must hold for this service to be used
+    #[serde(default, alias = "if")]
+    pub when: Option<Condition>,
+
+    // Profiles this service belongs to, it is only used when one of
+    // them is active. Without any profiles it is always used
+    #[serde(default)]
+    pub profiles: Vec<String>,
+
+    // What file is this service defined in for debugging info
+    #[serde(skip)]
+    pub src: PathBuf,
+}
+
+/// Change systemctl makes to the state of a unit
+#[derive(Serialize, Debug, PartialEq, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceAction {
+    Enable,
+    Disable,
+    Mask,
+    Unmask,
+}
+
+/// Changes needed to bring a declared unit to its declared state
+#[derive(Debug)]
+pub struct ServiceChange {
+    // Name of the unit
+    pub name: String,
+
+    // Whether this is a user unit
+    pub user: bool,
+
+    // State systemctl currently reports for the unit
+    pub state: String,
+
+    // Actions to run, in order
+    pub actions: Vec<ServiceAction>,
+}
+
+/// What applying the declared services would change on the system
+#[derive(Debug, Default)]
+pub struct ServicePlan {
+    // Declared units which aren't in their declared state
+    pub changes: Vec<ServiceChange>,
+
+    // Declared units systemctl could not find, which may
+    // be provided by a package which isn't installed yet
+    pub missing: Vec<String>,
+
+    // System units which aren't declared but have been enabled
+    // by the admin rather than by the distribution's presets
+    pub undeclared: Vec<String>,
+}
+
+fn default_is_true() -> bool {
+    true
+}
+
+impl Display for ServiceAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServiceAction::Enable => write!(f, "enable"),
+            ServiceAction::Disable => write!(f, "disable"),
+            ServiceAction::Mask => write!(f, "mask"),
+            ServiceAction::Unmask => write!(f, "unmask"),
+        }
+    }
+}
+
+impl Display for Service {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} unit {}, referenced in configuration file {:?}",
+            if self.user { "user" } else { "system" },
+            self.name,
+            self.src
+        )
+    }
+}
+
+/// Creates a systemctl command for system or user units, working on the
+/// systemd_root directory instead of the running system when it is set.
+/// User units of a root directory are enabled for every user
+fn systemctl_command(user: bool, arguments: &[&str]) -> Vec<String> {
+    let mut command = vec![SYSTEMCTL_COMMAND_STRING.to_string()];
+
+    match &ROOT_CONFIG.get_config().systemd_root {
+        Some(root) => {
+            command.push(format!("--root={}", root.to_string_lossy()));
+            if user {
+                command.push("--global".to_string());
+            }
+        }
+        None if user => command.push("--user".to_string()),
+        None => {}
+    }
+
+    command.extend(arguments.iter().map(|argument| argument.to_string()));
+    command
+}
+
+/// Gets the state systemctl reports for the unit file of a unit, such as
+/// enabled, disabled, masked or static, if the unit exists. The status is
+/// ignored as systemctl exits unsuccessfully for every state but enabled
+fn unit_state(runner: &dyn CommandRunner, service: &Service) -> anyhow::Result<Option<String>> {
+    let state = runner
+        .query(&systemctl_command(
+            service.user,
+            &["is-enabled", "--", &service.name],
+        ))
+        .with_context(|| {
+            format!(
+                "While trying to run systemctl to get the state of {}",
+                service
+            )
+        })?
+        .stdout
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+
+    Ok((!state.is_empty() && state != "not-found").then_some(state))
+}
+
+/// Actions which bring a unit from its current state to the declared state,
+/// units which can't be enabled such as static units are left alone. Units
+/// only enabled at runtime are lost on reboot, so are enabled for good
+fn service_actions(service: &Service, state: &str) -> Vec<ServiceAction> {
+    let masked = state.starts_with("masked");
+    let enabled = state == "enabled";
+    let enableable = enabled || state == "disabled" || state == "enabled-runtime";
+
+    if service.masked {
+        return match masked {
+            true => Vec::new(),
+            false => vec![ServiceAction::Mask],
+        };
+    }
+
+    let mut actions = Vec::new();
+    if masked {
+        actions.push(ServiceAction::Unmask);
+    }
+
+    match (service.enabled, enabled) {
+        (true, false) if masked || enableable => actions.push(ServiceAction::Enable),
+        (false, true) => actions.push(ServiceAction::Disable),
+        _ => {}
+    }
+
+    actions
+}
+
+/// Gets every enabled system unit which isn't declared and wasn't
+/// enabled by the distribution's presets, so was enabled by the admin
+fn undeclared_units(
+    runner: &dyn CommandRunner,
+    services: &[Service],
+) -> anyhow::Result<Vec<String>> {
+    let output = runner
+        .query_stdout(&systemctl_command(
+            false,
+            &[
+                "list-unit-files",
+                "--state=enabled",
+                "--no-legend",
+                "--no-pager",
+            ],
+        ))
+        .context("While trying to run systemctl to get every enabled unit")?;
+
+    // Units are listed as "name state preset", older
+    // versions of systemd don't list the preset at all
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let mut columns = line.split_whitespace();
+            let name = columns.next()?;
+            let preset = columns.nth(1);
+
+            (preset != Some("enabled")
+                && !services
+                    .iter()
+                    .any(|service| !service.user && service.name == name))
+            .then(|| name.to_string())
+        })
+        .collect())
+}
+
+/// Ensures blueprint can change the units, given whether each is a user
+/// unit. System units of the running system need root, elevated through
+/// the privilege command when not root. Units of a systemd_root directory
+/// are changed as whoever runs blueprint
+pub fn privilege_command(
+    mut user_units: impl Iterator<Item = bool>,
+) -> anyhow::Result<Option<&'static str>> {
+    let config = ROOT_CONFIG.get_config();
+    if is_root() || config.systemd_root.is_some() || user_units.all(|user| user) {
+        return Ok(None);
+    }
+
+    match config.privilege_command {
+        Some(privilege_command) => Ok(Some(privilege_command.program())),
+        None => bail!(
+            "System units need root to be changed, set privilege_command in the config or run blueprint as root"
+        ),
+    }
+}
+
+impl ServicePlan {
+    /// Works out what applying the services would change
+    /// on the system, without changing anything
+    pub fn new(runner: &dyn CommandRunner, services: &[Service]) -> anyhow::Result<Self> {
+        let mut plan = ServicePlan::default();
+        if services.is_empty() {
+            return Ok(plan);
+        }
+
+        for service in services {
+            let Some(state) = unit_state(runner, service)? else {
+                plan.missing.push(service.name.clone());
+                continue;
+            };
+            let actions = service_actions(service, &state);
+
+            if !actions.is_empty() {
+                plan.changes.push(ServiceChange {
+                    name: service.name.clone(),
+                    user: service.user,
+                    state,
+                    actions,
+                });
+            }
+        }
+
+        plan.undeclared = undeclared_units(runner, services)?;
+
+        Ok(plan)
+    }
+
+    /// How many units have drifted from the declared services
+    pub fn drift(&self) -> usize {
+        self.changes.len() + self.missing.len() + self.undeclared.len()
+    }
+
+    /// Logs what applying the services would change
+    pub fn log(&self) {
+        if self.changes.is_empty() && self.missing.is_empty() {
+            info!("Services are up to date, nothing to do");
+            return;
+        }
+
+        info!("Plan for services:");
+        if !self.missing.is_empty() {
+            info!(
+                "  not found until packages are installed {} units: {}",
+                self.missing.len(),
+                self.missing.join(" ")
+            );
+        }
+        for change in &self.changes {
+            info!(
+                "  {} {} unit {}, currently {}",
+                change
+                    .actions
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<String>>()
+                    .join(" and "),
+                if change.user { "user" } else { "system" },
+                change.name,
+                change.state
+            );
+        }
+    }
+
+    /// Logs where the units have drifted from the declared services
+    pub fn log_drift(&self) {
+        if self.drift() == 0 {
+            info!("Services match the declared services");
+            return;
+        }
+
+        info!("Drift in services:");
+        for change in &self.changes {
+            info!(
+                "  {} unit {} is {}, which is not its declared state",
+                if change.user { "user" } else { "system" },
+                change.name,
+                change.state
+            );
+        }
+
+        if !self.missing.is_empty() {
+            info!(
+                "  not found {} units: {}",
+                self.missing.len(),
+                self.missing.join(" ")
+            );
+        }
+
+        if !self.undeclared.is_empty() {
+            info!(
+                "  enabled by the admin but not declared {} units: {}",
+                self.undeclared.len(),
+                self.undeclared.join(" ")
+            );
+        }
+    }
+
+    /// Runs every action of the plan through systemctl
+    pub fn apply(&self, runner: &dyn CommandRunner) -> anyhow::Result<()> {
+        // Every package is installed by now, so the units should exist
+        if !self.missing.is_empty() {
+            bail!(
+                "Could not find units {}, are the packages providing them declared?",
+                self.missing.join(" ")
+            );
+        }
+
+        if self.changes.is_empty() {
+            info!("Services are up to date, nothing to do");
+            return Ok(());
+        }
+
+        let privileged = PrivilegedRunner::new(
+            runner,
+            privilege_command(self.changes.iter().map(|change| change.user))?,
+        );
+
+        for change in &self.changes {
+            // User units belong to whoever runs blueprint
+            let runner: &dyn CommandRunner = if change.user { runner } else { &privileged };
+
+            for action in &change.actions {
+                let action = action.to_string();
+                let systemctl_exit_status = runner
+                    .run(&systemctl_command(
+                        change.user,
+                        &[&action, "--", &change.name],
+                    ))
+                    .with_context(|| {
+                        format!(
+                            "While trying to run systemctl to {} {}",
+                            action, change.name
+                        )
+                    })?
+                    .status;
+
+                if !systemctl_exit_status.success() {
+                    bail!(
+                        "Systemctl did not successfully {} {}, returned exit code of {}, aborting",
+                        action,
+                        change.name,
+                        systemctl_exit_status
+                    );
+                }
+            }
+        }
+
+        info!(
+            "Systemctl completed changing {} units, proceeding",
+            self.changes.len()
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{RecordingRunner, argv};
+
+    /// System or user service which is declared enabled, disabled or masked
+    fn service(name: &str, enabled: bool, masked: bool, user: bool) -> Service {
+        Service {
+            name: name.to_string(),
+            enabled,
+            masked,
+            user,
+            when: None,
+            profiles: Vec::new(),
+            src: PathBuf::from("blueprint.toml"),
+        }
+    }
+
+    #[test]
+    fn only_persistently_enabled_units_are_enabled() {
+        let enabled = service("sshd.service", true, false, false);
+        let disabled = service("sshd.service", false, false, false);
+
+        assert_eq!(service_actions(&enabled, "enabled"), Vec::new());
+        assert_eq!(
+            service_actions(&enabled, "enabled-runtime"),
+            vec![ServiceAction::Enable]
+        );
+        assert_eq!(service_actions(&disabled, "enabled-runtime"), Vec::new());
+        assert_eq!(
+            service_actions(&disabled, "enabled"),
+            vec![ServiceAction::Disable]
+        );
+        assert_eq!(service_actions(&enabled, "static"), Vec::new());
+        assert_eq!(
+            service_actions(&enabled, "masked"),
+            vec![ServiceAction::Unmask, ServiceAction::Enable]
+        );
+    }
+
+    #[test]
+    fn plan_and_apply_work_on_the_systemd_root() {
+        ROOT_CONFIG.set_test_config();
+        let runner = RecordingRunner::default();
+        runner.push_output(1, "enabled-runtime\n");
+        runner.push_output(1, "disabled\n");
+        runner.push_output(0, "enabled\n");
+        runner.push_output(0, "enabled\n");
+        runner.push_output(
+            0,
+            "cups.service enabled disabled\ngdm.service enabled enabled\nfoo.service enabled disabled\n",
+        );
+
+        let services = [
+            service("sshd.service", true, false, false),
+            service("pipewire.service", true, false, true),
+            service("bluetooth.service", true, true, false),
+            service("cups.service", false, false, false),
+        ];
+        let plan = ServicePlan::new(&runner, &services).unwrap();
+        plan.apply(&runner).unwrap();
+
+        assert_eq!(plan.undeclared, argv(&["foo.service"]));
+        assert_eq!(
+            runner.commands(),
+            vec![
+                argv(&[
+                    "systemctl",
+                    "--root=/mnt",
+                    "is-enabled",
+                    "--",
+                    "sshd.service"
+                ]),
+                argv(&[
+                    "systemctl",
+                    "--root=/mnt",
+                    "--global",
+                    "is-enabled",
+                    "--",
+                    "pipewire.service"
+                ]),
+                argv(&[
+                    "systemctl",
+                    "--root=/mnt",
+                    "is-enabled",
+                    "--",
+                    "bluetooth.service"
+                ]),
+                argv(&[
+                    "systemctl",
+                    "--root=/mnt",
+                    "is-enabled",
+                    "--",
+                    "cups.service"
+                ]),
+                argv(&[
+                    "systemctl",
+                    "--root=/mnt",
+                    "list-unit-files",
+                    "--state=enabled",
+                    "--no-legend",
+                    "--no-pager"
+                ]),
+                argv(&["systemctl", "--root=/mnt", "enable", "--", "sshd.service"]),
+                argv(&[
+                    "systemctl",
+                    "--root=/mnt",
+                    "--global",
+                    "enable",
+                    "--",
+                    "pipewire.service"
+                ]),
+                argv(&[
+                    "systemctl",
+                    "--root=/mnt",
+                    "mask",
+                    "--",
+                    "bluetooth.service"
+                ]),
+                argv(&["systemctl", "--root=/mnt", "disable", "--", "cups.service"]),
+            ]
+        );
+    }
+}